@@ -0,0 +1,123 @@
+//! Floods the stack with SYNs from spoofed sources through a raw socket, to
+//! load-test the listeners' SYN queues and cookies. With the stack running,
+//! as root:
+//!
+//!     cargo run --example syn_flood -- 10.0.0.2 7 100000
+//!
+//! and meanwhile check that `nc 10.0.0.2 7` still connects.
+
+use libc::{
+    c_void, in_addr, sa_family_t, sendto, sockaddr, sockaddr_in, socket,
+    socklen_t, AF_INET, IPPROTO_RAW, SOCK_RAW,
+};
+use rand::Rng;
+use std::io::{Error, Result};
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+
+const USAGE: &str = "usage: syn_flood <address> <port> [count]";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (dest, port, count) = match args.as_slice() {
+        [dest, port] => (dest.parse(), port.parse(), Ok(u64::MAX)),
+        [dest, port, count] => (dest.parse(), port.parse(), count.parse()),
+        _ => panic!("{}", USAGE),
+    };
+    let (dest, port, count): (Ipv4Addr, u16, u64) =
+        (dest.expect(USAGE), port.expect(USAGE), count.expect(USAGE));
+
+    // IPPROTO_RAW implies IP_HDRINCL, we write the IP header ourselves
+    let fd = unsafe { socket(AF_INET, SOCK_RAW, IPPROTO_RAW) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let addr = sockaddr_in {
+        sin_family: AF_INET as sa_family_t,
+        sin_port: 0,
+        sin_addr: in_addr {
+            s_addr: u32::from(dest).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+
+    let mut rng = rand::thread_rng();
+    for sent in 0..count {
+        // Sources elsewhere in the tun network, whose SYN-ACKs go nowhere
+        let source = Ipv4Addr::new(10, 0, 0, rng.gen_range(3..=254));
+        let packet =
+            syn(source, rng.gen_range(1024..=65535), dest, port, rng.gen());
+        let n = unsafe {
+            sendto(
+                fd,
+                packet.as_ptr() as *const c_void,
+                packet.len(),
+                0,
+                &addr as *const sockaddr_in as *const sockaddr,
+                size_of::<sockaddr_in>() as socklen_t,
+            )
+        };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        if sent % 10_000 == 0 {
+            println!("{} SYNs sent", sent);
+        }
+    }
+    Ok(())
+}
+
+/// An IPv4 packet carrying a SYN with an MSS option, so that cookies have
+/// something to encode.
+fn syn(
+    source: Ipv4Addr,
+    source_port: u16,
+    dest: Ipv4Addr,
+    dest_port: u16,
+    seq: u32,
+) -> Vec<u8> {
+    const IP_LEN: usize = 20;
+    const TCP_LEN: usize = 24;
+    let mut packet = vec![0; IP_LEN + TCP_LEN];
+
+    let ip = &mut packet[..IP_LEN];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((IP_LEN + TCP_LEN) as u16).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6;
+    ip[12..16].copy_from_slice(&source.octets());
+    ip[16..20].copy_from_slice(&dest.octets());
+    let ip_checksum = checksum(ip);
+    ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    let tcp = &mut packet[IP_LEN..];
+    tcp[0..2].copy_from_slice(&source_port.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dest_port.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[12] = ((TCP_LEN / 4) as u8) << 4;
+    tcp[13] = 0x02;
+    tcp[14..16].copy_from_slice(&64240u16.to_be_bytes());
+    tcp[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+
+    let mut pseudo = Vec::with_capacity(12 + TCP_LEN);
+    pseudo.extend(source.octets());
+    pseudo.extend(dest.octets());
+    pseudo.extend([0, 6]);
+    pseudo.extend((TCP_LEN as u16).to_be_bytes());
+    pseudo.extend(&*tcp);
+    let tcp_checksum = checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+    packet
+}
+
+/// The Internet checksum, RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
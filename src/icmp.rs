@@ -1,3 +1,5 @@
+use crate::ip::{IpHeader, IpProtocol};
+use crate::packet::Packet;
 use crate::{network_checksum, AsSlice};
 
 #[repr(transparent)]
@@ -16,6 +18,11 @@ impl IcmpType {
     pub const BAD_IP_HEADER: Self = Self(12);
 }
 
+/// Codes of `IcmpType::DESTINATION_UNREACHABLE`.
+pub const PORT_UNREACHABLE: u8 = 3;
+/// The datagram needed fragmenting but had DF set, RFC 1191 section 4.
+pub const FRAGMENTATION_NEEDED: u8 = 4;
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 pub struct IcmpHeader {
@@ -25,7 +32,9 @@ pub struct IcmpHeader {
 }
 
 impl IcmpHeader {
-    // SAFETY: Needs to be given a valid length
+    /// # Safety
+    ///
+    /// `length` bytes of header and data have to follow `self`.
     pub unsafe fn checksum(&self, length: usize) -> u16 {
         network_checksum(
             self as *const IcmpHeader as *const u16,
@@ -34,10 +43,73 @@ impl IcmpHeader {
         )
     }
 
-    // SAFETY: Needs to be given a valid length
+    /// # Safety
+    ///
+    /// `length` bytes of header and data have to follow `self`.
     pub unsafe fn set_checksum(&mut self, length: usize) {
         self.checksum = self.checksum(length);
     }
 }
 
 impl AsSlice for IcmpHeader {}
+
+/// Answers an echo request with its identifier, sequence number and data,
+/// RFC 792.
+pub fn echo_reply(request: &mut Packet) -> Packet {
+    request.data_offset = request.l4_offset.map(|x| x + 4);
+    reply(request, IcmpType::ECHO_REPLY, 0, request.data().unwrap())
+}
+
+/// RFC 1122 section 4.1.3.1. Like every ICMP error, it quotes the IP header
+/// and the first 8 bytes of the datagram, RFC 792.
+pub fn port_unreachable(packet: &Packet) -> Option<Packet> {
+    let ip = packet.ip_header().unwrap();
+    // Never in reply to broadcasts or multicasts, RFC 1122 section 3.2.2
+    if ip.destination == u32::MAX || ip.destination >> 28 == 0xe {
+        return None;
+    }
+    let mut original = *ip;
+    original.bswap();
+    // Any IP options, then the start of the datagram
+    let rest = packet.l3_offset.unwrap() as usize + size_of::<IpHeader>();
+    let end = (packet.l4_offset.unwrap() as usize + 8).min(packet.data.len());
+    // The first 4 bytes are unused
+    let mut data = vec![0; 4];
+    data.extend(original.as_slice());
+    data.extend(&packet.data[rest..end]);
+    Some(reply(
+        packet,
+        IcmpType::DESTINATION_UNREACHABLE,
+        PORT_UNREACHABLE,
+        &data,
+    ))
+}
+
+/// An ICMP message back to where `packet` came from. It goes out with a
+/// header of its own, rather than one derived from `packet`, which may
+/// have arrived with its TTL run out or as a fragment.
+fn reply(packet: &Packet, type_: IcmpType, code: u8, data: &[u8]) -> Packet {
+    let ip = packet.ip_header().unwrap();
+    let reply_header =
+        IpHeader::new(IpProtocol::ICMP, ip.destination, ip.source);
+    let icmp_header = IcmpHeader {
+        type_,
+        code,
+        checksum: 0,
+    };
+    let data_len = data.len();
+    let mut reply_packet = Packet::new_from_data(data);
+    reply_packet.fill_l4(icmp_header);
+    reply_packet.fill_l3(reply_header);
+    reply_packet.ip_header_mut().unwrap().total_len =
+        reply_packet.len().unwrap() as u16;
+    reply_packet.ip_header_mut().unwrap().bswap();
+    reply_packet.ip_header_mut().unwrap().set_checksum();
+    unsafe {
+        reply_packet
+            .icmp_header_mut()
+            .unwrap()
+            .set_checksum(data_len + 4);
+    }
+    reply_packet
+}
@@ -20,6 +20,16 @@ impl std::fmt::Debug for IpProtocol {
     }
 }
 
+/// The ECN field in the low bits of `dscp_ecn`, RFC 3168 section 5.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct = 0,
+    Ect1 = 1,
+    Ect0 = 2,
+    /// Congestion Experienced, set by a router instead of dropping.
+    Ce = 3,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct IpHeader {
@@ -39,6 +49,25 @@ impl IpHeader {
     pub const RESERVED_BIT: u16 = 0x8000;
     pub const DF_BIT: u16 = 0x4000;
     pub const MF_BIT: u16 = 0x2000;
+    pub const ECN_MASK: u8 = 0x03;
+
+    /// A fresh header in native byte order, for packets that are not a reply
+    /// to anything.
+    pub fn new(protocol: IpProtocol, source: u32, destination: u32) -> Self {
+        Self {
+            // 0x80 is the native endian marker, see `bswap`
+            version_ihl: 0xC5,
+            dscp_ecn: 0,
+            total_len: 0,
+            id: 0,
+            flags_frag_offset: Self::DF_BIT,
+            ttl: 64,
+            protocol,
+            checksum: 0,
+            source,
+            destination,
+        }
+    }
 
     pub fn version(&self) -> u8 {
         ((self.version_ihl & 0xF0) >> 4) & 0x7
@@ -68,6 +97,19 @@ impl IpHeader {
         (self.flags_frag_offset & 0x1FFF) as usize * 8
     }
 
+    pub fn ecn(&self) -> Ecn {
+        match self.dscp_ecn & Self::ECN_MASK {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    pub fn set_ecn(&mut self, ecn: Ecn) {
+        self.dscp_ecn = (self.dscp_ecn & !Self::ECN_MASK) | ecn as u8;
+    }
+
     pub fn bswap(&mut self) {
         self.total_len = self.total_len.swap_bytes();
         self.id = self.id.swap_bytes();
@@ -102,14 +144,16 @@ impl IpHeader {
         self.checksum = self.checksum();
     }
 
+    /// Keeps the DSCP, but not the ECN codepoint: only a transport that
+    /// negotiated ECN may mark its packets ECN-capable.
     pub fn reply_header(&self) -> Self {
         Self {
             version_ihl: 0x45 | (self.version_ihl & 0x80),
-            dscp_ecn: self.dscp_ecn,
+            dscp_ecn: self.dscp_ecn & !Self::ECN_MASK,
             total_len: 0,
             id: self.id,
             flags_frag_offset: self.flags_frag_offset,
-            ttl: self.ttl - 1,
+            ttl: self.ttl.saturating_sub(1),
             protocol: self.protocol,
             checksum: 0,
             source: self.destination,
@@ -138,7 +182,7 @@ fn test_bswap() {
     ];
     let header = unsafe { &mut *(buffer.as_mut_ptr() as *mut IpHeader) };
     header.bswap();
-    assert_eq!(header.total_len, 0x0073);
+    assert_eq!({ header.total_len }, 0x0073);
     header.bswap();
-    assert_eq!(header.total_len, 0x7300);
+    assert_eq!({ header.total_len }, 0x7300);
 }
@@ -0,0 +1,114 @@
+//! A userspace TCP/IP stack. Applications use it through `socket::Sockets`,
+//! which also takes the packets of the interface and hands back the ones to
+//! send, leaving the interface itself to the event loop in `main.rs`.
+
+pub mod icmp;
+pub mod ip;
+pub mod packet;
+pub mod socket;
+pub mod tcp;
+mod timer;
+pub mod udp;
+
+fn ones_complement_sum(a: u16, b: u16) -> u16 {
+    let (mut result, overflow) = a.overflowing_add(b);
+    if overflow {
+        result += 1;
+    }
+    result
+}
+
+/// # Safety
+///
+/// `pointer` must be valid for reads of `length` bytes.
+pub unsafe fn network_partial_checksum(
+    pointer: *const u16,
+    length: usize,
+) -> u16 {
+    let mut acc = 0;
+    for i in 0..length / 2 {
+        acc = ones_complement_sum(
+            acc,
+            pointer.add(i).read_unaligned().swap_bytes(),
+        );
+    }
+    acc
+}
+
+/// # Safety
+///
+/// `pointer` must be valid for reads of `length` bytes.
+pub unsafe fn network_checksum(
+    pointer: *const u16,
+    length: usize,
+    checksum: u16,
+) -> u16 {
+    let mut acc = !checksum.swap_bytes();
+    for i in 0..length / 2 {
+        acc = ones_complement_sum(
+            acc,
+            pointer.add(i).read_unaligned().swap_bytes(),
+        );
+    }
+    if length & 1 != 0 {
+        acc = ones_complement_sum(
+            acc,
+            ((*(pointer as *const u8).offset(length as isize - 1)) as u16) << 8,
+        );
+    }
+    (!acc).swap_bytes()
+}
+
+/// # Safety
+///
+/// `pointer` and `pointer2` must be valid for reads of `length` and
+/// `length2` bytes respectively.
+pub unsafe fn network_checksum_2part(
+    pointer: *const u16,
+    length: usize,
+    pointer2: *const u16,
+    length2: usize,
+    checksum: u16,
+) -> u16 {
+    let mut acc = !checksum.swap_bytes();
+    for i in 0..length / 2 {
+        acc = ones_complement_sum(
+            acc,
+            pointer.add(i).read_unaligned().swap_bytes(),
+        );
+    }
+    if length & 1 != 0 {
+        acc = ones_complement_sum(
+            acc,
+            ((*(pointer as *const u8).offset(length as isize - 1)) as u16) << 8,
+        );
+    }
+    for i in 0..length2 / 2 {
+        acc = ones_complement_sum(
+            acc,
+            pointer2.add(i).read_unaligned().swap_bytes(),
+        );
+    }
+    if length2 & 1 != 0 {
+        acc = ones_complement_sum(
+            acc,
+            ((*(pointer2 as *const u8).offset(length2 as isize - 1)) as u16)
+                << 8,
+        );
+    }
+    (!acc).swap_bytes()
+}
+
+pub trait AsSlice {
+    fn as_slice<'a>(&'a self) -> &'a [u8]
+    where
+        Self: Sized,
+    {
+        unsafe {
+            std::slice::from_raw_parts::<'a, u8>(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
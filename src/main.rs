@@ -1,48 +1,93 @@
 use ifstructs::ifreq;
-use libc::{c_short, c_ulong, c_void, close, ioctl, open, O_RDWR};
-use rand::Rng;
+use libc::{
+    c_int, c_short, c_ulong, c_void, close, ioctl, open, poll, pollfd, socket,
+    AF_INET, O_RDWR, POLLIN, SIOCGIFMTU, SOCK_DGRAM,
+};
 use std::fs::File;
-use std::io::{Error, Read, Result, Write};
-use std::os::unix::io::FromRawFd;
-
-mod icmp;
-mod ip;
-mod packet;
-mod udp;
-
-use icmp::{IcmpHeader, IcmpType};
-use ip::IpProtocol;
-use packet::Packet;
-
-static mut INTERFACE: Option<Box<File>> = None;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use tcp::packet::Packet;
+use tcp::socket::{SocketHandle, SocketOption, SocketType, Sockets};
+
+static INTERFACE: OnceLock<File> = OnceLock::new();
+
+/// The stack's own address, on the other end of the tun interface from
+/// the host's, see `make_tun.sh`.
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const ECHO_PORT: u16 = 7;
+const ECHO_BACKLOG: usize = 128;
+/// Answers every datagram with `UDP_REPLY`.
+const UDP_REPLY_PORT: u16 = 25500;
+const UDP_REPLY: &[u8] = b"This is your reply!\r\n";
 
 fn main() -> Result<()> {
     let file = tun_alloc("tun0")?;
-
-    unsafe {
-        INTERFACE = Some(Box::new(file));
-    }
+    INTERFACE.set(file).unwrap();
+
+    let mut sockets = Sockets::new(LOCAL_ADDR);
+    sockets.tcp_mut().set_mtu(interface_mtu("tun0")?);
+    let echo = sockets.socket(SocketType::Stream);
+    sockets.setsockopt(echo, SocketOption::FastOpen(true), Instant::now())?;
+    sockets.bind(echo, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, ECHO_PORT))?;
+    sockets.listen(echo, ECHO_BACKLOG)?;
+    let mut echo_connections = Vec::new();
+    let reply = sockets.socket(SocketType::Datagram);
+    sockets.bind(
+        reply,
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, UDP_REPLY_PORT),
+    )?;
 
     loop {
-        let mut packet = read_packet();
-
-        if packet.data[0] & 0xF0 != 0x40 {
-            println!("Not IPv4, discarding");
-            continue;
+        let timeout = sockets
+            .next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if wait_for_packet(timeout)? {
+            sockets.on_packet(read_packet(), Instant::now());
         }
 
-        if let Some(ip_header) = packet.ip_header_mut() {
-            ip_header.bswap();
+        let now = Instant::now();
+        run_echo(&mut sockets, echo, &mut echo_connections, now);
+        run_udp_reply(&mut sockets, reply, now);
+        for packet in sockets.poll(now) {
+            send_packet(&packet);
         }
+    }
+}
 
-        handle_ip(&mut packet);
+/// Blocks until the interface is readable or `timeout` passes, returning
+/// whether there is a packet to read.
+fn wait_for_packet(timeout: Option<Duration>) -> Result<bool> {
+    let mut fds = [pollfd {
+        fd: INTERFACE.get().unwrap().as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    }];
+    // Round up, waking early would only go around the loop again
+    let timeout_ms = match timeout {
+        Some(timeout) => timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(c_int::MAX as u128) as c_int,
+        None => -1,
+    };
+    let n = unsafe { poll(fds.as_mut_ptr(), 1, timeout_ms) };
+    if n < 0 {
+        let error = Error::last_os_error();
+        if error.kind() == ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(error);
     }
+    Ok(fds[0].revents & POLLIN != 0)
 }
 
 fn read_packet() -> Packet {
     let mut buffer = [0; 4096];
-    let n_read =
-        unsafe { INTERFACE.as_mut().unwrap().read(&mut buffer) }.unwrap();
+    let n_read = INTERFACE.get().unwrap().read(&mut buffer).unwrap();
     let vec = buffer[..n_read].to_vec();
     println!("-> {:02x?}", vec);
     Packet::new(vec)
@@ -50,106 +95,69 @@ fn read_packet() -> Packet {
 
 fn send_packet(packet: &Packet) {
     println!("<- {:02x?}", packet.whole());
-    unsafe {
-        INTERFACE
-            .as_mut()
-            .unwrap()
-            .write(packet.whole().unwrap())
-            .unwrap();
-    }
-}
-
-fn handle_ip(packet: &mut Packet) {
-    let (protocol, len) = {
-        let ip = packet.ip_header().unwrap();
-        (ip.protocol, ip.header_len())
-    };
-    packet.l4_offset = Some(len as isize);
-    match protocol {
-        IpProtocol::ICMP => handle_icmp(packet),
-        IpProtocol::UDP => handle_udp(packet),
-        _ => {}
-    };
+    INTERFACE
+        .get()
+        .unwrap()
+        .write_all(packet.whole().unwrap())
+        .unwrap();
 }
 
-fn handle_icmp(packet: &mut Packet) {
-    let icmp_type = packet.icmp_header().unwrap().type_;
-    match icmp_type {
-        icmp::IcmpType::ECHO_REQUEST => handle_icmp_echo(packet),
-        _ => {}
-    };
+/// A connection to `ECHO_PORT`, with what was read from it but did not
+/// fit into the send buffer yet.
+struct EchoConnection {
+    socket: SocketHandle,
+    unsent: Vec<u8>,
 }
 
-fn handle_icmp_echo(packet: &mut Packet) {
-    packet.data_offset = packet.l4_offset.map(|x| x + 4);
-
-    send_icmp(packet, IcmpType::ECHO_REPLY, packet.data().unwrap());
+/// Echoes back everything received on connections to `ECHO_PORT`.
+fn run_echo(
+    sockets: &mut Sockets,
+    listener: SocketHandle,
+    connections: &mut Vec<EchoConnection>,
+    now: Instant,
+) {
+    while let Ok((socket, _)) = sockets.accept(listener, now) {
+        connections.push(EchoConnection {
+            socket,
+            unsent: Vec::new(),
+        });
+    }
+
+    connections.retain_mut(|connection| match echo(sockets, connection, now) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => true,
+        _ => {
+            let _ = sockets.close(connection.socket, now);
+            false
+        }
+    });
 }
 
-fn handle_udp(packet: &mut Packet) {
-    {
-        let udp_header = packet.udp_header_mut().unwrap();
-        udp_header.bswap();
+/// Echoes until the connection would block, or returns `Ok` once the peer
+/// is done sending.
+fn echo(
+    sockets: &mut Sockets,
+    connection: &mut EchoConnection,
+    now: Instant,
+) -> Result<()> {
+    let mut buffer = [0; 1024];
+    loop {
+        while !connection.unsent.is_empty() {
+            let n = sockets.send(connection.socket, &connection.unsent, now)?;
+            connection.unsent.drain(..n);
+        }
+        match sockets.recv(connection.socket, &mut buffer, now)? {
+            0 => return Ok(()),
+            n => connection.unsent.extend(&buffer[..n]),
+        }
     }
-    send_udp(packet, b"This is your reply!\r\n");
 }
 
-fn send_icmp(packet: &Packet, type_: icmp::IcmpType, data: &[u8]) {
-    let reply_header = packet.ip_header().unwrap().reply_header();
-    let icmp_header = IcmpHeader {
-        type_,
-        code: 0,
-        checksum: 0,
-    };
-    let data_len = data.len();
-    let mut reply_packet = Packet::new_from_data(data);
-    reply_packet.fill_l4(icmp_header);
-    reply_packet.fill_l3(reply_header);
-    reply_packet.ip_header_mut().unwrap().total_len =
-        reply_packet.len().unwrap() as u16;
-    reply_packet.ip_header_mut().unwrap().bswap();
-    reply_packet.ip_header_mut().unwrap().set_checksum();
-    unsafe {
-        reply_packet
-            .icmp_header_mut()
-            .unwrap()
-            .set_checksum(data_len + 4);
+/// Answers `UDP_REPLY_PORT`.
+fn run_udp_reply(sockets: &mut Sockets, socket: SocketHandle, now: Instant) {
+    let mut buffer = [0; 1500];
+    while let Ok((_, from)) = sockets.recv_from(socket, &mut buffer, now) {
+        let _ = sockets.send_to(socket, UDP_REPLY, from, now);
     }
-
-    send_packet(&reply_packet);
-}
-
-fn send_udp(packet: &Packet, data: &[u8]) {
-    let mut rng = rand::thread_rng();
-
-    let reply_ip_header = packet.ip_header().unwrap().reply_header();
-    let reply_udp_header = packet.udp_header().unwrap().reply_header();
-    let data_len = data.len();
-    let mut reply_packet = Packet::new_from_data(data);
-    reply_packet.fill_l4(reply_udp_header);
-    reply_packet.fill_l3(reply_ip_header);
-    reply_packet.ip_header_mut().unwrap().total_len =
-        reply_packet.len().unwrap() as u16;
-    reply_packet.ip_header_mut().unwrap().id = 1; // = rng.gen();
-    reply_packet.udp_header_mut().unwrap().len = data_len as u16 + 8;
-    reply_packet.udp_header_mut().unwrap().bswap();
-    reply_packet.ip_header_mut().unwrap().bswap();
-    let source_ip = reply_packet.ip_header().unwrap().source;
-    let destination_ip = reply_packet.ip_header().unwrap().destination;
-
-    println!("{:08x} {:08x}", source_ip, destination_ip);
-
-    unsafe {
-        reply_packet
-            .udp_header_mut()
-            .unwrap()
-            .set_ip_checksum(source_ip, destination_ip);
-    }
-
-    // reply_packet.udp_header_mut().unwrap().checksum = 0;
-    reply_packet.ip_header_mut().unwrap().set_checksum();
-
-    send_packet(&reply_packet);
 }
 
 // tun: 1 tap: 2 no_pi: 4096
@@ -160,7 +168,7 @@ const TUNSETIFF: c_ulong = 1074025674;
 
 fn tun_alloc(name: &str) -> Result<File> {
     unsafe {
-        let fd = open("/dev/net/tun\0".as_ptr() as *const i8, O_RDWR);
+        let fd = open(c"/dev/net/tun".as_ptr(), O_RDWR);
         if fd < 0 {
             return Err(Error::from_raw_os_error(-fd));
         }
@@ -177,95 +185,22 @@ fn tun_alloc(name: &str) -> Result<File> {
     }
 }
 
-fn ones_complement_sum(a: u16, b: u16) -> u16 {
-    let (mut result, overflow) = a.overflowing_add(b);
-    if overflow {
-        result += 1;
-    }
-    result
-}
-
-pub unsafe fn network_partial_checksum(
-    pointer: *const u16,
-    length: usize,
-) -> u16 {
-    let mut acc = 0;
-    for i in 0..length / 2 {
-        acc = ones_complement_sum(
-            acc,
-            (*pointer.offset(i as isize)).swap_bytes(),
-        );
-    }
-    acc
-}
-
-pub unsafe fn network_checksum(
-    pointer: *const u16,
-    length: usize,
-    checksum: u16,
-) -> u16 {
-    let mut acc = !checksum.swap_bytes();
-    for i in 0..length / 2 {
-        acc = ones_complement_sum(
-            acc,
-            (*pointer.offset(i as isize)).swap_bytes(),
-        );
-    }
-    if length & 1 != 0 {
-        acc = ones_complement_sum(
-            acc,
-            ((*(pointer as *const u8).offset(length as isize - 1)) as u16) << 8,
-        );
-    }
-    (!acc).swap_bytes()
-}
-
-pub unsafe fn network_checksum_2part(
-    pointer: *const u16,
-    length: usize,
-    pointer2: *const u16,
-    length2: usize,
-    checksum: u16,
-) -> u16 {
-    let mut acc = !checksum.swap_bytes();
-    for i in 0..length / 2 {
-        acc = ones_complement_sum(
-            acc,
-            (*pointer.offset(i as isize)).swap_bytes(),
-        );
-    }
-    if length & 1 != 0 {
-        acc = ones_complement_sum(
-            acc,
-            ((*(pointer as *const u8).offset(length as isize - 1)) as u16) << 8,
-        );
-    }
-    for i in 0..length2 / 2 {
-        acc = ones_complement_sum(
-            acc,
-            (*pointer2.offset(i as isize)).swap_bytes(),
-        );
-    }
-    if length2 & 1 != 0 {
-        acc = ones_complement_sum(
-            acc,
-            ((*(pointer2 as *const u8).offset(length2 as isize - 1)) as u16)
-                << 8,
-        );
-    }
-    (!acc).swap_bytes()
-}
+/// Asks the kernel for the MTU of the interface called `name`.
+fn interface_mtu(name: &str) -> Result<usize> {
+    unsafe {
+        let fd = socket(AF_INET, SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
 
-trait AsSlice {
-    fn as_slice<'a>(&'a self) -> &[u8]
-    where
-        Self: Sized,
-    {
-        unsafe {
-            std::slice::from_raw_parts::<'a, u8>(
-                self as *const Self as *const u8,
-                std::mem::size_of::<Self>(),
-            )
+        let mut ifreq = ifreq::from_name(name).unwrap();
+        let err =
+            ioctl(fd, SIOCGIFMTU, &mut ifreq as *mut ifreq as *mut c_void);
+        let error = Error::last_os_error();
+        close(fd);
+        if err < 0 {
+            return Err(error);
         }
+        Ok(ifreq.ifr_ifru.ifr_mtu as usize)
     }
 }
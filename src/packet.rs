@@ -1,5 +1,6 @@
 use crate::icmp::IcmpHeader;
 use crate::ip::IpHeader;
+use crate::tcp::TcpHeader;
 use crate::udp::UdpHeader;
 use crate::AsSlice;
 
@@ -41,9 +42,16 @@ impl Packet {
     }
 
     pub fn fill_l4<T: AsSlice>(&mut self, l4: T) {
+        self.fill_l4_with_options(l4, &[]);
+    }
+
+    /// Writes `options` into the headroom right before the data, and the
+    /// header before them.
+    pub fn fill_l4_with_options<T: AsSlice>(&mut self, l4: T, options: &[u8]) {
         let s = l4.as_slice();
         let l = s.len();
-        let d = self.data_offset.unwrap() as usize;
+        let d = self.data_offset.unwrap() as usize - options.len();
+        self.data[d..d + options.len()].copy_from_slice(options);
         self.data[d - l..d].copy_from_slice(s);
         self.l4_offset = Some((d - l) as isize);
     }
@@ -84,6 +92,10 @@ impl Packet {
         unsafe { Some(&*(self.l4_ptr()? as *const UdpHeader)) }
     }
 
+    pub fn tcp_header(&self) -> Option<&TcpHeader> {
+        unsafe { Some(&*(self.l4_ptr()? as *const TcpHeader)) }
+    }
+
     pub fn ip_header_mut(&mut self) -> Option<&mut IpHeader> {
         unsafe { Some(&mut *(self.l3_mut_ptr()? as *mut IpHeader)) }
     }
@@ -96,6 +108,10 @@ impl Packet {
         unsafe { Some(&mut *(self.l4_mut_ptr()? as *mut UdpHeader)) }
     }
 
+    pub fn tcp_header_mut(&mut self) -> Option<&mut TcpHeader> {
+        unsafe { Some(&mut *(self.l4_mut_ptr()? as *mut TcpHeader)) }
+    }
+
     pub fn data(&self) -> Option<&[u8]> {
         Some(&self.data[self.data_offset? as usize..])
     }
@@ -111,6 +127,10 @@ impl Packet {
     pub fn len(&self) -> Option<usize> {
         Some(self.data.len() - self.l3_offset? as usize)
     }
+
+    pub fn is_empty(&self) -> Option<bool> {
+        Some(self.len()? == 0)
+    }
 }
 
 #[test]
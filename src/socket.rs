@@ -0,0 +1,945 @@
+//! A Berkeley sockets interface to the stack, for applications to use it
+//! without knowing about 4-tuples or connection state. Sockets are named by
+//! handles, like file descriptors, and every call is non-blocking: instead
+//! of waiting, it fails with `WouldBlock`. A `connect` returns right away,
+//! with the handshake finishing as the event loop runs: data sent meanwhile
+//! is queued, and a connection that fails shows up as `SocketOption::Error`
+//! and in the errors of further calls.
+
+use crate::icmp::{self, IcmpHeader, IcmpType};
+use crate::ip::{IpHeader, IpProtocol};
+use crate::packet::Packet;
+use crate::tcp::{CongestionAlgorithm, Keepalive, Quad, Segment, TcpStack};
+use crate::udp::{Datagram, UdpStack};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Result};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Where ports come from for sockets that don't bind one, RFC 6335 section
+/// 6.
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SocketType {
+    /// TCP, like `SOCK_STREAM`.
+    Stream,
+    /// UDP, like `SOCK_DGRAM`.
+    Datagram,
+}
+
+/// Names one socket of a `Sockets` until it is closed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SocketHandle(u64);
+
+/// A socket option along with its value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SocketOption {
+    /// Like `TCP_NODELAY`.
+    NoDelay(bool),
+    /// Like `TCP_CORK`.
+    Cork(bool),
+    /// Like `SO_KEEPALIVE` with `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and
+    /// `TCP_KEEPCNT`.
+    KeepAlive(Option<Keepalive>),
+    /// Like `SO_LINGER`.
+    Linger(Option<Duration>),
+    /// Like `TCP_CONGESTION`.
+    Congestion(CongestionAlgorithm),
+    /// Whether losses are detected with RACK-TLP, on by default.
+    RackTlp(bool),
+    /// Like `TCP_FASTOPEN` on a listening socket and `TCP_FASTOPEN_CONNECT`
+    /// on one that connects.
+    FastOpen(bool),
+    /// Like `SO_ERROR`, read only: why the connection failed.
+    Error(Option<ErrorKind>),
+    /// Read only, datagrams dropped because the receive queue was full, like
+    /// the counter of `SO_RXQ_OVFL`.
+    RecvDrops(u64),
+}
+
+/// Which option `getsockopt` should get.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SocketOptionKind {
+    NoDelay,
+    Cork,
+    KeepAlive,
+    Linger,
+    Congestion,
+    RackTlp,
+    FastOpen,
+    Error,
+    RecvDrops,
+}
+
+impl SocketOptionKind {
+    fn applies_to(self, type_: SocketType) -> bool {
+        match self {
+            Self::Error => true,
+            Self::RecvDrops => type_ == SocketType::Datagram,
+            _ => type_ == SocketType::Stream,
+        }
+    }
+
+    fn is_read_only(self) -> bool {
+        matches!(self, Self::Error | Self::RecvDrops)
+    }
+}
+
+impl SocketOption {
+    pub fn kind(&self) -> SocketOptionKind {
+        match self {
+            Self::NoDelay(_) => SocketOptionKind::NoDelay,
+            Self::Cork(_) => SocketOptionKind::Cork,
+            Self::KeepAlive(_) => SocketOptionKind::KeepAlive,
+            Self::Linger(_) => SocketOptionKind::Linger,
+            Self::Congestion(_) => SocketOptionKind::Congestion,
+            Self::RackTlp(_) => SocketOptionKind::RackTlp,
+            Self::FastOpen(_) => SocketOptionKind::FastOpen,
+            Self::Error(_) => SocketOptionKind::Error,
+            Self::RecvDrops(_) => SocketOptionKind::RecvDrops,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum SocketState {
+    Unconnected,
+    Listening,
+    Connected(Quad),
+}
+
+struct Socket {
+    type_: SocketType,
+    local: Option<SocketAddrV4>,
+    state: SocketState,
+    /// The options set so far. They are applied to the connection once
+    /// there is one, and passed on to the connections a listener accepts.
+    options: HashMap<SocketOptionKind, SocketOption>,
+}
+
+/// The sockets of the applications, over the TCP and UDP stacks they
+/// share. The interface's packets go in through `on_packet`, and what the
+/// stacks send comes out of `poll`.
+pub struct Sockets {
+    tcp: TcpStack,
+    udp: UdpStack,
+    /// The stack's only address.
+    addr: Ipv4Addr,
+    sockets: HashMap<SocketHandle, Socket>,
+    next_handle: u64,
+    /// ICMP messages waiting for `poll`.
+    outgoing: VecDeque<Packet>,
+}
+
+impl Sockets {
+    pub fn new(addr: Ipv4Addr) -> Self {
+        Self {
+            tcp: TcpStack::new(),
+            udp: UdpStack::new(addr),
+            addr,
+            sockets: HashMap::new(),
+            next_handle: 0,
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    /// The TCP stack underneath, for what is set for all connections.
+    pub fn tcp(&self) -> &TcpStack {
+        &self.tcp
+    }
+
+    pub fn tcp_mut(&mut self) -> &mut TcpStack {
+        &mut self.tcp
+    }
+
+    /// Takes a packet as read from the interface, IP header and all.
+    pub fn on_packet(&mut self, mut packet: Packet, now: Instant) {
+        let Some(l3) = packet
+            .l3_offset
+            .and_then(|l3| usize::try_from(l3).ok())
+            .filter(|&l3| l3 + size_of::<IpHeader>() <= packet.data.len())
+        else {
+            println!("ip: truncated header, discarding");
+            return;
+        };
+        if packet.data[l3] >> 4 != 4 {
+            println!("Not IPv4, discarding");
+            return;
+        }
+        let ip = packet.ip_header_mut().unwrap();
+        ip.bswap();
+        let (protocol, header_len, total_len) =
+            (ip.protocol, ip.header_len() as usize, ip.total_len as usize);
+        if header_len < size_of::<IpHeader>()
+            || header_len > total_len
+            || l3 + total_len > packet.data.len()
+        {
+            println!("ip: malformed header, discarding");
+            return;
+        }
+        // Whatever the link padded the packet with
+        packet.data.truncate(l3 + total_len);
+        packet.l4_offset = Some((l3 + header_len) as isize);
+        match protocol {
+            IpProtocol::ICMP => self.on_icmp(packet, now),
+            IpProtocol::TCP => {
+                if let Some(segment) = Segment::parse(packet) {
+                    self.tcp.on_segment(segment, now);
+                }
+            }
+            IpProtocol::UDP => {
+                if let Some(datagram) = Datagram::parse(packet)
+                    .and_then(|d| self.udp.on_datagram(d))
+                {
+                    self.outgoing
+                        .extend(icmp::port_unreachable(&datagram.packet));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the timers that are due and takes every packet there is to
+    /// send on the interface.
+    pub fn poll(&mut self, now: Instant) -> Vec<Packet> {
+        self.tcp.on_tick(now);
+        let mut packets: Vec<Packet> = self.outgoing.drain(..).collect();
+        packets.extend(self.tcp.take_outgoing());
+        packets.extend(self.udp.take_outgoing());
+        packets
+    }
+
+    /// When `poll` should be called next, if nothing arrives before then.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tcp.next_deadline()
+    }
+
+    pub fn socket(&mut self, type_: SocketType) -> SocketHandle {
+        self.insert(Socket {
+            type_,
+            local: None,
+            state: SocketState::Unconnected,
+            options: HashMap::new(),
+        })
+    }
+
+    /// Binds to the stack's address or the unspecified one, which are the
+    /// same thing here. Port zero picks an ephemeral port.
+    pub fn bind(
+        &mut self,
+        handle: SocketHandle,
+        addr: SocketAddrV4,
+    ) -> Result<()> {
+        if !addr.ip().is_unspecified() && *addr.ip() != self.addr {
+            return Err(ErrorKind::AddrNotAvailable.into());
+        }
+        let socket = self.get(handle)?;
+        if socket.local.is_some() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let type_ = socket.type_;
+        let port = match addr.port() {
+            0 => self.ephemeral_port(type_)?,
+            port if self.port_in_use(type_, port) => {
+                return Err(ErrorKind::AddrInUse.into())
+            }
+            port => port,
+        };
+        let local = SocketAddrV4::new(*addr.ip(), port);
+        if type_ == SocketType::Datagram {
+            self.udp.bind(local)?;
+        }
+        self.get_mut(handle)?.local = Some(local);
+        Ok(())
+    }
+
+    /// Listening again changes the backlog. An unbound socket is bound to
+    /// an ephemeral port first.
+    pub fn listen(
+        &mut self,
+        handle: SocketHandle,
+        backlog: usize,
+    ) -> Result<()> {
+        let socket = self.get(handle)?;
+        if socket.type_ != SocketType::Stream {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        if let SocketState::Connected(_) = socket.state {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let port = self.autobind(handle)?.port();
+        self.tcp.listen(port, backlog);
+        let socket = self.get_mut(handle)?;
+        socket.state = SocketState::Listening;
+        if let Some(&SocketOption::FastOpen(enabled)) =
+            socket.options.get(&SocketOptionKind::FastOpen)
+        {
+            self.tcp.set_fast_open(port, enabled)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a new socket for the next connection that completed its
+    /// handshake, along with the peer's address. It starts out with the
+    /// options of the listening socket.
+    pub fn accept(
+        &mut self,
+        handle: SocketHandle,
+        now: Instant,
+    ) -> Result<(SocketHandle, SocketAddrV4)> {
+        let socket = self.get(handle)?;
+        if socket.type_ != SocketType::Stream {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let (SocketState::Listening, Some(local)) =
+            (socket.state, socket.local)
+        else {
+            return Err(ErrorKind::InvalidInput.into());
+        };
+        let options = socket.options.clone();
+        let quad =
+            self.tcp.accept(local.port()).ok_or(ErrorKind::WouldBlock)?;
+        for &option in options.values() {
+            if let Err(error) = set_option(&mut self.tcp, quad, option, now) {
+                self.tcp.abort(quad, now);
+                return Err(error);
+            }
+        }
+        let accepted = self.insert(Socket {
+            type_: SocketType::Stream,
+            local: Some(SocketAddrV4::new(
+                quad.local_addr.into(),
+                quad.local_port,
+            )),
+            state: SocketState::Connected(quad),
+            options,
+        });
+        Ok((accepted, remote_addr(quad)))
+    }
+
+    /// Starts connecting to `addr`, binding to an ephemeral port first if
+    /// the socket is not bound yet. A datagram socket only takes datagrams
+    /// from `addr` from then on, and may be connected again elsewhere.
+    pub fn connect(
+        &mut self,
+        handle: SocketHandle,
+        addr: SocketAddrV4,
+        now: Instant,
+    ) -> Result<()> {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let socket = self.get(handle)?;
+        let type_ = socket.type_;
+        if type_ == SocketType::Stream
+            && socket.state != SocketState::Unconnected
+        {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let bound = socket.local.is_some();
+        let local = self.autobind(handle)?;
+        let quad = Quad {
+            local_addr: self.addr.into(),
+            local_port: local.port(),
+            remote_addr: (*addr.ip()).into(),
+            remote_port: addr.port(),
+        };
+        if let Err(error) = self.start_connection(handle, quad, now) {
+            if !bound {
+                self.unbind(handle);
+            }
+            return Err(error);
+        }
+        self.get_mut(handle)?.state = SocketState::Connected(quad);
+        Ok(())
+    }
+
+    pub fn send(
+        &mut self,
+        handle: SocketHandle,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<usize> {
+        let quad = self.connection(handle)?;
+        match self.get(handle)?.type_ {
+            SocketType::Stream => self.tcp.send(quad, data, now),
+            SocketType::Datagram => {
+                self.udp.send_to(quad.local_port, data, None)
+            }
+        }
+    }
+
+    /// Sends a datagram to `addr`, binding to an ephemeral port first if the
+    /// socket is not bound yet. On a stream socket `addr` is ignored, like
+    /// on Linux.
+    pub fn send_to(
+        &mut self,
+        handle: SocketHandle,
+        data: &[u8],
+        addr: SocketAddrV4,
+        now: Instant,
+    ) -> Result<usize> {
+        if self.get(handle)?.type_ == SocketType::Stream {
+            return self.send(handle, data, now);
+        }
+        let local = self.autobind(handle)?;
+        self.udp.send_to(local.port(), data, Some(addr))
+    }
+
+    /// Returns `Ok(0)` at the end of the stream.
+    pub fn recv(
+        &mut self,
+        handle: SocketHandle,
+        buf: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        Ok(self.recv_from(handle, buf, now)?.0)
+    }
+
+    /// Like `recv`, along with where the data came from. Datagrams that do
+    /// not fit into `buf` are cut short.
+    pub fn recv_from(
+        &mut self,
+        handle: SocketHandle,
+        buf: &mut [u8],
+        now: Instant,
+    ) -> Result<(usize, SocketAddrV4)> {
+        let socket = self.get(handle)?;
+        match (socket.type_, socket.local) {
+            (SocketType::Stream, _) => {
+                let quad = self.connection(handle)?;
+                Ok((self.tcp.recv(quad, buf, now)?, remote_addr(quad)))
+            }
+            (SocketType::Datagram, Some(local)) => {
+                self.udp.recv_from(local.port(), buf)
+            }
+            // Nothing can arrive before there is a port to arrive at
+            (SocketType::Datagram, None) => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    pub fn shutdown(
+        &mut self,
+        handle: SocketHandle,
+        how: Shutdown,
+        now: Instant,
+    ) -> Result<()> {
+        if self.get(handle)?.type_ != SocketType::Stream {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let quad = self.connection(handle)?;
+        self.tcp.shutdown(quad, how, now)
+    }
+
+    /// Closes the socket and frees its port. Its connection carries on
+    /// closing without it, while connections a listener has not accepted
+    /// yet are reset.
+    pub fn close(&mut self, handle: SocketHandle, now: Instant) -> Result<()> {
+        let socket = self
+            .sockets
+            .remove(&handle)
+            .ok_or(ErrorKind::InvalidInput)?;
+        match (socket.type_, socket.state, socket.local) {
+            (SocketType::Datagram, _, Some(local)) => {
+                self.udp.close(local.port())
+            }
+            (SocketType::Stream, SocketState::Listening, Some(local)) => {
+                self.tcp.unlisten(local.port(), now)
+            }
+            (SocketType::Stream, SocketState::Connected(quad), _) => {
+                self.tcp.close(quad, now)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The local address, unspecified until the socket is bound.
+    pub fn getsockname(&self, handle: SocketHandle) -> Result<SocketAddrV4> {
+        let socket = self.get(handle)?;
+        Ok(match (socket.state, socket.local) {
+            (SocketState::Connected(quad), _) => {
+                SocketAddrV4::new(quad.local_addr.into(), quad.local_port)
+            }
+            (_, Some(local)) => local,
+            (_, None) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        })
+    }
+
+    pub fn getpeername(&self, handle: SocketHandle) -> Result<SocketAddrV4> {
+        Ok(remote_addr(self.connection(handle)?))
+    }
+
+    pub fn getsockopt(
+        &self,
+        handle: SocketHandle,
+        kind: SocketOptionKind,
+    ) -> Result<SocketOption> {
+        let socket = self.get(handle)?;
+        if !kind.applies_to(socket.type_) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        if let Some(&option) = socket.options.get(&kind) {
+            return Ok(option);
+        }
+        Ok(match kind {
+            SocketOptionKind::NoDelay => SocketOption::NoDelay(false),
+            SocketOptionKind::Cork => SocketOption::Cork(false),
+            SocketOptionKind::KeepAlive => SocketOption::KeepAlive(None),
+            SocketOptionKind::Linger => SocketOption::Linger(None),
+            SocketOptionKind::Congestion => SocketOption::Congestion(
+                self.tcp.default_congestion_algorithm(),
+            ),
+            SocketOptionKind::RackTlp => SocketOption::RackTlp(true),
+            SocketOptionKind::FastOpen => SocketOption::FastOpen(false),
+            SocketOptionKind::Error => {
+                SocketOption::Error(match (socket.type_, socket.state) {
+                    (SocketType::Stream, SocketState::Connected(quad)) => {
+                        self.tcp.connection(quad).and_then(|tcb| tcb.error())
+                    }
+                    _ => None,
+                })
+            }
+            SocketOptionKind::RecvDrops => {
+                SocketOption::RecvDrops(match socket.local {
+                    Some(local) => self.udp.drops(local.port())?,
+                    None => 0,
+                })
+            }
+        })
+    }
+
+    pub fn setsockopt(
+        &mut self,
+        handle: SocketHandle,
+        option: SocketOption,
+        now: Instant,
+    ) -> Result<()> {
+        let socket = self.get(handle)?;
+        if !option.kind().applies_to(socket.type_) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        if option.kind().is_read_only() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        match (socket.state, socket.local, option) {
+            (SocketState::Connected(quad), _, _) => {
+                set_option(&mut self.tcp, quad, option, now)?
+            }
+            (
+                SocketState::Listening,
+                Some(local),
+                SocketOption::FastOpen(enabled),
+            ) => self.tcp.set_fast_open(local.port(), enabled)?,
+            _ => {}
+        }
+        self.get_mut(handle)?.options.insert(option.kind(), option);
+        Ok(())
+    }
+
+    fn on_icmp(&mut self, mut packet: Packet, now: Instant) {
+        let l4 = packet.l4_offset.unwrap() as usize;
+        if l4 + size_of::<IcmpHeader>() > packet.data.len() {
+            println!("icmp: truncated header, discarding");
+            return;
+        }
+        let header = packet.icmp_header().unwrap();
+        let (type_, code) = (header.type_, header.code);
+        if type_ == IcmpType::ECHO_REQUEST {
+            self.outgoing.push_back(icmp::echo_reply(&mut packet));
+        } else if type_ == IcmpType::DESTINATION_UNREACHABLE
+            && code == icmp::FRAGMENTATION_NEEDED
+        {
+            self.on_fragmentation_needed(&packet, now);
+        }
+    }
+
+    /// RFC 1191 section 4: the next-hop MTU takes the low half of the unused
+    /// word, followed by the IP header and the first 8 bytes of the datagram
+    /// that did not fit. For TCP those hold the ports and sequence number.
+    fn on_fragmentation_needed(&mut self, packet: &Packet, now: Instant) {
+        let start = packet.l4_offset.unwrap() as usize + 4;
+        let data = &packet.data[start.min(packet.data.len())..];
+        if data.len() < 4 + size_of::<IpHeader>() {
+            return;
+        }
+        let next_hop = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let mut quoted = Packet::new(data[4..].to_vec());
+        let original = {
+            let ip = quoted.ip_header_mut().unwrap();
+            ip.bswap();
+            *ip
+        };
+        let l4 = original.header_len() as usize;
+        if original.protocol != IpProtocol::TCP || quoted.data.len() < l4 + 8 {
+            return;
+        }
+        let tcp_start = &quoted.data[l4..l4 + 8];
+        let quad = Quad {
+            local_addr: original.source,
+            local_port: u16::from_be_bytes([tcp_start[0], tcp_start[1]]),
+            remote_addr: original.destination,
+            remote_port: u16::from_be_bytes([tcp_start[2], tcp_start[3]]),
+        };
+        let seq = u32::from_be_bytes(tcp_start[4..8].try_into().unwrap());
+        self.tcp.on_packet_too_big(
+            quad,
+            seq,
+            next_hop,
+            original.total_len as usize,
+            now,
+        );
+    }
+
+    fn insert(&mut self, socket: Socket) -> SocketHandle {
+        let handle = SocketHandle(self.next_handle);
+        self.next_handle += 1;
+        self.sockets.insert(handle, socket);
+        handle
+    }
+
+    fn get(&self, handle: SocketHandle) -> Result<&Socket> {
+        Ok(self.sockets.get(&handle).ok_or(ErrorKind::InvalidInput)?)
+    }
+
+    fn get_mut(&mut self, handle: SocketHandle) -> Result<&mut Socket> {
+        Ok(self
+            .sockets
+            .get_mut(&handle)
+            .ok_or(ErrorKind::InvalidInput)?)
+    }
+
+    fn connection(&self, handle: SocketHandle) -> Result<Quad> {
+        match self.get(handle)?.state {
+            SocketState::Connected(quad) => Ok(quad),
+            _ => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Connects the stack underneath to `quad`, with the socket's options
+    /// applied. Nothing is left of the connection if that fails.
+    fn start_connection(
+        &mut self,
+        handle: SocketHandle,
+        quad: Quad,
+        now: Instant,
+    ) -> Result<()> {
+        let socket = &self.sockets[&handle];
+        if socket.type_ == SocketType::Datagram {
+            return self.udp.connect(quad.local_port, remote_addr(quad));
+        }
+        match socket.options.get(&SocketOptionKind::FastOpen) {
+            Some(SocketOption::FastOpen(true)) => {
+                self.tcp.connect_fast_open(quad, now)?
+            }
+            _ => self.tcp.connect(quad, now)?,
+        }
+        for &option in socket.options.values() {
+            if let Err(error) = set_option(&mut self.tcp, quad, option, now) {
+                self.tcp.abort(quad, now);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes `autobind`.
+    fn unbind(&mut self, handle: SocketHandle) {
+        let socket = self.sockets.get_mut(&handle).unwrap();
+        if let Some(local) = socket.local.take() {
+            if socket.type_ == SocketType::Datagram {
+                self.udp.close(local.port());
+            }
+        }
+    }
+
+    /// Binds the socket to an ephemeral port unless it is bound already.
+    fn autobind(&mut self, handle: SocketHandle) -> Result<SocketAddrV4> {
+        if let Some(local) = self.get(handle)?.local {
+            return Ok(local);
+        }
+        self.bind(handle, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        Ok(self.get(handle)?.local.unwrap())
+    }
+
+    /// TCP and UDP ports are separate.
+    fn port_in_use(&self, type_: SocketType, port: u16) -> bool {
+        match type_ {
+            SocketType::Stream => self.sockets.values().any(|socket| {
+                socket.type_ == SocketType::Stream
+                    && socket.local.is_some_and(|l| l.port() == port)
+            }),
+            SocketType::Datagram => self.udp.is_bound(port),
+        }
+    }
+
+    /// A free port, searched for from a random starting point to make them
+    /// harder to guess, RFC 6056 section 3.3.1.
+    fn ephemeral_port(&self, type_: SocketType) -> Result<u16> {
+        let first = *EPHEMERAL_PORTS.start();
+        let count = EPHEMERAL_PORTS.end() - first + 1;
+        let offset = rand::thread_rng().gen_range(0..count);
+        (0..count)
+            .map(|i| first + (offset + i) % count)
+            .find(|&port| !self.port_in_use(type_, port))
+            .ok_or(ErrorKind::AddrNotAvailable.into())
+    }
+}
+
+fn remote_addr(quad: Quad) -> SocketAddrV4 {
+    SocketAddrV4::new(quad.remote_addr.into(), quad.remote_port)
+}
+
+fn set_option(
+    tcp: &mut TcpStack,
+    quad: Quad,
+    option: SocketOption,
+    now: Instant,
+) -> Result<()> {
+    match option {
+        SocketOption::NoDelay(nodelay) => tcp.set_nodelay(quad, nodelay, now),
+        SocketOption::Cork(cork) => tcp.set_cork(quad, cork, now),
+        SocketOption::KeepAlive(keepalive) => {
+            tcp.set_keepalive(quad, keepalive)
+        }
+        SocketOption::Linger(linger) => tcp.set_linger(quad, linger),
+        SocketOption::Congestion(algorithm) => {
+            tcp.set_congestion_algorithm(quad, algorithm)
+        }
+        SocketOption::RackTlp(enabled) => tcp.set_rack_tlp(quad, enabled),
+        // Only matters until the connection is made
+        SocketOption::FastOpen(_) => Ok(()),
+        SocketOption::Error(_) | SocketOption::RecvDrops(_) => {
+            Err(ErrorKind::InvalidInput.into())
+        }
+    }
+}
+
+/// The packet as it arrives on the other end of the wire.
+#[cfg(test)]
+fn loopback(packet: &Packet) -> Packet {
+    Packet::new(packet.whole().unwrap().to_vec())
+}
+
+/// Delivers everything two stacks send each other until both go quiet.
+#[cfg(test)]
+fn exchange(a: &mut Sockets, b: &mut Sockets, now: Instant) {
+    loop {
+        let (to_b, to_a) = (a.poll(now), b.poll(now));
+        if to_b.is_empty() && to_a.is_empty() {
+            return;
+        }
+        for packet in to_b {
+            b.on_packet(loopback(&packet), now);
+        }
+        for packet in to_a {
+            a.on_packet(loopback(&packet), now);
+        }
+    }
+}
+
+#[test]
+fn test_sockets() {
+    let now = Instant::now();
+    let mut server = Sockets::new(Ipv4Addr::new(10, 0, 0, 2));
+    let mut client = Sockets::new(Ipv4Addr::new(10, 0, 0, 1));
+    let server_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 7);
+    let listener = server.socket(SocketType::Stream);
+    server
+        .setsockopt(listener, SocketOption::NoDelay(true), now)
+        .unwrap();
+    server
+        .bind(listener, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 7))
+        .unwrap();
+    server.listen(listener, 8).unwrap();
+    let other = server.socket(SocketType::Stream);
+    let error = server.bind(other, server_addr).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::AddrInUse);
+    let error = server.accept(listener, now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+    // Connecting binds an ephemeral port, and what is sent before the
+    // handshake completes goes out after it
+    let socket = client.socket(SocketType::Stream);
+    client.connect(socket, server_addr, now).unwrap();
+    let client_addr = client.getsockname(socket).unwrap();
+    assert!(EPHEMERAL_PORTS.contains(&client_addr.port()));
+    client.send(socket, b"hello", now).unwrap();
+    exchange(&mut client, &mut server, now);
+
+    // The accepted socket takes on the listener's options
+    let (accepted, peer) = server.accept(listener, now).unwrap();
+    assert_eq!(peer, client_addr);
+    assert_eq!(server.getpeername(accepted).unwrap(), client_addr);
+    let quad = server.connection(accepted).unwrap();
+    assert!(server.tcp().connection(quad).unwrap().nodelay());
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(accepted, &mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+
+    // Closing the listener resets what it did not accept yet, but leaves
+    // the accepted connection be
+    let pending = client.socket(SocketType::Stream);
+    client.connect(pending, server_addr, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    server.close(listener, now).unwrap();
+    server.send(accepted, b"bye", now).unwrap();
+    server.close(accepted, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    let error = client.send(pending, b"hello", now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    assert_eq!(client.recv(socket, &mut buf, now).unwrap(), 3);
+    assert_eq!(client.recv(socket, &mut buf, now).unwrap(), 0);
+    let error = server.recv(accepted, &mut buf, now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+    // A connect that fails leaves the socket unconnected
+    client.close(socket, now).unwrap();
+    let again = client.socket(SocketType::Stream);
+    client.bind(again, client_addr).unwrap();
+    let error = client.connect(again, server_addr, now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::AddrInUse);
+    let error = client.getpeername(again).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotConnected);
+    assert_eq!(client.getsockname(again).unwrap(), client_addr);
+
+    // Now nobody is listening
+    let refused = client.socket(SocketType::Stream);
+    client.connect(refused, server_addr, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert_eq!(
+        client.getsockopt(refused, SocketOptionKind::Error).unwrap(),
+        SocketOption::Error(Some(ErrorKind::ConnectionRefused))
+    );
+}
+
+#[test]
+fn test_datagram_sockets() {
+    let now = Instant::now();
+    let mut server = Sockets::new(Ipv4Addr::new(10, 0, 0, 2));
+    let mut client = Sockets::new(Ipv4Addr::new(10, 0, 0, 1));
+    let server_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 53);
+    let socket = server.socket(SocketType::Datagram);
+    server.bind(socket, server_addr).unwrap();
+    let error = server.listen(socket, 8).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Unsupported);
+    let error = server
+        .setsockopt(socket, SocketOption::NoDelay(true), now)
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+    // UDP ports don't get in the way of TCP ones
+    let stream = server.socket(SocketType::Stream);
+    server.bind(stream, server_addr).unwrap();
+
+    // Sending binds an ephemeral port, which the reply comes back to
+    let unconnected = client.socket(SocketType::Datagram);
+    let error = client.send(unconnected, b"query", now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotConnected);
+    client
+        .send_to(unconnected, b"query", server_addr, now)
+        .unwrap();
+    exchange(&mut client, &mut server, now);
+    let mut buf = [0; 16];
+    let (n, from) = server.recv_from(socket, &mut buf, now).unwrap();
+    assert_eq!(&buf[..n], b"query");
+    assert_eq!(from.port(), client.getsockname(unconnected).unwrap().port());
+    server.send_to(socket, b"answer", from, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.recv(unconnected, &mut buf, now).unwrap(), 6);
+
+    // A connected socket does not hear from anyone else
+    let connected = client.socket(SocketType::Datagram);
+    client.connect(connected, server_addr, now).unwrap();
+    client.send(connected, b"hello", now).unwrap();
+    exchange(&mut client, &mut server, now);
+    let (_, from) = server.recv_from(socket, &mut buf, now).unwrap();
+    assert_eq!(from, client.getsockname(connected).unwrap());
+    assert_eq!(client.getpeername(connected).unwrap(), server_addr);
+    let other = server.socket(SocketType::Datagram);
+    server.send_to(other, b"spoofed", from, now).unwrap();
+    server.send_to(socket, b"world", from, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.recv(connected, &mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+    let error = client.recv(connected, &mut buf, now).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::WouldBlock);
+    assert_eq!(
+        client
+            .getsockopt(connected, SocketOptionKind::RecvDrops)
+            .unwrap(),
+        SocketOption::RecvDrops(0)
+    );
+
+    // Nobody is bound to the port, which the sender hears about over ICMP,
+    // even for a first fragment whose TTL ran out
+    let closed = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 54);
+    client.send_to(unconnected, b"query", closed, now).unwrap();
+    for packet in client.poll(now) {
+        let mut packet = loopback(&packet);
+        packet.data[6] = (IpHeader::MF_BIT >> 8) as u8;
+        packet.data[8] = 0;
+        server.on_packet(packet, now);
+    }
+    let replies = server.poll(now);
+    assert_eq!(replies.len(), 1);
+    let mut reply = loopback(&replies[0]);
+    let ip = reply.ip_header_mut().unwrap();
+    ip.bswap();
+    let (protocol, destination) = (ip.protocol, ip.destination);
+    assert_eq!(protocol, IpProtocol::ICMP);
+    assert_eq!(destination, Ipv4Addr::new(10, 0, 0, 1).into());
+    assert!(ip.df_bit() && !ip.mf_bit());
+    assert_eq!(ip.ttl, 64);
+    reply.l4_offset = Some(ip.header_len() as isize);
+    let header = reply.icmp_header().unwrap();
+    assert_eq!(header.type_, IcmpType::DESTINATION_UNREACHABLE);
+    assert_eq!(header.code, icmp::PORT_UNREACHABLE);
+}
+
+#[test]
+fn test_malformed_packets() {
+    use crate::AsSlice;
+
+    let now = Instant::now();
+    let mut sockets = Sockets::new(Ipv4Addr::new(10, 0, 0, 2));
+    let ip_packet = |protocol, total_len: usize, l4: &[u8]| {
+        let mut ip = IpHeader::new(protocol, 0x0a00_0001, 0x0a00_0002);
+        ip.total_len = total_len as u16;
+        ip.bswap();
+        ip.set_checksum();
+        let mut data = ip.as_slice().to_vec();
+        data.extend(l4);
+        data
+    };
+    let echo_request = [8, 0, 0, 0, 0, 1, 0, 1, b'h', b'i'];
+
+    // Cut off in the IP header, one whose length points past the packet,
+    // and ones that are longer than what arrived
+    let mut long_header = ip_packet(IpProtocol::ICMP, 30, &echo_request);
+    long_header[0] = 0x4f;
+    let malformed = [
+        ip_packet(IpProtocol::ICMP, 30, &echo_request)[..10].to_vec(),
+        long_header,
+        ip_packet(IpProtocol::ICMP, 100, &echo_request),
+        ip_packet(IpProtocol::ICMP, 22, &echo_request[..2]),
+        ip_packet(IpProtocol::UDP, 24, &[0x13, 0x88, 0x13, 0x88]),
+        ip_packet(IpProtocol::TCP, 30, &[0; 10]),
+    ];
+    for data in malformed {
+        sockets.on_packet(Packet::new(data), now);
+    }
+    assert!(sockets.poll(now).is_empty());
+
+    // Padding after the packet is not echoed back
+    let mut data = ip_packet(IpProtocol::ICMP, 30, &echo_request);
+    data.extend([0; 4]);
+    sockets.on_packet(Packet::new(data), now);
+    let replies = sockets.poll(now);
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].len(), Some(30));
+    assert_eq!(&replies[0].whole().unwrap()[24..], &echo_request[4..]);
+}
@@ -0,0 +1,529 @@
+use crate::ip::{Ecn, IpHeader, IpProtocol};
+use crate::packet::Packet;
+use crate::{network_checksum_2part, AsSlice};
+use std::fmt;
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+mod auth;
+mod congestion;
+mod fastopen;
+mod isn;
+mod options;
+mod pmtu;
+mod rack;
+mod rate;
+mod reassembly;
+mod retransmit;
+mod stack;
+mod syncookie;
+mod tcb;
+
+pub use auth::AuthKey;
+pub use congestion::CongestionAlgorithm;
+pub use options::TcpOption;
+pub use stack::TcpStack;
+pub use tcb::{Keepalive, Tcb};
+
+#[repr(transparent)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+pub struct TcpFlags(u8);
+
+impl TcpFlags {
+    pub const FIN: Self = Self(0x01);
+    pub const SYN: Self = Self(0x02);
+    pub const RST: Self = Self(0x04);
+    pub const PSH: Self = Self(0x08);
+    pub const ACK: Self = Self(0x10);
+    pub const URG: Self = Self(0x20);
+    pub const ECE: Self = Self(0x40);
+    pub const CWR: Self = Self(0x80);
+
+    const NAMES: [(Self, &'static str); 8] = [
+        (Self::FIN, "FIN"),
+        (Self::SYN, "SYN"),
+        (Self::RST, "RST"),
+        (Self::PSH, "PSH"),
+        (Self::ACK, "ACK"),
+        (Self::URG, "URG"),
+        (Self::ECE, "ECE"),
+        (Self::CWR, "CWR"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for TcpFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for TcpFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for TcpFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl fmt::Debug for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "TcpFlags({})", names.join(" | "))
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct TcpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub data_offset: u8,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct TcpIpPseudoHeader {
+    source_ip: u32,
+    destination_ip: u32,
+    zero: u8,
+    protocol: IpProtocol,
+    len: u16,
+}
+
+impl TcpHeader {
+    pub const MIN_LEN: usize = size_of::<Self>();
+
+    pub fn new(source_port: u16, destination_port: u16) -> Self {
+        Self {
+            source_port,
+            destination_port,
+            seq: 0,
+            ack: 0,
+            data_offset: (Self::MIN_LEN as u8 / 4) << 4,
+            flags: TcpFlags::empty(),
+            window: 0,
+            checksum: 0,
+            urgent: 0,
+        }
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.data_offset >> 4) as usize * 4
+    }
+
+    pub fn reply_header(&self) -> Self {
+        Self::new(self.destination_port, self.source_port)
+    }
+
+    pub fn bswap(&mut self) {
+        self.source_port = self.source_port.swap_bytes();
+        self.destination_port = self.destination_port.swap_bytes();
+        self.seq = self.seq.swap_bytes();
+        self.ack = self.ack.swap_bytes();
+        self.window = self.window.swap_bytes();
+        self.checksum = self.checksum.swap_bytes();
+        self.urgent = self.urgent.swap_bytes();
+    }
+
+    // Safety assertions:
+    // - `len` bytes of header, options and data follow `self`.
+    // - all fields are big-endian.
+    unsafe fn ip_checksum(
+        &self,
+        source_ip: u32,
+        destination_ip: u32,
+        len: usize,
+    ) -> u16 {
+        let pseudo_header = TcpIpPseudoHeader {
+            source_ip,
+            destination_ip,
+            zero: 0,
+            protocol: IpProtocol::TCP,
+            len: (len as u16).swap_bytes(),
+        };
+
+        network_checksum_2part(
+            self as *const TcpHeader as *const u16,
+            len,
+            &pseudo_header as *const TcpIpPseudoHeader as *const u16,
+            size_of::<TcpIpPseudoHeader>(),
+            self.checksum,
+        )
+    }
+
+    /// # Safety
+    ///
+    /// `len` bytes of header, options and data have to follow `self`, with
+    /// all fields big-endian.
+    pub unsafe fn set_ip_checksum(
+        &mut self,
+        source_ip: u32,
+        destination_ip: u32,
+        len: usize,
+    ) {
+        self.checksum = self.ip_checksum(source_ip, destination_ip, len);
+    }
+
+    /// # Safety
+    ///
+    /// `len` bytes of header, options and data have to follow `self`, with
+    /// all fields big-endian.
+    pub unsafe fn verify_ip_checksum(
+        &self,
+        source_ip: u32,
+        destination_ip: u32,
+        len: usize,
+    ) -> bool {
+        self.ip_checksum(source_ip, destination_ip, len) == self.checksum
+    }
+}
+
+impl AsSlice for TcpHeader {}
+
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    seq_lt(b, a)
+}
+
+pub fn seq_ge(a: u32, b: u32) -> bool {
+    seq_le(b, a)
+}
+
+/// The addresses and ports identifying one TCP connection, in native byte
+/// order, from the point of view of this stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Quad {
+    pub local_addr: u32,
+    pub local_port: u16,
+    pub remote_addr: u32,
+    pub remote_port: u16,
+}
+
+impl Quad {
+    /// The addresses and ports in network byte order, as fed into MACs.
+    pub fn to_be_bytes(&self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&self.local_addr.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.local_port.to_be_bytes());
+        bytes[6..10].copy_from_slice(&self.remote_addr.to_be_bytes());
+        bytes[10..].copy_from_slice(&self.remote_port.to_be_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for Quad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} <-> {}:{}",
+            Ipv4Addr::from(self.local_addr),
+            self.local_port,
+            Ipv4Addr::from(self.remote_addr),
+            self.remote_port
+        )
+    }
+}
+
+/// An incoming TCP segment. The header fields are copied out in native byte
+/// order and the packet's `data_offset` points at the payload.
+pub struct Segment {
+    pub quad: Quad,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub urgent: u16,
+    pub options: Vec<TcpOption>,
+    pub packet: Packet,
+}
+
+impl Segment {
+    /// Takes a packet with its IP header in native byte order and `l4_offset`
+    /// set, as `handle_ip` leaves it. Returns `None` if the segment is
+    /// truncated, fails its checksum or has malformed options.
+    pub fn parse(mut packet: Packet) -> Option<Self> {
+        let ip = *packet.ip_header()?;
+        let l4_offset = packet.l4_offset? as usize;
+        let end = packet.data.len().min(ip.total_len as usize);
+        let tcp_len = end.checked_sub(l4_offset)?;
+        if tcp_len < TcpHeader::MIN_LEN {
+            return None;
+        }
+        packet.data.truncate(end);
+
+        let tcp = packet.tcp_header_mut()?;
+        let header_len = tcp.header_len();
+        if header_len < TcpHeader::MIN_LEN || header_len > tcp_len {
+            return None;
+        }
+        // SAFETY: `tcp_len` bytes of the packet follow the header, checked
+        // above.
+        let valid = unsafe {
+            tcp.verify_ip_checksum(
+                ip.source.swap_bytes(),
+                ip.destination.swap_bytes(),
+                tcp_len,
+            )
+        };
+        if !valid {
+            println!("tcp: bad checksum, discarding");
+            return None;
+        }
+        tcp.bswap();
+        let tcp = *tcp;
+
+        let options_start = l4_offset + TcpHeader::MIN_LEN;
+        let options = &packet.data[options_start..l4_offset + header_len];
+        let Some(options) = TcpOption::parse(options) else {
+            println!("tcp: malformed options, discarding");
+            return None;
+        };
+        packet.data_offset = Some((l4_offset + header_len) as isize);
+        Some(Self {
+            quad: Quad {
+                local_addr: ip.destination,
+                local_port: tcp.destination_port,
+                remote_addr: ip.source,
+                remote_port: tcp.source_port,
+            },
+            seq: tcp.seq,
+            ack: tcp.ack,
+            flags: tcp.flags,
+            window: tcp.window,
+            urgent: tcp.urgent,
+            options,
+            packet,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.packet.data().unwrap()
+    }
+
+    /// The ECN codepoint of the IP header it came in.
+    pub fn ecn(&self) -> Ecn {
+        self.packet.ip_header().unwrap().ecn()
+    }
+
+    pub fn mss(&self) -> Option<u16> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::Mss(mss) => Some(*mss),
+            _ => None,
+        })
+    }
+
+    pub fn window_scale(&self) -> Option<u8> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::WindowScale(shift) => Some(*shift),
+            _ => None,
+        })
+    }
+
+    /// TSval and TSecr.
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::Timestamps { value, echo } => Some((*value, *echo)),
+            _ => None,
+        })
+    }
+
+    /// The Fast Open cookie, empty for a request.
+    pub fn fast_open(&self) -> Option<&[u8]> {
+        self.options.iter().find_map(|option| match option {
+            TcpOption::FastOpen(cookie) => Some(cookie.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Whether the segment carries an MD5 signature or TCP-AO MAC.
+    pub fn is_signed(&self) -> bool {
+        self.options.iter().any(|option| {
+            matches!(
+                option,
+                TcpOption::Md5Signature(_) | TcpOption::Authentication { .. }
+            )
+        })
+    }
+
+    /// Whether the SYN asks for ECN, or the SYN-ACK agrees to it, RFC 3168
+    /// section 6.1.1.
+    pub fn is_ecn_setup(&self) -> bool {
+        let ecn = self.flags & (TcpFlags::ECE | TcpFlags::CWR);
+        if self.flags.contains(TcpFlags::ACK) {
+            ecn == TcpFlags::ECE
+        } else {
+            ecn == TcpFlags::ECE | TcpFlags::CWR
+        }
+    }
+
+    pub fn sack_permitted(&self) -> bool {
+        self.options.contains(&TcpOption::SackPermitted)
+    }
+
+    pub fn sack_blocks(&self) -> &[(u32, u32)] {
+        self.options
+            .iter()
+            .find_map(|option| match option {
+                TcpOption::Sack(blocks) => Some(blocks.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The amount of sequence space the segment occupies, counting SYN and
+    /// FIN.
+    pub fn len(&self) -> u32 {
+        let mut len = self.data().len() as u32;
+        if self.flags.contains(TcpFlags::SYN) {
+            len += 1;
+        }
+        if self.flags.contains(TcpFlags::FIN) {
+            len += 1;
+        }
+        len
+    }
+
+    /// Whether the segment occupies no sequence space, like a bare ACK.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Builds a complete IP packet carrying a TCP segment from `quad.local_*` to
+/// `quad.remote_*`, with `ecn` in its IP header. `header` is given in native
+/// byte order, everything in the returned packet is in network byte order
+/// with checksums filled in.
+pub fn make_packet(
+    quad: Quad,
+    mut header: TcpHeader,
+    options: &[TcpOption],
+    data: &[u8],
+    ecn: Ecn,
+) -> Packet {
+    let options = TcpOption::encode(options);
+    let header_len = TcpHeader::MIN_LEN + options.len();
+    header.data_offset = ((header_len / 4) as u8) << 4;
+    let mut packet = Packet::new_from_data(data);
+    packet.fill_l4_with_options(header, &options);
+    let mut ip =
+        IpHeader::new(IpProtocol::TCP, quad.local_addr, quad.remote_addr);
+    ip.set_ecn(ecn);
+    packet.fill_l3(ip);
+    let total_len = packet.len().unwrap();
+    let tcp_len = total_len - size_of::<IpHeader>();
+    packet.ip_header_mut().unwrap().total_len = total_len as u16;
+    packet.tcp_header_mut().unwrap().bswap();
+    packet.ip_header_mut().unwrap().bswap();
+    // SAFETY: the packet was just built with `tcp_len` bytes of TCP header
+    // and data.
+    unsafe {
+        packet.tcp_header_mut().unwrap().set_ip_checksum(
+            quad.local_addr.to_be(),
+            quad.remote_addr.to_be(),
+            tcp_len,
+        );
+    }
+    packet.ip_header_mut().unwrap().set_checksum();
+    packet
+}
+
+/// Feeds a packet built by `make_packet` back through the receive path, as
+/// if it had been read from the interface by the peer.
+#[cfg(test)]
+pub fn loopback(packet: &Packet) -> Segment {
+    let mut packet = Packet::new(packet.whole().unwrap().to_vec());
+    packet.ip_header_mut().unwrap().bswap();
+    packet.l4_offset = Some(packet.ip_header().unwrap().header_len() as isize);
+    Segment::parse(packet).expect("valid segment")
+}
+
+#[test]
+fn test_tcp_checksum() {
+    // SYN from 10.0.0.1:48262 to 10.0.0.2:7, captured from `nc`.
+    let buffer: &[u8] = &[
+        0x45, 0x00, 0x00, 0x3c, 0x5c, 0x6d, 0x40, 0x00, 0x40, 0x06, 0xca, 0x4c,
+        0x0a, 0x00, 0x00, 0x01, 0x0a, 0x00, 0x00, 0x02, 0xbc, 0x86, 0x00, 0x07,
+        0x3b, 0x4a, 0x58, 0x05, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02, 0xfa, 0xf0,
+        0x2b, 0xd6, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a,
+        0x7e, 0x4c, 0x3f, 0x0d, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+    ];
+
+    let tcp_header = unsafe { &*(buffer[20..].as_ptr() as *const TcpHeader) };
+    assert_eq!(tcp_header.header_len(), 40);
+    unsafe {
+        assert!(tcp_header.verify_ip_checksum(0x100_000a, 0x200_000a, 40));
+    }
+}
+
+#[test]
+fn test_make_packet_round_trip() {
+    let quad = Quad {
+        local_addr: 0x0a00_0002,
+        local_port: 7,
+        remote_addr: 0x0a00_0001,
+        remote_port: 48262,
+    };
+    let mut header = TcpHeader::new(quad.local_port, quad.remote_port);
+    header.seq = 1000;
+    header.ack = 2000;
+    header.flags = TcpFlags::ACK | TcpFlags::PSH;
+    header.window = 4096;
+
+    let options = [TcpOption::Sack(vec![(3000, 4000)])];
+    let segment =
+        loopback(&make_packet(quad, header, &options, b"hello", Ecn::NotEct));
+    assert_eq!(segment.quad.remote_port, 7);
+    assert_eq!(segment.quad.local_port, 48262);
+    assert_eq!(segment.seq, 1000);
+    assert_eq!(segment.ack, 2000);
+    assert_eq!(segment.flags, TcpFlags::ACK | TcpFlags::PSH);
+    assert_eq!(segment.data(), b"hello");
+    assert_eq!(segment.sack_blocks(), [(3000, 4000)]);
+    assert_eq!(segment.len(), 5);
+}
+
+#[test]
+fn test_seq_wrapping() {
+    assert!(seq_lt(0xffff_fff0, 0x10));
+    assert!(seq_gt(0x10, 0xffff_fff0));
+    assert!(seq_le(5, 5));
+    assert!(!seq_lt(5, 5));
+}
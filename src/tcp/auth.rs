@@ -0,0 +1,313 @@
+use super::{seq_ge, seq_gt, Quad, Segment, TcpHeader, TcpOption};
+use crate::packet::Packet;
+use crate::AsSlice;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MD5_SIGNATURE: u8 = 19;
+const KIND_AUTHENTICATION: u8 = 29;
+/// HMAC-SHA-1-96, RFC 5926 section 3.2.
+const AO_MAC_LEN: usize = 12;
+/// The traffic key length KDF_HMAC_SHA1 is asked for, in bits.
+const AO_TRAFFIC_KEY_BITS: u16 = 160;
+
+/// A key shared with a peer, to sign segments with. Segments to and from
+/// the peer without a valid signature are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthKey {
+    /// The password of an RFC 2385 MD5 signature.
+    Md5(Vec<u8>),
+    /// A TCP-AO master key tuple, RFC 5925 section 3.1, using the
+    /// HMAC-SHA-1-96 MAC and KDF_HMAC_SHA1 of RFC 5926. Options are
+    /// included in the MAC.
+    Ao {
+        /// The KeyID of our segments.
+        send_id: u8,
+        /// The KeyID of the peer's.
+        recv_id: u8,
+        key: Vec<u8>,
+    },
+}
+
+impl AuthKey {
+    /// The option reserving room for the signature of an outgoing segment.
+    pub fn option(&self) -> TcpOption {
+        match self {
+            Self::Md5(_) => TcpOption::Md5Signature([0; 16]),
+            Self::Ao {
+                send_id, recv_id, ..
+            } => TcpOption::Authentication {
+                key_id: *send_id,
+                rnext_key_id: *recv_id,
+                mac: vec![0; AO_MAC_LEN],
+            },
+        }
+    }
+
+    /// Fills in the signature of a packet that `make_packet` built with
+    /// `option` among its options, and fixes up its checksum. `isns` are
+    /// the ISNs of the sender and the receiver, `sne` the sequence number
+    /// extension, both only used by TCP-AO.
+    pub fn sign(
+        &self,
+        packet: &mut Packet,
+        quad: Quad,
+        isns: (u32, u32),
+        sne: u32,
+    ) {
+        let l4_offset = packet.l4_offset.unwrap() as usize;
+        let segment = &mut packet.data[l4_offset..];
+        let (start, signature) = self
+            .signature(
+                quad.local_addr,
+                quad.remote_addr,
+                segment.to_vec(),
+                isns,
+                sne,
+            )
+            .expect("room for the signature");
+        segment[start..start + signature.len()].copy_from_slice(&signature);
+        let tcp_len = segment.len();
+        // SAFETY: the packet holds `tcp_len` bytes of TCP header and data.
+        unsafe {
+            packet.tcp_header_mut().unwrap().set_ip_checksum(
+                quad.local_addr.to_be(),
+                quad.remote_addr.to_be(),
+                tcp_len,
+            );
+        }
+    }
+
+    /// Whether `seg` carries a valid signature under this key.
+    pub fn verify(&self, seg: &Segment, isns: (u32, u32), sne: u32) -> bool {
+        if let Self::Ao { recv_id, .. } = self {
+            let key_id = seg.options.iter().find_map(|option| match option {
+                TcpOption::Authentication { key_id, .. } => Some(*key_id),
+                _ => None,
+            });
+            if key_id != Some(*recv_id) {
+                return false;
+            }
+        }
+        // Back to the bytes on the wire, `Segment::parse` swapped the header
+        let l4_offset = seg.packet.l4_offset.unwrap() as usize;
+        let mut header = *seg.packet.tcp_header().unwrap();
+        header.bswap();
+        let mut segment = header.as_slice().to_vec();
+        segment.extend(&seg.packet.data[l4_offset + TcpHeader::MIN_LEN..]);
+        let quad = seg.quad;
+        match self.signature(
+            quad.remote_addr,
+            quad.local_addr,
+            segment.clone(),
+            isns,
+            sne,
+        ) {
+            Some((start, signature)) => {
+                segment[start..start + signature.len()] == signature
+            }
+            None => false,
+        }
+    }
+
+    /// The signature of `segment`, the TCP header, options and data as on
+    /// the wire, and where it goes. `None` if there is no option for it.
+    fn signature(
+        &self,
+        source: u32,
+        destination: u32,
+        mut segment: Vec<u8>,
+        isns: (u32, u32),
+        sne: u32,
+    ) -> Option<(usize, Vec<u8>)> {
+        let header_len = (segment[12] >> 4) as usize * 4;
+        let options = &segment[TcpHeader::MIN_LEN..header_len];
+        let (start, len) = match self {
+            Self::Md5(_) => (find_option(options, KIND_MD5_SIGNATURE)?, 16),
+            // After the KeyID and RNextKeyID
+            Self::Ao { .. } => {
+                (find_option(options, KIND_AUTHENTICATION)? + 2, AO_MAC_LEN)
+            }
+        };
+        let start = TcpHeader::MIN_LEN + start;
+        if start + len > header_len {
+            return None;
+        }
+        // Both are computed with the checksum and the signature zeroed
+        segment[16..18].fill(0);
+        segment[start..start + len].fill(0);
+        let mut pseudo_header = Vec::with_capacity(12);
+        pseudo_header.extend(source.to_be_bytes());
+        pseudo_header.extend(destination.to_be_bytes());
+        pseudo_header.extend([0, 6]);
+        pseudo_header.extend((segment.len() as u16).to_be_bytes());
+
+        let signature = match self {
+            // RFC 2385 section 2.0: no options, but the key
+            Self::Md5(key) => {
+                let mut md5 = Md5::new();
+                md5.update(&pseudo_header);
+                md5.update(&segment[..TcpHeader::MIN_LEN]);
+                md5.update(&segment[header_len..]);
+                md5.update(key);
+                md5.finalize().to_vec()
+            }
+            // RFC 5925 section 5.1
+            Self::Ao { key, .. } => {
+                let ports = &segment[..4];
+                let traffic_key =
+                    traffic_key(key, source, destination, ports, isns);
+                let mut mac =
+                    Hmac::<Sha1>::new_from_slice(&traffic_key).unwrap();
+                mac.update(&sne.to_be_bytes());
+                mac.update(&pseudo_header);
+                mac.update(&segment);
+                mac.finalize().into_bytes()[..AO_MAC_LEN].to_vec()
+            }
+        };
+        Some((start, signature))
+    }
+}
+
+/// KDF_HMAC_SHA1, RFC 5926 section 3.1.1, over the connection's context,
+/// RFC 5925 section 5.2. Segments with SYN and without ACK have no
+/// destination ISN yet and use 0.
+fn traffic_key(
+    master_key: &[u8],
+    source: u32,
+    destination: u32,
+    ports: &[u8],
+    isns: (u32, u32),
+) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(master_key).unwrap();
+    mac.update(&[1]);
+    mac.update(b"TCP-AO");
+    mac.update(&source.to_be_bytes());
+    mac.update(&destination.to_be_bytes());
+    mac.update(ports);
+    mac.update(&isns.0.to_be_bytes());
+    mac.update(&isns.1.to_be_bytes());
+    mac.update(&AO_TRAFFIC_KEY_BITS.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Where the data of the option of `kind` starts in the options area of a
+/// header that `TcpOption::parse` accepted.
+fn find_option(options: &[u8], kind: u8) -> Option<usize> {
+    let mut i = 0;
+    while let Some(&k) = options.get(i) {
+        match k {
+            KIND_END => return None,
+            KIND_NOP => i += 1,
+            k if k == kind => return Some(i + 2),
+            _ => i += *options.get(i + 1)? as usize,
+        }
+    }
+    None
+}
+
+/// The sequence number extension of TCP-AO, RFC 5925 section 6.2: how
+/// often the sequence numbers in one direction wrapped around.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sne {
+    /// The highest sequence number seen so far, and its extension.
+    seq: u32,
+    sne: u32,
+}
+
+impl Sne {
+    pub fn new(isn: u32) -> Self {
+        Self { seq: isn, sne: 0 }
+    }
+
+    /// The extension of `seq`, which lies within a window of the sequence
+    /// numbers seen so far.
+    pub fn get(&self, seq: u32) -> u32 {
+        if seq_ge(seq, self.seq) && seq < self.seq {
+            self.sne.wrapping_add(1)
+        } else if !seq_ge(seq, self.seq) && seq > self.seq {
+            self.sne.wrapping_sub(1)
+        } else {
+            self.sne
+        }
+    }
+
+    pub fn update(&mut self, seq: u32) {
+        if seq_gt(seq, self.seq) {
+            self.sne = self.get(seq);
+            self.seq = seq;
+        }
+    }
+}
+
+#[test]
+fn test_sign_and_verify() {
+    use super::{loopback, make_packet, TcpFlags};
+    use crate::ip::Ecn;
+
+    let quad = Quad {
+        local_addr: 0x0a00_0002,
+        local_port: 179,
+        remote_addr: 0x0a00_0001,
+        remote_port: 40000,
+    };
+    let mut header = TcpHeader::new(quad.local_port, quad.remote_port);
+    header.seq = 1000;
+    header.ack = 2000;
+    header.flags = TcpFlags::ACK;
+    let keys = [
+        AuthKey::Md5(b"secret".to_vec()),
+        AuthKey::Ao {
+            send_id: 1,
+            recv_id: 1,
+            key: b"secret".to_vec(),
+        },
+    ];
+    for key in keys {
+        let options =
+            [TcpOption::Timestamps { value: 1, echo: 2 }, key.option()];
+        let mut packet =
+            make_packet(quad, header, &options, b"update", Ecn::NotEct);
+        key.sign(&mut packet, quad, (999, 1999), 0);
+        // The checksum was fixed up, or this would not parse
+        let seg = loopback(&packet);
+        assert!(key.verify(&seg, (999, 1999), 0));
+        match &key {
+            AuthKey::Md5(_) => {
+                assert!(!AuthKey::Md5(b"guess".to_vec()).verify(
+                    &seg,
+                    (0, 0),
+                    0
+                ))
+            }
+            AuthKey::Ao { .. } => {
+                // The traffic keys depend on the ISNs, the MAC on the SNE
+                assert!(!key.verify(&seg, (999, 0), 0));
+                assert!(!key.verify(&seg, (999, 1999), 1));
+                let other = AuthKey::Ao {
+                    send_id: 1,
+                    recv_id: 2,
+                    key: b"secret".to_vec(),
+                };
+                assert!(!other.verify(&seg, (999, 1999), 0));
+            }
+        }
+
+        let mut tampered = loopback(&packet);
+        *tampered.packet.data.last_mut().unwrap() ^= 1;
+        assert!(!key.verify(&tampered, (999, 1999), 0));
+    }
+}
+
+#[test]
+fn test_sne() {
+    let mut sne = Sne::new(0xffff_ff00);
+    assert_eq!(sne.get(0xffff_ff80), 0);
+    sne.update(0x10);
+    assert_eq!(sne.get(0x20), 1);
+    // A retransmission from before the wrap
+    assert_eq!(sne.get(0xffff_ff80), 0);
+}
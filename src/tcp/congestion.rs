@@ -0,0 +1,110 @@
+use super::rate::RateSample;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+mod bbr;
+mod cubic;
+mod newreno;
+
+pub use bbr::Bbr;
+pub use cubic::Cubic;
+pub use newreno::NewReno;
+
+/// RFC 6928 initial window.
+const INITIAL_WINDOW_SEGMENTS: usize = 10;
+
+/// What an acknowledgement of new data tells the congestion controller.
+#[derive(Debug, Clone)]
+pub struct Ack {
+    /// Bytes newly acknowledged.
+    pub acked: usize,
+    /// Bytes outstanding before this ACK arrived.
+    pub flight_size: usize,
+    /// The connection's smoothed round trip time, if there is a sample.
+    pub srtt: Option<Duration>,
+    pub now: Instant,
+}
+
+/// A congestion control algorithm. The connection handles loss detection
+/// and recovery itself and tells the algorithm about the events; the
+/// algorithm decides how the congestion window reacts.
+pub trait CongestionControl {
+    /// New data was acknowledged outside of loss recovery.
+    fn on_ack(&mut self, ack: &Ack);
+
+    /// A loss was detected through duplicate acknowledgements and fast
+    /// recovery is starting. Called at most once per window of data.
+    fn on_loss(&mut self, flight_size: usize, now: Instant);
+
+    /// The peer echoed a Congestion Experienced mark, RFC 3168 section
+    /// 6.1.2. Called at most once per window of data, and never during
+    /// fast recovery. Reacts as to a loss unless the algorithm says
+    /// otherwise, but there is no recovery to end.
+    fn on_congestion_echo(&mut self, flight_size: usize, now: Instant) {
+        self.on_loss(flight_size, now);
+    }
+
+    /// Fast recovery ended with everything outstanding at its start
+    /// acknowledged.
+    fn on_recovery_end(&mut self, flight_size: usize, now: Instant);
+
+    /// The retransmission timer expired.
+    fn on_timeout(&mut self, flight_size: usize, now: Instant);
+
+    /// A delivery rate sample was taken, whether or not the connection is in
+    /// loss recovery. Only model-based algorithms care.
+    fn on_rate_sample(&mut self, _sample: &RateSample) {}
+
+    fn cwnd(&self) -> usize;
+
+    fn ssthresh(&self) -> usize;
+
+    /// Bytes per second to pace transmissions at, if the algorithm paces.
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// The algorithms a connection can be configured to use.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+    NewReno,
+    /// The Linux default.
+    #[default]
+    Cubic,
+    Bbr,
+}
+
+impl CongestionAlgorithm {
+    pub fn build(self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            Self::NewReno => Box::new(NewReno::new(mss)),
+            Self::Cubic => Box::new(Cubic::new(mss)),
+            Self::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+impl fmt::Display for CongestionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewReno => write!(f, "newreno"),
+            Self::Cubic => write!(f, "cubic"),
+            Self::Bbr => write!(f, "bbr"),
+        }
+    }
+}
+
+/// RFC 5681 slow start with RFC 3465 appropriate byte counting, shared by
+/// the loss-based algorithms. Returns the part of `acked` left over once
+/// `cwnd` reaches `ssthresh`.
+fn slow_start(
+    cwnd: &mut usize,
+    ssthresh: usize,
+    acked: usize,
+    mss: usize,
+) -> usize {
+    let increase = acked.min(2 * mss).min(ssthresh - *cwnd);
+    *cwnd += increase;
+    acked - increase.min(acked)
+}
@@ -0,0 +1,413 @@
+use super::{Ack, CongestionControl, RateSample, INITIAL_WINDOW_SEGMENTS};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// 2/ln(2), the smallest gain that lets startup double the sending rate
+/// every round trip.
+const HIGH_GAIN: f64 = 2.885;
+const DRAIN_GAIN: f64 = 1.0 / HIGH_GAIN;
+const CWND_GAIN: f64 = 2.0;
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const BTL_BW_FILTER_ROUNDS: u64 = 10;
+const RTPROP_FILTER_LEN: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+const MIN_PIPE_CWND_SEGMENTS: usize = 4;
+/// The pipe is considered full once three round trips in a row failed to
+/// grow the bandwidth estimate by a quarter.
+const FULL_BW_GROWTH: f64 = 1.25;
+const FULL_BW_ROUNDS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw {
+        cycle_index: usize,
+        cycle_start: Instant,
+    },
+    ProbeRtt {
+        /// Set once the window has drained down to the minimum.
+        done_at: Option<Instant>,
+        round_done: bool,
+    },
+}
+
+/// Windowed maximum of the delivery rate over the last
+/// `BTL_BW_FILTER_ROUNDS` round trips, kept as a monotonic queue.
+#[derive(Debug, Clone, Default)]
+struct MaxFilter {
+    samples: VecDeque<(u64, f64)>,
+}
+
+impl MaxFilter {
+    fn get(&self) -> f64 {
+        self.samples.front().map_or(0.0, |&(_, bw)| bw)
+    }
+
+    fn update(&mut self, round: u64, bw: f64) {
+        while self.samples.back().is_some_and(|&(_, b)| b <= bw) {
+            self.samples.pop_back();
+        }
+        self.samples.push_back((round, bw));
+        while self
+            .samples
+            .front()
+            .is_some_and(|&(r, _)| r + BTL_BW_FILTER_ROUNDS <= round)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Model-based congestion control after BBR version 1,
+/// draft-cardwell-iccrg-bbr-congestion-control-00. Instead of reacting to
+/// loss, BBR estimates the bottleneck bandwidth and the round trip
+/// propagation delay from delivery rate samples, paces at the bandwidth and
+/// keeps about two bandwidth-delay products in flight.
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    mss: usize,
+    mode: Mode,
+    btl_bw: MaxFilter,
+    /// The minimum RTT and when it was measured.
+    rtprop: Option<(Duration, Instant)>,
+    rtprop_expired: bool,
+    round_count: u64,
+    next_round_delivered: u64,
+    round_start: bool,
+    full_bw: f64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+    /// The window to go back to after loss recovery or PROBE_RTT.
+    prior_cwnd: usize,
+    cwnd: usize,
+    /// Bytes per second.
+    pacing_rate: f64,
+}
+
+impl Bbr {
+    pub fn new(mss: usize) -> Self {
+        let cwnd = INITIAL_WINDOW_SEGMENTS * mss;
+        Self {
+            mss,
+            mode: Mode::Startup,
+            btl_bw: MaxFilter::default(),
+            rtprop: None,
+            rtprop_expired: false,
+            round_count: 0,
+            next_round_delivered: 0,
+            round_start: false,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            prior_cwnd: cwnd,
+            cwnd,
+            // The initial window over a nominal 1ms RTT
+            pacing_rate: HIGH_GAIN * cwnd as f64 * 1000.0,
+        }
+    }
+
+    /// Bytes per second.
+    pub fn btl_bw(&self) -> f64 {
+        self.btl_bw.get()
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => DRAIN_GAIN,
+            Mode::ProbeBw { cycle_index, .. } => PACING_GAIN_CYCLE[cycle_index],
+            Mode::ProbeRtt { .. } => 1.0,
+        }
+    }
+
+    fn cwnd_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup | Mode::Drain => HIGH_GAIN,
+            Mode::ProbeBw { .. } => CWND_GAIN,
+            Mode::ProbeRtt { .. } => 1.0,
+        }
+    }
+
+    fn min_pipe_cwnd(&self) -> usize {
+        MIN_PIPE_CWND_SEGMENTS * self.mss
+    }
+
+    /// `gain` times the estimated bandwidth-delay product.
+    fn inflight(&self, gain: f64) -> usize {
+        let Some((rtprop, _)) = self.rtprop else {
+            return INITIAL_WINDOW_SEGMENTS * self.mss;
+        };
+        let bdp = self.btl_bw() * rtprop.as_secs_f64();
+        ((gain * bdp) as usize).max(self.min_pipe_cwnd())
+    }
+
+    fn update_btl_bw(&mut self, rs: &RateSample) {
+        self.round_start = rs.prior_delivered >= self.next_round_delivered;
+        if self.round_start {
+            self.next_round_delivered = rs.delivered;
+            self.round_count += 1;
+        }
+        // An app limited sample only shows what the network can do at least
+        if rs.delivery_rate >= self.btl_bw() || !rs.is_app_limited {
+            self.btl_bw.update(self.round_count, rs.delivery_rate);
+        }
+    }
+
+    fn check_cycle_phase(&mut self, rs: &RateSample) {
+        let Mode::ProbeBw {
+            cycle_index,
+            cycle_start,
+        } = self.mode
+        else {
+            return;
+        };
+        let gain = PACING_GAIN_CYCLE[cycle_index];
+        let full_length = self
+            .rtprop
+            .is_none_or(|(rtprop, _)| rs.now - cycle_start > rtprop);
+        let prior_in_flight = rs.in_flight + rs.acked;
+        let advance = if gain > 1.0 {
+            full_length && prior_in_flight >= self.inflight(gain)
+        } else if gain < 1.0 {
+            full_length || prior_in_flight <= self.inflight(1.0)
+        } else {
+            full_length
+        };
+        if advance {
+            self.mode = Mode::ProbeBw {
+                cycle_index: (cycle_index + 1) % PACING_GAIN_CYCLE.len(),
+                cycle_start: rs.now,
+            };
+        }
+    }
+
+    fn check_full_pipe(&mut self, rs: &RateSample) {
+        if self.filled_pipe || !self.round_start || rs.is_app_limited {
+            return;
+        }
+        if self.btl_bw() >= self.full_bw * FULL_BW_GROWTH {
+            self.full_bw = self.btl_bw();
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        self.filled_pipe = self.full_bw_rounds >= FULL_BW_ROUNDS;
+    }
+
+    fn check_drain(&mut self, rs: &RateSample) {
+        if self.mode == Mode::Startup && self.filled_pipe {
+            self.mode = Mode::Drain;
+        }
+        if self.mode == Mode::Drain && rs.in_flight <= self.inflight(1.0) {
+            self.enter_probe_bw(rs.now);
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        // Start in a random phase, but never the draining one
+        let offset = rand::thread_rng().gen_range(0..=6);
+        self.mode = Mode::ProbeBw {
+            cycle_index: (PACING_GAIN_CYCLE.len() - offset)
+                % PACING_GAIN_CYCLE.len(),
+            cycle_start: now,
+        };
+    }
+
+    fn update_rtprop(&mut self, rs: &RateSample) {
+        self.rtprop_expired = self
+            .rtprop
+            .is_some_and(|(_, at)| rs.now > at + RTPROP_FILTER_LEN);
+        if let Some(rtt) = rs.rtt {
+            if self.rtprop_expired
+                || self.rtprop.is_none_or(|(min, _)| rtt <= min)
+            {
+                self.rtprop = Some((rtt, rs.now));
+            }
+        }
+    }
+
+    fn check_probe_rtt(&mut self, rs: &RateSample) {
+        if self.rtprop_expired && !matches!(self.mode, Mode::ProbeRtt { .. }) {
+            self.prior_cwnd = self.cwnd;
+            self.mode = Mode::ProbeRtt {
+                done_at: None,
+                round_done: false,
+            };
+        }
+        let Mode::ProbeRtt {
+            done_at,
+            round_done,
+        } = self.mode
+        else {
+            return;
+        };
+        match done_at {
+            None if rs.in_flight <= self.min_pipe_cwnd() => {
+                self.mode = Mode::ProbeRtt {
+                    done_at: Some(rs.now + PROBE_RTT_DURATION),
+                    round_done: false,
+                };
+                self.next_round_delivered = rs.delivered;
+            }
+            None => {}
+            Some(done_at) => {
+                let round_done = round_done || self.round_start;
+                self.mode = Mode::ProbeRtt {
+                    done_at: Some(done_at),
+                    round_done,
+                };
+                if round_done && rs.now > done_at {
+                    if let Some((_, at)) = &mut self.rtprop {
+                        *at = rs.now;
+                    }
+                    self.cwnd = self.cwnd.max(self.prior_cwnd);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(rs.now);
+                    } else {
+                        self.mode = Mode::Startup;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_pacing_rate(&mut self) {
+        let rate = self.pacing_gain() * self.btl_bw();
+        if rate > 0.0 && (self.filled_pipe || rate > self.pacing_rate) {
+            self.pacing_rate = rate;
+        }
+    }
+
+    fn set_cwnd(&mut self, rs: &RateSample) {
+        // Room for a few segments queued around the pacing of the rest
+        let target = self.inflight(self.cwnd_gain()) + 3 * self.mss;
+        if self.filled_pipe {
+            self.cwnd = (self.cwnd + rs.acked).min(target);
+        } else if self.cwnd < target
+            || rs.delivered < (INITIAL_WINDOW_SEGMENTS * self.mss) as u64
+        {
+            self.cwnd += rs.acked;
+        }
+        self.cwnd = self.cwnd.max(self.min_pipe_cwnd());
+        if matches!(self.mode, Mode::ProbeRtt { .. }) {
+            self.cwnd = self.cwnd.min(self.min_pipe_cwnd());
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn on_ack(&mut self, _ack: &Ack) {
+        // Everything is driven by the rate samples
+    }
+
+    fn on_rate_sample(&mut self, rs: &RateSample) {
+        self.update_btl_bw(rs);
+        self.check_cycle_phase(rs);
+        self.check_full_pipe(rs);
+        self.check_drain(rs);
+        self.update_rtprop(rs);
+        self.check_probe_rtt(rs);
+        self.set_pacing_rate();
+        self.set_cwnd(rs);
+    }
+
+    fn on_loss(&mut self, flight_size: usize, _now: Instant) {
+        // Packet conservation: only send as much as leaves the network
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = flight_size.max(self.min_pipe_cwnd());
+    }
+
+    /// Like Linux's BBR, which only follows its model and ignores ECN.
+    fn on_congestion_echo(&mut self, _flight_size: usize, _now: Instant) {}
+
+    fn on_recovery_end(&mut self, _flight_size: usize, _now: Instant) {
+        self.cwnd = self.cwnd.max(self.prior_cwnd);
+    }
+
+    fn on_timeout(&mut self, _flight_size: usize, _now: Instant) {
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// BBR has no slow start threshold.
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        Some(self.pacing_rate)
+    }
+}
+
+/// Feeds one round trip's worth of delivery at `rate` to `bbr`.
+#[cfg(test)]
+fn deliver_round(
+    bbr: &mut Bbr,
+    delivered: &mut u64,
+    now: &mut Instant,
+    rate: f64,
+    rtt: Duration,
+) {
+    let prior_delivered = *delivered;
+    let acked = (rate * rtt.as_secs_f64()) as usize;
+    *delivered += acked as u64;
+    *now += rtt;
+    bbr.on_rate_sample(&RateSample {
+        delivery_rate: rate,
+        rtt: Some(rtt),
+        prior_delivered,
+        delivered: *delivered,
+        acked,
+        in_flight: acked.min(bbr.cwnd()),
+        is_app_limited: false,
+        now: *now,
+    });
+}
+
+#[test]
+fn test_bbr() {
+    let mss = 1000;
+    let rate = 1_000_000.0;
+    let rtt = Duration::from_millis(50);
+    let bdp = 50_000;
+    let mut bbr = Bbr::new(mss);
+    let mut delivered = 0;
+    let mut now = Instant::now();
+
+    // Startup ends after three rounds without bandwidth growth, and drain
+    // right away since only a BDP is in flight.
+    for _ in 0..3 {
+        deliver_round(&mut bbr, &mut delivered, &mut now, rate, rtt);
+        assert_eq!(bbr.mode, Mode::Startup);
+    }
+    deliver_round(&mut bbr, &mut delivered, &mut now, rate, rtt);
+    assert!(bbr.filled_pipe);
+    assert!(matches!(bbr.mode, Mode::ProbeBw { .. }));
+    assert_eq!(bbr.btl_bw(), rate);
+    assert_eq!(bbr.rtprop.unwrap().0, rtt);
+
+    for _ in 0..20 {
+        deliver_round(&mut bbr, &mut delivered, &mut now, rate, rtt);
+        assert_eq!(bbr.pacing_rate().unwrap(), bbr.pacing_gain() * rate);
+    }
+    assert_eq!(bbr.cwnd(), 2 * bdp + 3 * mss);
+
+    // Without a new minimum RTT for 10 seconds, PROBE_RTT drains the pipe
+    // to refresh it.
+    let slower = rtt + Duration::from_millis(10);
+    while !matches!(bbr.mode, Mode::ProbeRtt { .. }) {
+        deliver_round(&mut bbr, &mut delivered, &mut now, rate, slower);
+    }
+    assert_eq!(bbr.cwnd(), 4 * mss);
+    for _ in 0..5 {
+        deliver_round(&mut bbr, &mut delivered, &mut now, rate, slower);
+    }
+    assert!(matches!(bbr.mode, Mode::ProbeBw { .. }));
+    assert_eq!(bbr.rtprop.unwrap().0, slower);
+}
@@ -0,0 +1,186 @@
+use super::{slow_start, Ack, CongestionControl, INITIAL_WINDOW_SEGMENTS};
+use std::time::{Duration, Instant};
+
+const C: f64 = 0.4;
+const BETA: f64 = 0.7;
+/// Growth of the Reno-friendly window per window acknowledged, RFC 8312
+/// section 4.2.
+const ALPHA: f64 = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+
+/// CUBIC from RFC 8312, with fast convergence. Window calculations are done
+/// in segments, like the RFC; the window itself grows one segment at a time.
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Window just before the last reduction, in segments.
+    w_max: f64,
+    /// Seconds it takes the cubic function to get back to `w_max`.
+    k: f64,
+    /// Start of the current congestion avoidance period.
+    epoch_start: Option<Instant>,
+    /// What Reno would have grown the window to since `epoch_start`.
+    w_est: f64,
+    /// Bytes acknowledged since `cwnd` last grew in congestion avoidance.
+    bytes_acked: usize,
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: INITIAL_WINDOW_SEGMENTS * mss,
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            w_est: 0.0,
+            bytes_acked: 0,
+        }
+    }
+
+    fn segments(&self) -> f64 {
+        self.cwnd as f64 / self.mss as f64
+    }
+
+    /// W_cubic(t), equation 1.
+    fn w_cubic(&self, t: f64) -> f64 {
+        C * (t - self.k).powi(3) + self.w_max
+    }
+
+    // Section 4.5
+    fn reduce(&mut self) {
+        let cwnd = self.segments();
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.ssthresh = ((self.cwnd as f64 * BETA) as usize).max(2 * self.mss);
+        self.epoch_start = None;
+        self.bytes_acked = 0;
+    }
+
+    fn congestion_avoidance(
+        &mut self,
+        acked: usize,
+        rtt: Duration,
+        now: Instant,
+    ) {
+        let cwnd = self.segments();
+        let epoch_start = *self.epoch_start.get_or_insert_with(|| {
+            self.k = if cwnd < self.w_max {
+                ((self.w_max - cwnd) / C).cbrt()
+            } else {
+                self.w_max = cwnd;
+                0.0
+            };
+            self.w_est = cwnd;
+            now
+        });
+
+        self.w_est += ALPHA * (acked as f64 / self.mss as f64) / cwnd;
+        let t = (now - epoch_start + rtt).as_secs_f64();
+        let target = self.w_cubic(t).max(self.w_est);
+
+        // Grow by (target - cwnd) / cwnd per segment acknowledged, or very
+        // slowly if the target is not above the current window.
+        let segments_per_increase = if target > cwnd {
+            (cwnd / (target - cwnd)).max(1.0)
+        } else {
+            100.0 * cwnd
+        };
+        let threshold = (segments_per_increase * self.mss as f64) as usize;
+        self.bytes_acked += acked;
+        while self.bytes_acked >= threshold {
+            self.bytes_acked -= threshold;
+            self.cwnd += self.mss;
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, ack: &Ack) {
+        let mut acked = ack.acked;
+        if self.cwnd < self.ssthresh {
+            acked = slow_start(&mut self.cwnd, self.ssthresh, acked, self.mss);
+        }
+        if acked > 0 {
+            let rtt = ack.srtt.unwrap_or_default();
+            self.congestion_avoidance(acked, rtt, ack.now);
+        }
+    }
+
+    fn on_loss(&mut self, _flight_size: usize, _now: Instant) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_recovery_end(&mut self, _flight_size: usize, _now: Instant) {
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, _flight_size: usize, _now: Instant) {
+        self.reduce();
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+}
+
+#[test]
+fn test_cubic() {
+    let mss = 1000;
+    let rtt = Duration::from_millis(100);
+    let mut now = Instant::now();
+    let mut cc = Cubic::new(mss);
+    cc.cwnd = 100 * mss;
+    cc.on_loss(100 * mss, now);
+    assert_eq!(cc.cwnd(), 70 * mss);
+    assert_eq!(cc.w_max, 100.0);
+
+    // A window of ACKs every round trip
+    let round_trip = |cc: &mut Cubic, now: &mut Instant| {
+        *now += rtt;
+        for _ in 0..cc.cwnd() / mss {
+            let ack = Ack {
+                acked: mss,
+                flight_size: cc.cwnd(),
+                srtt: Some(rtt),
+                now: *now,
+            };
+            cc.on_ack(&ack);
+        }
+    };
+
+    // Concave growth up to the plateau around w_max at K seconds
+    let k = Duration::from_secs_f64((30.0 / C).cbrt());
+    let epoch = now;
+    round_trip(&mut cc, &mut now);
+    let first = cc.cwnd();
+    assert!(first > 70 * mss, "cwnd {first}");
+    while now - epoch < k {
+        round_trip(&mut cc, &mut now);
+    }
+    let plateau = cc.cwnd();
+    assert!((95 * mss..=101 * mss).contains(&plateau), "cwnd {plateau}");
+
+    // Then convex growth beyond it
+    while now - epoch < 2 * k {
+        round_trip(&mut cc, &mut now);
+    }
+    assert!(cc.cwnd() > 110 * mss, "cwnd {}", cc.cwnd());
+
+    // Fast convergence: losing before getting back to w_max releases
+    // bandwidth for other flows.
+    cc.cwnd = 90 * mss;
+    cc.on_loss(90 * mss, now);
+    assert_eq!(cc.w_max, 90.0 * (1.0 + BETA) / 2.0);
+}
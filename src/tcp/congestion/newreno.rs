@@ -0,0 +1,104 @@
+use super::{slow_start, Ack, CongestionControl, INITIAL_WINDOW_SEGMENTS};
+use std::time::Instant;
+
+/// RFC 5681 window management with the RFC 6582 NewReno recovery exit.
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Bytes acknowledged since `cwnd` last grew in congestion avoidance.
+    bytes_acked: usize,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: INITIAL_WINDOW_SEGMENTS * mss,
+            ssthresh: usize::MAX,
+            bytes_acked: 0,
+        }
+    }
+
+    fn reduce(&mut self, flight_size: usize) {
+        self.ssthresh = (flight_size / 2).max(2 * self.mss);
+        self.bytes_acked = 0;
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn on_ack(&mut self, ack: &Ack) {
+        let mut acked = ack.acked;
+        if self.cwnd < self.ssthresh {
+            acked = slow_start(&mut self.cwnd, self.ssthresh, acked, self.mss);
+        }
+        if acked == 0 {
+            return;
+        }
+        // Congestion avoidance: one segment per window acknowledged
+        self.bytes_acked += acked;
+        if self.bytes_acked >= self.cwnd {
+            self.bytes_acked -= self.cwnd;
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_loss(&mut self, flight_size: usize, _now: Instant) {
+        self.reduce(flight_size);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_recovery_end(&mut self, flight_size: usize, _now: Instant) {
+        // RFC 6582 section 3.2 step 3, option 1
+        self.cwnd = self.ssthresh.min(flight_size.max(self.mss) + self.mss);
+    }
+
+    fn on_timeout(&mut self, flight_size: usize, _now: Instant) {
+        self.reduce(flight_size);
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+}
+
+#[test]
+fn test_newreno() {
+    let now = Instant::now();
+    let mss = 1000;
+    let mut cc = NewReno::new(mss);
+    assert_eq!(cc.cwnd(), 10 * mss);
+
+    let ack = |acked| Ack {
+        acked,
+        flight_size: 0,
+        srtt: None,
+        now,
+    };
+    cc.on_ack(&ack(mss));
+    assert_eq!(cc.cwnd(), 11 * mss);
+
+    cc.on_loss(20 * mss, now);
+    assert_eq!(cc.ssthresh(), 10 * mss);
+    assert_eq!(cc.cwnd(), 10 * mss);
+
+    // One segment per window in congestion avoidance
+    for _ in 0..9 {
+        cc.on_ack(&ack(mss));
+    }
+    assert_eq!(cc.cwnd(), 10 * mss);
+    cc.on_ack(&ack(mss));
+    assert_eq!(cc.cwnd(), 11 * mss);
+
+    cc.on_timeout(8 * mss, now);
+    assert_eq!(cc.ssthresh(), 4 * mss);
+    assert_eq!(cc.cwnd(), mss);
+    cc.on_ack(&ack(mss));
+    assert_eq!(cc.cwnd(), 2 * mss);
+}
@@ -0,0 +1,76 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use std::time::{Duration, Instant};
+
+/// How long a secret is used for new cookies. Cookies made with the
+/// previous one are still accepted, so a cookie lasts at least this long.
+const SECRET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Fast Open cookies for the clients of our listeners, RFC 7413 section
+/// 4.1.2: a MAC of the client's address under a secret that is replaced
+/// every `SECRET_LIFETIME`.
+pub struct FastOpenCookies {
+    secret: [u8; 16],
+    previous: Option<[u8; 16]>,
+    rotated_at: Instant,
+}
+
+impl FastOpenCookies {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+            previous: None,
+            rotated_at: now,
+        }
+    }
+
+    /// The cookie to hand out to `addr`.
+    pub fn cookie(&mut self, addr: u32, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        Self::mac(self.secret, addr)
+    }
+
+    pub fn is_valid(&mut self, addr: u32, cookie: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        [Some(self.secret), self.previous]
+            .into_iter()
+            .flatten()
+            .any(|secret| Self::mac(secret, addr) == cookie)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let age = now.saturating_duration_since(self.rotated_at);
+        if age < SECRET_LIFETIME {
+            return;
+        }
+        // Long enough without cookies that the current one is stale too
+        self.previous = (age < 2 * SECRET_LIFETIME).then_some(self.secret);
+        self.secret = rand::thread_rng().gen();
+        self.rotated_at = now;
+    }
+
+    /// HMAC-SHA1 of the address, truncated to 8 bytes, the size Linux
+    /// uses.
+    fn mac(secret: [u8; 16], addr: u32) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&secret).unwrap();
+        mac.update(&addr.to_be_bytes());
+        mac.finalize().into_bytes()[..8].to_vec()
+    }
+}
+
+#[test]
+fn test_fast_open_cookie() {
+    let now = Instant::now();
+    let mut cookies = FastOpenCookies::new(now);
+    let cookie = cookies.cookie(0x0a00_0001, now);
+    assert!(cookies.is_valid(0x0a00_0001, &cookie, now));
+    assert!(!cookies.is_valid(0x0a00_0003, &cookie, now));
+    assert!(!cookies.is_valid(0x0a00_0001, &cookie[..4], now));
+
+    // Still good for one rotation of the secret, not for two
+    let later = now + SECRET_LIFETIME;
+    assert!(cookies.is_valid(0x0a00_0001, &cookie, later));
+    assert_ne!(cookies.cookie(0x0a00_0001, later), cookie);
+    assert!(!cookies.is_valid(0x0a00_0001, &cookie, later + SECRET_LIFETIME));
+}
@@ -0,0 +1,57 @@
+use super::Quad;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// The clock of RFC 9293 section 3.4.1 ticks every 4 microseconds.
+const CLOCK_TICK: Duration = Duration::from_micros(4);
+
+struct Secret {
+    key: [u8; 16],
+    start: Instant,
+}
+
+/// Made up once per run of the stack, RFC 6528 section 3.
+static SECRET: OnceLock<Secret> = OnceLock::new();
+
+/// The initial sequence number for a connection on `quad`, RFC 6528
+/// section 3: the clock plus a keyed MAC of the 4-tuple, HMAC-SHA1
+/// truncated to 32 bits standing in for its F(). An off-path attacker can't
+/// predict it, while later connections on the same 4-tuple still start
+/// beyond earlier ones.
+pub fn isn(quad: Quad, now: Instant) -> u32 {
+    let secret = SECRET.get_or_init(|| Secret {
+        key: rand::thread_rng().gen(),
+        start: Instant::now(),
+    });
+    let elapsed = now.saturating_duration_since(secret.start);
+    let clock = elapsed.as_nanos() / CLOCK_TICK.as_nanos();
+    let mut mac = Hmac::<Sha1>::new_from_slice(&secret.key).unwrap();
+    mac.update(&quad.to_be_bytes());
+    let offset = mac.finalize().into_bytes();
+    u32::from_be_bytes(offset[..4].try_into().unwrap())
+        .wrapping_add(clock as u32)
+}
+
+#[test]
+fn test_isn() {
+    // Well after the clock started, whichever test started it
+    let now = Instant::now() + Duration::from_secs(24 * 60 * 60);
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 80,
+    };
+    let first = isn(quad, now);
+    let later = isn(quad, now + Duration::from_millis(1));
+    assert_eq!(later.wrapping_sub(first), 250);
+
+    let other = Quad {
+        local_port: 40001,
+        ..quad
+    };
+    assert_ne!(isn(other, now), first);
+}
@@ -0,0 +1,261 @@
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMPS: u8 = 8;
+const KIND_MD5_SIGNATURE: u8 = 19;
+const KIND_AUTHENTICATION: u8 = 29;
+const KIND_FAST_OPEN: u8 = 34;
+
+/// Space for options in a TCP header, the data offset field counts up to
+/// 60 bytes of header.
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+/// The TCP options this stack knows about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    /// Maximum segment size, RFC 9293 section 3.7.1. Only on SYNs.
+    Mss(u16),
+    /// RFC 7323 section 2. Only on SYNs.
+    WindowScale(u8),
+    /// RFC 2018. Only on SYNs.
+    SackPermitted,
+    /// Start and end of each block of data received out of order, RFC 2018.
+    Sack(Vec<(u32, u32)>),
+    /// RFC 7323 section 3.
+    Timestamps { value: u32, echo: u32 },
+    /// A Fast Open cookie, or a request for one if empty, RFC 7413 section
+    /// 4.1.1. Only on SYNs.
+    FastOpen(Vec<u8>),
+    /// RFC 2385 section 3.0.
+    Md5Signature([u8; 16]),
+    /// TCP-AO, RFC 5925 section 2.2.
+    Authentication {
+        key_id: u8,
+        rnext_key_id: u8,
+        mac: Vec<u8>,
+    },
+    /// Anything else, skipped over.
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// Parses the options area of a TCP header. Returns `None` if an option
+    /// runs past the end of the header or has the wrong length for its
+    /// kind.
+    pub fn parse(mut bytes: &[u8]) -> Option<Vec<Self>> {
+        let mut options = Vec::new();
+        while let Some(&kind) = bytes.first() {
+            match kind {
+                KIND_END => break,
+                KIND_NOP => {
+                    bytes = &bytes[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *bytes.get(1)? as usize;
+            if len < 2 || len > bytes.len() {
+                return None;
+            }
+            let data = &bytes[2..len];
+            let option = match (kind, data.len()) {
+                (KIND_MSS, 2) => {
+                    Self::Mss(u16::from_be_bytes([data[0], data[1]]))
+                }
+                (KIND_WINDOW_SCALE, 1) => Self::WindowScale(data[0]),
+                (KIND_SACK_PERMITTED, 0) => Self::SackPermitted,
+                (KIND_SACK, n) if n > 0 && n % 8 == 0 => Self::Sack(
+                    data.chunks_exact(8)
+                        .map(|block| (be_u32(&block[..4]), be_u32(&block[4..])))
+                        .collect(),
+                ),
+                (KIND_TIMESTAMPS, 8) => Self::Timestamps {
+                    value: be_u32(&data[..4]),
+                    echo: be_u32(&data[4..]),
+                },
+                (KIND_FAST_OPEN, n) if n == 0 || (4..=16).contains(&n) => {
+                    Self::FastOpen(data.to_vec())
+                }
+                (KIND_MD5_SIGNATURE, 16) => {
+                    Self::Md5Signature(data.try_into().unwrap())
+                }
+                (KIND_AUTHENTICATION, n) if n >= 2 => Self::Authentication {
+                    key_id: data[0],
+                    rnext_key_id: data[1],
+                    mac: data[2..].to_vec(),
+                },
+                (
+                    KIND_MSS | KIND_WINDOW_SCALE | KIND_SACK_PERMITTED
+                    | KIND_SACK | KIND_TIMESTAMPS | KIND_FAST_OPEN
+                    | KIND_MD5_SIGNATURE | KIND_AUTHENTICATION,
+                    _,
+                ) => return None,
+                _ => Self::Unknown {
+                    kind,
+                    data: data.to_vec(),
+                },
+            };
+            options.push(option);
+            bytes = &bytes[len..];
+        }
+        Some(options)
+    }
+
+    /// Encodes `options` in order, padded with end of option list bytes to
+    /// a multiple of 4.
+    pub fn encode(options: &[Self]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAX_OPTIONS_LEN);
+        for option in options {
+            option.write(&mut bytes);
+        }
+        bytes.resize(bytes.len().next_multiple_of(4), KIND_END);
+        debug_assert!(bytes.len() <= MAX_OPTIONS_LEN, "{:?}", options);
+        bytes
+    }
+
+    /// The length of `encode(options)`.
+    pub fn encoded_len(options: &[Self]) -> usize {
+        options
+            .iter()
+            .map(Self::len)
+            .sum::<usize>()
+            .next_multiple_of(4)
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Mss(_) => 4,
+            Self::WindowScale(_) => 3,
+            Self::SackPermitted => 2,
+            Self::Sack(blocks) => 2 + 8 * blocks.len(),
+            Self::Timestamps { .. } => 10,
+            Self::FastOpen(cookie) => 2 + cookie.len(),
+            Self::Md5Signature(_) => 18,
+            Self::Authentication { mac, .. } => 4 + mac.len(),
+            Self::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let kind = match self {
+            Self::Mss(_) => KIND_MSS,
+            Self::WindowScale(_) => KIND_WINDOW_SCALE,
+            Self::SackPermitted => KIND_SACK_PERMITTED,
+            Self::Sack(_) => KIND_SACK,
+            Self::Timestamps { .. } => KIND_TIMESTAMPS,
+            Self::FastOpen(_) => KIND_FAST_OPEN,
+            Self::Md5Signature(_) => KIND_MD5_SIGNATURE,
+            Self::Authentication { .. } => KIND_AUTHENTICATION,
+            Self::Unknown { kind, .. } => *kind,
+        };
+        bytes.extend([kind, self.len() as u8]);
+        match self {
+            Self::Mss(mss) => bytes.extend(mss.to_be_bytes()),
+            Self::WindowScale(shift) => bytes.push(*shift),
+            Self::SackPermitted => {}
+            Self::Sack(blocks) => {
+                for (start, end) in blocks {
+                    bytes.extend(start.to_be_bytes());
+                    bytes.extend(end.to_be_bytes());
+                }
+            }
+            Self::Timestamps { value, echo } => {
+                bytes.extend(value.to_be_bytes());
+                bytes.extend(echo.to_be_bytes());
+            }
+            Self::FastOpen(cookie) => bytes.extend(cookie),
+            Self::Md5Signature(digest) => bytes.extend(digest),
+            Self::Authentication {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => {
+                bytes.extend([*key_id, *rnext_key_id]);
+                bytes.extend(mac);
+            }
+            Self::Unknown { data, .. } => bytes.extend(data),
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[test]
+fn test_parse_options() {
+    // The options of the SYN in `test_tcp_checksum`
+    let bytes = [
+        0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a, 0x7e, 0x4c, 0x3f, 0x0d,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+    ];
+    let options = TcpOption::parse(&bytes).unwrap();
+    assert_eq!(
+        options,
+        [
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                value: 0x7e4c_3f0d,
+                echo: 0
+            },
+            TcpOption::WindowScale(7),
+        ]
+    );
+
+    // Zero length, running past the end, and a bad length for the kind
+    assert_eq!(TcpOption::parse(&[0x22, 0x00]), None);
+    assert_eq!(TcpOption::parse(&[0x02, 0x04, 0x05]), None);
+    assert_eq!(TcpOption::parse(&[0x02, 0x03, 0x05, 0x00]), None);
+    // Unknown options are skipped, nothing after the end is looked at
+    assert_eq!(
+        TcpOption::parse(&[0xfd, 0x03, 0xff, 0x00, 0x02]).unwrap(),
+        [TcpOption::Unknown {
+            kind: 0xfd,
+            data: vec![0xff]
+        }]
+    );
+}
+
+#[test]
+fn test_encode_options() {
+    let options = [
+        TcpOption::Mss(1460),
+        TcpOption::SackPermitted,
+        TcpOption::WindowScale(7),
+    ];
+    let bytes = TcpOption::encode(&options);
+    assert_eq!(
+        bytes,
+        [
+            0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x03, 0x03, 0x07, 0x00, 0x00,
+            0x00
+        ]
+    );
+    assert_eq!(TcpOption::encoded_len(&options), bytes.len());
+    assert_eq!(TcpOption::parse(&bytes).unwrap(), options);
+
+    let sack = [TcpOption::Sack(vec![(1, 2), (3, 4)])];
+    assert_eq!(TcpOption::parse(&TcpOption::encode(&sack)).unwrap(), sack);
+    let fast_open =
+        [TcpOption::FastOpen(vec![]), TcpOption::FastOpen(vec![7; 8])];
+    assert_eq!(
+        TcpOption::parse(&TcpOption::encode(&fast_open)).unwrap(),
+        fast_open
+    );
+    // Cookies are 4 to 16 bytes
+    assert_eq!(TcpOption::parse(&[34, 4, 0, 0]), None);
+
+    let auth = [
+        TcpOption::Md5Signature([1; 16]),
+        TcpOption::Authentication {
+            key_id: 1,
+            rnext_key_id: 2,
+            mac: vec![3; 12],
+        },
+    ];
+    assert_eq!(TcpOption::parse(&TcpOption::encode(&auth)).unwrap(), auth);
+}
@@ -0,0 +1,179 @@
+use super::seq_le;
+use std::time::{Duration, Instant};
+
+/// Every host takes datagrams this large, RFC 1122 section 3.3.3, so no
+/// path MTU is taken to be smaller.
+pub const MIN_MTU: usize = 576;
+/// Where the search starts over after a black hole, RFC 4821 section 7.2.
+const BASE_MTU: usize = 1024;
+/// The search stops once it is narrowed down this far.
+const SEARCH_GRANULARITY: usize = 32;
+/// How long until larger sizes are tried again, RFC 1191 section 6.3 and
+/// RFC 4821 section 7.7.
+pub const RESEARCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Next-hop MTUs to guess from the size of the datagram that did not fit,
+/// for routers that don't report theirs, RFC 1191 section 7.
+const PLATEAUS: [usize; 6] = [32000, 17914, 8166, 4352, 2002, 1492];
+
+/// The largest packets that make it to the peer. Lowered by ICMP
+/// "fragmentation needed" messages, RFC 1191, or when full-sized segments
+/// disappear without any, and found again by probing with larger segments,
+/// RFC 4821.
+#[derive(Debug)]
+pub struct PathMtu {
+    mtu: usize,
+    /// Neither the interface nor the peer's MSS allow for more.
+    max: usize,
+    /// The smallest size known not to make it.
+    search_high: usize,
+    /// The size and sequence space end of the probe in flight.
+    probe: Option<(usize, u32)>,
+    /// When the search may start over.
+    research_at: Option<Instant>,
+}
+
+impl PathMtu {
+    pub fn new(max: usize) -> Self {
+        Self {
+            mtu: max,
+            max,
+            search_high: max,
+            probe: None,
+            research_at: None,
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn set_max(&mut self, max: usize) {
+        self.max = self.max.min(max);
+        self.mtu = self.mtu.min(self.max);
+        self.search_high = self.search_high.min(self.max);
+    }
+
+    /// Takes in the next-hop MTU of an ICMP "fragmentation needed" for a
+    /// datagram of `len` bytes, zero if the router did not say. Returns
+    /// whether the path MTU went down.
+    pub fn on_too_big(&mut self, next_hop: usize, len: usize) -> bool {
+        let next_hop = if (MIN_MTU..len).contains(&next_hop) {
+            next_hop
+        } else {
+            PLATEAUS
+                .into_iter()
+                .find(|&plateau| plateau < len)
+                .unwrap_or(MIN_MTU)
+        };
+        self.lower(next_hop)
+    }
+
+    /// Takes in a path MTU learned elsewhere, like from another connection
+    /// to the same peer. Returns whether the path MTU went down.
+    pub fn lower(&mut self, mtu: usize) -> bool {
+        let mtu = mtu.max(MIN_MTU);
+        self.search_high = self.search_high.min(mtu);
+        if self.probe.is_some_and(|(size, _)| size > mtu) {
+            self.probe = None;
+        }
+        if mtu >= self.mtu {
+            return false;
+        }
+        self.mtu = mtu;
+        true
+    }
+
+    /// Full-sized segments keep timing out: something on the path may be
+    /// dropping them without telling, RFC 4821 section 7.5. Returns whether
+    /// the path MTU went down.
+    pub fn on_black_hole(&mut self) -> bool {
+        if self.mtu <= BASE_MTU {
+            return false;
+        }
+        self.search_high = self.mtu;
+        self.mtu = BASE_MTU;
+        self.probe = None;
+        self.research_at = None;
+        true
+    }
+
+    /// The size of the probe to send next, if there is something left to
+    /// search, RFC 4821 section 7.3.
+    pub fn probe_size(&mut self, now: Instant) -> Option<usize> {
+        if self.probe.is_some() || self.mtu >= self.max {
+            return None;
+        }
+        match self.research_at {
+            Some(at) if at > now => return None,
+            Some(_) => {
+                self.research_at = None;
+                self.search_high = self.max;
+            }
+            None => {}
+        }
+        if self.search_high < self.mtu + SEARCH_GRANULARITY {
+            self.research_at = Some(now + RESEARCH_INTERVAL);
+            return None;
+        }
+        Some((self.mtu + self.search_high).div_ceil(2))
+    }
+
+    pub fn on_probe_sent(&mut self, size: usize, end: u32) {
+        self.probe = Some((size, end));
+    }
+
+    pub fn probe_end(&self) -> Option<u32> {
+        self.probe.map(|(_, end)| end)
+    }
+
+    /// Returns whether the path MTU went up, because `ack` covers the
+    /// probe.
+    pub fn on_ack(&mut self, ack: u32) -> bool {
+        match self.probe {
+            Some((size, end)) if seq_le(end, ack) => {
+                self.probe = None;
+                self.mtu = size;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn on_probe_lost(&mut self) {
+        if let Some((size, _)) = self.probe.take() {
+            self.search_high = self.search_high.min(size - 1);
+        }
+    }
+}
+
+#[test]
+fn test_path_mtu() {
+    let now = Instant::now();
+    let mut path = PathMtu::new(1500);
+    assert_eq!(path.probe_size(now), None);
+
+    // A router without the next-hop MTU field, then one with
+    assert!(path.on_too_big(0, 1500));
+    assert_eq!(path.mtu(), 1492);
+    assert!(path.on_too_big(1400, 1492));
+    assert!(!path.on_too_big(1450, 1500));
+    assert_eq!(path.mtu(), 1400);
+    assert_eq!(path.probe_size(now), None);
+
+    // Ten minutes later, larger sizes are tried again
+    let later = now + RESEARCH_INTERVAL;
+    assert_eq!(path.probe_size(later), Some(1450));
+    path.on_probe_sent(1450, 1000);
+    path.on_probe_lost();
+    assert_eq!(path.probe_size(later), Some(1425));
+    path.on_probe_sent(1425, 2000);
+    assert!(!path.on_ack(1999));
+    assert!(path.on_ack(2000));
+    assert_eq!(path.mtu(), 1425);
+    assert_eq!(path.probe_size(later), None);
+
+    // Without ICMP, full-sized segments going missing have to do
+    assert!(path.on_black_hole());
+    assert_eq!(path.mtu(), BASE_MTU);
+    assert_eq!(path.probe_size(later), Some(1225));
+}
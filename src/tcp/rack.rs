@@ -0,0 +1,119 @@
+use super::retransmit::TxSegment;
+use super::{seq_gt, seq_lt};
+use std::time::{Duration, Instant};
+
+/// RACK's view of the connection, RFC 8985 section 6.1: the most recently
+/// sent segment known to be delivered. Anything sent a reordering window
+/// before it should have been delivered by now as well.
+#[derive(Debug, Default)]
+pub struct Rack {
+    /// Send time, end and round trip time of that segment.
+    xmit_ts: Option<Instant>,
+    end_seq: u32,
+    rtt: Duration,
+    /// The highest end of a segment delivered so far.
+    fack: Option<u32>,
+    min_rtt: Option<Duration>,
+    /// Set once a segment was delivered after a later one.
+    reordering_seen: bool,
+}
+
+impl Rack {
+    /// Takes a segment newly acknowledged or SACKed into account, RFC 8985
+    /// section 6.2 steps 1 and 2.
+    pub fn on_delivered(&mut self, segment: &TxSegment, now: Instant) {
+        let rtt = now.saturating_duration_since(segment.sent_at);
+        // Too quick for the retransmission, the original was delivered
+        if segment.retransmitted && self.min_rtt.is_some_and(|min| rtt < min) {
+            return;
+        }
+        let end = segment.end();
+        if !segment.retransmitted && self.fack.is_some_and(|f| seq_lt(end, f)) {
+            self.reordering_seen = true;
+        }
+        if self.fack.is_none_or(|fack| seq_gt(end, fack)) {
+            self.fack = Some(end);
+        }
+        if !segment.retransmitted {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        }
+        let newer = self.xmit_ts.is_none_or(|ts| {
+            segment.sent_at > ts
+                || (segment.sent_at == ts && seq_gt(end, self.end_seq))
+        });
+        if newer {
+            self.xmit_ts = Some(segment.sent_at);
+            self.end_seq = end;
+            self.rtt = rtt;
+        }
+    }
+
+    /// How long a segment may be late before it is considered lost, RFC
+    /// 8985 section 6.2 step 4. Without DSACK it does not adapt beyond a
+    /// quarter of the minimum RTT. Until reordering shows up, it is zero
+    /// once a loss is certain enough for fast recovery.
+    pub fn reo_wnd(
+        &self,
+        recovering: bool,
+        srtt: Option<Duration>,
+    ) -> Duration {
+        if !self.reordering_seen && recovering {
+            return Duration::ZERO;
+        }
+        let reo_wnd = self.min_rtt.unwrap_or_default() / 4;
+        srtt.map_or(reo_wnd, |srtt| reo_wnd.min(srtt))
+    }
+
+    /// When `segment` counts as lost, if it was sent before the most
+    /// recently delivered one. Sequence numbers break ties between segments
+    /// sent at the same time, but a retransmission went out after the
+    /// originals of its burst.
+    pub fn loss_deadline(
+        &self,
+        segment: &TxSegment,
+        reo_wnd: Duration,
+    ) -> Option<Instant> {
+        let xmit_ts = self.xmit_ts?;
+        let sent_before = segment.sent_at < xmit_ts
+            || (segment.sent_at == xmit_ts
+                && !segment.retransmitted
+                && seq_lt(segment.end(), self.end_seq));
+        sent_before.then(|| segment.sent_at + self.rtt + reo_wnd)
+    }
+}
+
+#[test]
+fn test_rack() {
+    use super::rate::DeliveryRate;
+    use super::TcpFlags;
+
+    let now = Instant::now();
+    let delivery = DeliveryRate::new(now).on_send(0, now);
+    let segment = |i: u32, sent_at: Instant| TxSegment {
+        seq: i * 100,
+        len: 100,
+        flags: TcpFlags::empty(),
+        sent_at,
+        retransmitted: false,
+        lost: false,
+        sacked: false,
+        delivery,
+    };
+    let ms = Duration::from_millis;
+    let mut rack = Rack::default();
+    rack.on_delivered(&segment(0, now), now + ms(100));
+    assert_eq!(rack.reo_wnd(false, None), ms(25));
+
+    // Segment 1 is overtaken by segment 2 and lost a window later
+    rack.on_delivered(&segment(2, now + ms(20)), now + ms(120));
+    let reo_wnd = rack.reo_wnd(false, None);
+    let deadline = rack.loss_deadline(&segment(1, now + ms(10)), reo_wnd);
+    assert_eq!(deadline, Some(now + ms(135)));
+    assert_eq!(rack.loss_deadline(&segment(3, now + ms(30)), reo_wnd), None);
+    assert_eq!(rack.reo_wnd(true, None), Duration::ZERO);
+
+    // It was only late after all
+    rack.on_delivered(&segment(1, now + ms(10)), now + ms(130));
+    assert!(rack.reordering_seen);
+    assert_eq!(rack.reo_wnd(true, Some(ms(10))), ms(10));
+}
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+/// The connection's delivery counters at the time a segment was sent.
+#[derive(Debug, Copy, Clone)]
+pub struct DeliverySnapshot {
+    pub delivered: u64,
+    pub delivered_at: Instant,
+    pub first_sent_at: Instant,
+    pub app_limited: bool,
+}
+
+/// One measurement of how fast the network delivered data.
+#[derive(Debug, Clone)]
+pub struct RateSample {
+    /// Bytes per second.
+    pub delivery_rate: f64,
+    /// Round trip time of the newest segment acknowledged, unless it was
+    /// retransmitted.
+    pub rtt: Option<Duration>,
+    /// `delivered` when that segment was sent.
+    pub prior_delivered: u64,
+    /// Bytes delivered over the life of the connection.
+    pub delivered: u64,
+    /// Bytes newly acknowledged.
+    pub acked: usize,
+    /// Bytes still in flight.
+    pub in_flight: usize,
+    /// The sender was not using all of its window, so the rate may be
+    /// lower than what the network could do.
+    pub is_app_limited: bool,
+    pub now: Instant,
+}
+
+/// Delivery rate estimation as described in
+/// draft-cheng-iccrg-delivery-rate-estimation: every segment remembers how
+/// much had been delivered when it was sent, and its acknowledgement turns
+/// the difference into a rate.
+#[derive(Debug)]
+pub struct DeliveryRate {
+    delivered: u64,
+    delivered_at: Instant,
+    first_sent_at: Instant,
+    /// Samples are app limited until `delivered` passes this, 0 if not.
+    app_limited_until: u64,
+}
+
+impl DeliveryRate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            delivered: 0,
+            delivered_at: now,
+            first_sent_at: now,
+            app_limited_until: 0,
+        }
+    }
+
+    /// Takes the snapshot for a segment that is being (re)transmitted.
+    pub fn on_send(
+        &mut self,
+        in_flight: usize,
+        now: Instant,
+    ) -> DeliverySnapshot {
+        if in_flight == 0 {
+            // Don't count idle time in the next sample
+            self.first_sent_at = now;
+            self.delivered_at = now;
+        }
+        DeliverySnapshot {
+            delivered: self.delivered,
+            delivered_at: self.delivered_at,
+            first_sent_at: self.first_sent_at,
+            app_limited: self.app_limited_until != 0,
+        }
+    }
+
+    /// The sender ran out of data while the window had room for more.
+    pub fn set_app_limited(&mut self, in_flight: usize) {
+        self.app_limited_until = (self.delivered + in_flight as u64).max(1);
+    }
+
+    /// Accounts for `acked` newly acknowledged bytes. `newest` is the send
+    /// time, retransmission flag and snapshot of the most recently sent
+    /// segment that is now fully acknowledged.
+    pub fn on_ack(
+        &mut self,
+        acked: usize,
+        newest: Option<(Instant, bool, DeliverySnapshot)>,
+        in_flight: usize,
+        now: Instant,
+    ) -> Option<RateSample> {
+        self.delivered += acked as u64;
+        self.delivered_at = now;
+        if self.app_limited_until != 0
+            && self.delivered > self.app_limited_until
+        {
+            self.app_limited_until = 0;
+        }
+
+        let (sent_at, retransmitted, snapshot) = newest?;
+        self.first_sent_at = sent_at;
+        // The slower of the send and ACK rates, so that neither bursts of
+        // sending nor compressed ACKs inflate the estimate.
+        let send_elapsed =
+            sent_at.saturating_duration_since(snapshot.first_sent_at);
+        let ack_elapsed = now.saturating_duration_since(snapshot.delivered_at);
+        let interval = send_elapsed.max(ack_elapsed);
+        if interval.is_zero() {
+            return None;
+        }
+        let delivered = self.delivered - snapshot.delivered;
+        Some(RateSample {
+            delivery_rate: delivered as f64 / interval.as_secs_f64(),
+            rtt: (!retransmitted)
+                .then(|| now.saturating_duration_since(sent_at)),
+            prior_delivered: snapshot.delivered,
+            delivered: self.delivered,
+            acked,
+            in_flight,
+            is_app_limited: snapshot.app_limited,
+            now,
+        })
+    }
+}
+
+#[test]
+fn test_delivery_rate() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut rate = DeliveryRate::new(start);
+
+    // Ten 1000 byte segments sent 1ms apart, acknowledged 100ms later
+    let snapshots: Vec<_> = (0..10)
+        .map(|i| {
+            (
+                start + ms(i),
+                rate.on_send(i as usize * 1000, start + ms(i)),
+            )
+        })
+        .collect();
+    let mut sample = None;
+    for (i, &(sent_at, snapshot)) in snapshots.iter().enumerate() {
+        let now = sent_at + ms(100);
+        let in_flight = (9 - i) * 1000;
+        sample =
+            rate.on_ack(1000, Some((sent_at, false, snapshot)), in_flight, now);
+    }
+    let sample = sample.unwrap();
+    assert_eq!(sample.delivered, 10_000);
+    assert_eq!(sample.prior_delivered, 0);
+    assert_eq!(sample.rtt, Some(ms(100)));
+    // 10000 bytes over the 109ms since the first segment was sent
+    assert_eq!(sample.delivery_rate, 10_000.0 / 0.109);
+    assert!(!sample.is_app_limited);
+
+    rate.set_app_limited(0);
+    let now = start + ms(200);
+    let snapshot = rate.on_send(0, now);
+    let sample = rate
+        .on_ack(1000, Some((now, true, snapshot)), 0, now + ms(50))
+        .unwrap();
+    assert!(sample.is_app_limited);
+    assert_eq!(sample.rtt, None);
+    assert_eq!(sample.delivery_rate, 1000.0 / 0.05);
+}
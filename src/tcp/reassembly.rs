@@ -0,0 +1,295 @@
+use super::{seq_ge, seq_gt, seq_le, seq_lt};
+use crate::packet::Packet;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// Part of the data of a received packet, held on to without copying.
+pub struct Fragment {
+    /// The sequence number of the first byte kept.
+    seq: u32,
+    packet: Packet,
+    /// What is kept of `packet.data()`.
+    range: Range<usize>,
+}
+
+impl Fragment {
+    pub fn data(&self) -> &[u8] {
+        &self.packet.data().unwrap()[self.range.clone()]
+    }
+
+    fn len(&self) -> u32 {
+        self.range.len() as u32
+    }
+
+    fn end_seq(&self) -> u32 {
+        self.seq.wrapping_add(self.len())
+    }
+
+    /// Memory charged to the queue: the whole buffer, headers and all.
+    fn size(&self) -> usize {
+        self.packet.data.len()
+    }
+
+    fn trim_front(&mut self, n: u32) {
+        self.seq = self.seq.wrapping_add(n);
+        self.range.start += n as usize;
+    }
+
+    fn trim_back(&mut self, n: u32) {
+        self.range.end -= n as usize;
+    }
+}
+
+/// Data received ahead of `rcv_nxt`, kept as sorted fragments that do not
+/// overlap. Each fragment keeps the packet it arrived in until the queue
+/// grows past its memory limit.
+pub struct ReassemblyQueue {
+    fragments: VecDeque<Fragment>,
+    /// The sequence number of a FIN that arrived ahead of `rcv_nxt`, like
+    /// Linux keeps it with its segment in the out-of-order queue.
+    fin: Option<u32>,
+    /// A sequence number from the most recently received segment, whose
+    /// block is reported first in SACK options.
+    last_seq: Option<u32>,
+    /// The sum of the fragments' sizes.
+    memory: usize,
+    limit: usize,
+}
+
+impl ReassemblyQueue {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            fragments: VecDeque::new(),
+            fin: None,
+            last_seq: None,
+            memory: 0,
+            limit,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.fragments.clear();
+        self.fin = None;
+        self.last_seq = None;
+        self.memory = 0;
+    }
+
+    /// Queues `packet.data()[range]`, which starts at `seq`. Where it
+    /// overlaps the ends of queued fragments it is trimmed, fragments it
+    /// covers entirely are dropped in its favour.
+    pub fn insert(&mut self, seq: u32, packet: Packet, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.last_seq = Some(seq);
+        let mut fragment = Fragment { seq, packet, range };
+        let mut i = 0;
+        while let Some(other) = self.fragments.get(i) {
+            if seq_le(other.end_seq(), fragment.seq) {
+                i += 1;
+            } else if seq_le(fragment.end_seq(), other.seq) {
+                break;
+            } else if seq_le(other.seq, fragment.seq) {
+                if seq_ge(other.end_seq(), fragment.end_seq()) {
+                    // Nothing new
+                    return;
+                }
+                fragment.trim_front(other.end_seq().wrapping_sub(fragment.seq));
+                i += 1;
+            } else if seq_le(other.end_seq(), fragment.end_seq()) {
+                let other = self.fragments.remove(i).unwrap();
+                self.memory -= other.size();
+            } else {
+                fragment.trim_back(fragment.end_seq().wrapping_sub(other.seq));
+                break;
+            }
+        }
+        self.memory += fragment.size();
+        self.fragments.insert(i, fragment);
+
+        if self.memory > self.limit {
+            self.collapse();
+        }
+        while self.memory > self.limit {
+            // Like Linux, give up on the data furthest ahead first
+            let dropped = self.fragments.pop_back().unwrap();
+            self.memory -= dropped.size();
+        }
+    }
+
+    /// Remembers that the peer's FIN is at `seq`, after the last byte it
+    /// sends.
+    pub fn insert_fin(&mut self, seq: u32) {
+        self.fin = Some(seq);
+    }
+
+    /// Whether the FIN is next once everything before `rcv_nxt` has been
+    /// taken. It is forgotten when it is.
+    pub fn take_fin(&mut self, rcv_nxt: u32) -> bool {
+        if self.fin != Some(rcv_nxt) {
+            return false;
+        }
+        self.fin = None;
+        true
+    }
+
+    /// Takes the data that continues at `rcv_nxt`, if any arrived.
+    pub fn pop(&mut self, rcv_nxt: u32) -> Option<Fragment> {
+        while let Some(first) = self.fragments.front() {
+            if seq_gt(first.seq, rcv_nxt) {
+                return None;
+            }
+            let mut fragment = self.fragments.pop_front().unwrap();
+            self.memory -= fragment.size();
+            if seq_lt(rcv_nxt, fragment.end_seq()) {
+                fragment.trim_front(rcv_nxt.wrapping_sub(fragment.seq));
+                return Some(fragment);
+            }
+        }
+        None
+    }
+
+    /// Start and end of up to `max` blocks for a SACK option, RFC 2018
+    /// section 4: the block holding the latest segment first, then the
+    /// others from the highest down.
+    pub fn sack_blocks(&self, max: usize) -> Vec<(u32, u32)> {
+        let blocks = self.blocks();
+        let latest = self.last_seq.and_then(|last| {
+            blocks
+                .iter()
+                .copied()
+                .find(|&(start, end)| seq_le(start, last) && seq_lt(last, end))
+        });
+        latest
+            .into_iter()
+            .chain(
+                blocks
+                    .into_iter()
+                    .rev()
+                    .filter(|&block| Some(block) != latest),
+            )
+            .take(max)
+            .collect()
+    }
+
+    /// Runs of adjacent fragments, as start and end sequence numbers.
+    fn blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for fragment in &self.fragments {
+            match blocks.last_mut() {
+                Some((_, end)) if *end == fragment.seq => {
+                    *end = fragment.end_seq()
+                }
+                _ => blocks.push((fragment.seq, fragment.end_seq())),
+            }
+        }
+        blocks
+    }
+
+    /// Copies each run of adjacent fragments into a buffer of its own,
+    /// leaving behind headers and trimmed data, like Linux's
+    /// `tcp_collapse`.
+    fn collapse(&mut self) {
+        let mut collapsed: VecDeque<Fragment> = VecDeque::new();
+        let mut run: Vec<u8> = Vec::new();
+        let mut run_seq = 0u32;
+        let mut finish = |run: &mut Vec<u8>, seq: u32| {
+            if !run.is_empty() {
+                let data = std::mem::take(run);
+                collapsed.push_back(Fragment {
+                    seq,
+                    range: 0..data.len(),
+                    packet: Packet {
+                        l3_offset: None,
+                        l4_offset: None,
+                        data_offset: Some(0),
+                        data,
+                    },
+                });
+            }
+        };
+        for fragment in self.fragments.drain(..) {
+            if run_seq.wrapping_add(run.len() as u32) != fragment.seq {
+                finish(&mut run, run_seq);
+                run_seq = fragment.seq;
+            }
+            run.extend(fragment.data());
+        }
+        finish(&mut run, run_seq);
+        self.fragments = collapsed;
+        self.memory = self.fragments.iter().map(Fragment::size).sum();
+    }
+}
+
+#[cfg(test)]
+fn insert_data(queue: &mut ReassemblyQueue, seq: u32, data: &[u8]) {
+    queue.insert(seq, Packet::new_from_data(data), 0..data.len());
+}
+
+#[test]
+fn test_reassembly() {
+    let mut queue = ReassemblyQueue::new(usize::MAX);
+    insert_data(&mut queue, 110, b"bbbb");
+    insert_data(&mut queue, 130, b"dddd");
+    insert_data(&mut queue, 120, b"cc");
+    assert_eq!(queue.sack_blocks(4), [(120, 122), (130, 134), (110, 114)]);
+    assert_eq!(queue.sack_blocks(2), [(120, 122), (130, 134)]);
+
+    // Overlapping both neighbours fills the holes around them
+    insert_data(&mut queue, 112, b"BBxxxxxxxxxxxxxxxxDD");
+    assert_eq!(queue.sack_blocks(4), [(110, 134)]);
+    assert_eq!(queue.fragments.len(), 3);
+
+    assert!(queue.pop(100).is_none());
+    let mut data: Vec<u8> = Vec::new();
+    while let Some(fragment) = queue.pop(112 + data.len() as u32) {
+        data.extend(fragment.data());
+    }
+    assert_eq!(data, b"bbxxxxxxxxxxxxxxxxdddd");
+    assert!(queue.is_empty());
+    assert_eq!(queue.memory, 0);
+
+    // Wrapping around the sequence space
+    insert_data(&mut queue, 4, b"late");
+    insert_data(&mut queue, 0xffff_fffe, b"ab");
+    assert_eq!(queue.sack_blocks(4), [(0xffff_fffe, 0), (4, 8)]);
+    assert_eq!(queue.pop(0xffff_ffff).unwrap().data(), b"b");
+    assert!(queue.pop(0).is_none());
+
+    // A FIN ahead of the data is only taken once the data before it is
+    queue.clear();
+    insert_data(&mut queue, 104, b"efgh");
+    queue.insert_fin(108);
+    assert!(!queue.take_fin(100));
+    assert!(queue.pop(100).is_none());
+    insert_data(&mut queue, 100, b"abcd");
+    assert_eq!(queue.pop(100).unwrap().data(), b"abcd");
+    assert_eq!(queue.pop(104).unwrap().data(), b"efgh");
+    assert!(queue.take_fin(108));
+    assert!(!queue.take_fin(108));
+}
+
+#[test]
+fn test_reassembly_limit() {
+    let size = Packet::new_from_data(&[0; 10]).data.len();
+    let mut queue = ReassemblyQueue::new(3 * size);
+    for seq in [100, 110, 120] {
+        insert_data(&mut queue, seq, &[seq as u8; 10]);
+    }
+    assert_eq!(queue.memory, 3 * size);
+
+    // Collapsing the first three into one buffer makes room for more
+    insert_data(&mut queue, 200, &[2; 10]);
+    insert_data(&mut queue, 300, &[3; 10]);
+    assert_eq!(queue.sack_blocks(4), [(300, 310), (200, 210), (100, 130)]);
+    assert!(queue.memory <= queue.limit);
+
+    // Until there is no room left and the highest data goes first
+    insert_data(&mut queue, 400, &[4; 400]);
+    assert_eq!(queue.sack_blocks(4), [(300, 310), (200, 210), (100, 130)]);
+    assert_eq!(queue.pop(125).unwrap().data(), [120; 5]);
+}
@@ -0,0 +1,430 @@
+use super::rack::Rack;
+use super::rate::DeliverySnapshot;
+use super::{seq_le, seq_lt, TcpFlags};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// RFC 6298 section 5.7
+const SYN_TIMEOUT_RTO: Duration = Duration::from_secs(3);
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Retransmission timeout calculation from RFC 6298.
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Takes a new round trip measurement. Per Karn's algorithm, callers
+    /// must not pass samples from retransmitted segments.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + CLOCK_GRANULARITY.max(4 * self.rttvar);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Doubles the timeout after it expired. It stays backed off until the
+    /// next valid sample.
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    /// The handshake needed a retransmission and so gave no sample; start
+    /// the data phase with a conservative timeout.
+    pub fn syn_timed_out(&mut self) {
+        if self.srtt.is_none() {
+            self.rto = SYN_TIMEOUT_RTO;
+        }
+    }
+}
+
+/// A transmitted but not yet acknowledged segment. The data itself stays in
+/// the connection's send buffer.
+#[derive(Debug, Clone)]
+pub struct TxSegment {
+    pub seq: u32,
+    /// Sequence space occupied, counting SYN and FIN.
+    pub len: u32,
+    /// SYN and/or FIN, the only flags that need to be retransmitted.
+    pub flags: TcpFlags,
+    pub sent_at: Instant,
+    pub retransmitted: bool,
+    /// Considered lost and waiting to be retransmitted.
+    pub lost: bool,
+    /// Reported received by a SACK block.
+    pub sacked: bool,
+    pub delivery: DeliverySnapshot,
+}
+
+impl TxSegment {
+    pub fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.len)
+    }
+
+    pub fn data_len(&self) -> usize {
+        let mut len = self.len;
+        if self.flags.contains(TcpFlags::SYN) {
+            len -= 1;
+        }
+        if self.flags.contains(TcpFlags::FIN) {
+            len -= 1;
+        }
+        len as usize
+    }
+
+    /// Sequence number of the first data byte.
+    pub fn data_seq(&self) -> u32 {
+        self.seq
+            .wrapping_add(self.flags.contains(TcpFlags::SYN) as u32)
+    }
+}
+
+/// Result of removing acknowledged segments from the queue.
+#[derive(Debug, Default)]
+pub struct Acked {
+    /// Send time of the newest fully acknowledged segment, if it was never
+    /// retransmitted and so can be used for an RTT sample.
+    pub rtt_sent_at: Option<Instant>,
+    /// Whether a retransmitted SYN was acknowledged.
+    pub syn_retransmitted: bool,
+    /// Send time, retransmission flag and delivery snapshot of the most
+    /// recently sent segment that was fully acknowledged.
+    pub newest: Option<(Instant, bool, DeliverySnapshot)>,
+}
+
+#[derive(Debug, Default)]
+pub struct RetransmitQueue {
+    segments: VecDeque<TxSegment>,
+}
+
+impl RetransmitQueue {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn push(&mut self, segment: TxSegment) {
+        self.segments.push_back(segment);
+    }
+
+    pub fn front_mut(&mut self) -> Option<&mut TxSegment> {
+        self.segments.front_mut()
+    }
+
+    pub fn back_mut(&mut self) -> Option<&mut TxSegment> {
+        self.segments.back_mut()
+    }
+
+    /// The oldest segment waiting to be retransmitted.
+    pub fn first_lost_mut(&mut self) -> Option<&mut TxSegment> {
+        self.segments.iter_mut().find(|segment| segment.lost)
+    }
+
+    /// Cuts the oldest segment waiting to be retransmitted down to
+    /// `max_len` bytes of data, for after the path MTU went down. The rest
+    /// waits its turn as a segment of its own.
+    pub fn split_first_lost(&mut self, max_len: usize) {
+        let Some(i) = self.segments.iter().position(|segment| segment.lost)
+        else {
+            return;
+        };
+        let segment = &mut self.segments[i];
+        if segment.data_len() <= max_len
+            || segment.flags.contains(TcpFlags::SYN)
+        {
+            return;
+        }
+        let mut rest = segment.clone();
+        rest.seq = segment.seq.wrapping_add(max_len as u32);
+        rest.len = segment.len - max_len as u32;
+        segment.len = max_len as u32;
+        segment.flags.remove(TcpFlags::FIN);
+        self.segments.insert(i + 1, rest);
+    }
+
+    /// Marks the segments with more than `max_len` bytes of data lost: the
+    /// path MTU went down, so they were dropped. Returns whether there were
+    /// any.
+    pub fn mark_oversized_lost(&mut self, max_len: usize) -> bool {
+        let mut marked = false;
+        for segment in &mut self.segments {
+            if !segment.sacked && segment.data_len() > max_len {
+                segment.lost = true;
+                marked = true;
+            }
+        }
+        marked
+    }
+
+    pub fn mark_all_lost(&mut self) {
+        for segment in &mut self.segments {
+            segment.lost = !segment.sacked;
+        }
+    }
+
+    /// Sequence space sent and believed to still be in the network, the
+    /// "pipe" of RFC 6675.
+    pub fn in_flight(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| !segment.lost && !segment.sacked)
+            .map(|segment| segment.len as usize)
+            .sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+
+    /// Updates the scoreboard with the blocks of a SACK option. Only whole
+    /// segments are marked, and passed on to `rack` as delivered.
+    pub fn sack(
+        &mut self,
+        blocks: &[(u32, u32)],
+        rack: &mut Rack,
+        now: Instant,
+    ) {
+        for segment in &mut self.segments {
+            if segment.sacked {
+                continue;
+            }
+            segment.sacked = blocks.iter().any(|&(start, end)| {
+                seq_le(start, segment.seq) && seq_le(segment.end(), end)
+            });
+            if segment.sacked {
+                segment.lost = false;
+                rack.on_delivered(segment, now);
+            }
+        }
+    }
+
+    pub fn sacked_segments(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| segment.sacked)
+            .count()
+    }
+
+    /// Marks the segments that RFC 6675's IsLost() considers lost: those
+    /// with `dupthresh` SACKed segments or more than `dupthresh - 1`
+    /// segments' worth of SACKed data above them. Segments that were
+    /// already retransmitted are left to the retransmission timer. Returns
+    /// whether anything new was marked.
+    pub fn mark_sack_losses(&mut self, dupthresh: u32, mss: usize) -> bool {
+        let mut sacked_segments = 0;
+        let mut sacked_bytes = 0;
+        let mut marked = false;
+        for segment in self.segments.iter_mut().rev() {
+            if segment.sacked {
+                sacked_segments += 1;
+                sacked_bytes += segment.len as usize;
+            } else if !segment.lost
+                && !segment.retransmitted
+                && (sacked_segments >= dupthresh
+                    || sacked_bytes > (dupthresh as usize - 1) * mss)
+            {
+                segment.lost = true;
+                marked = true;
+            }
+        }
+        marked
+    }
+
+    /// Marks the segments that RACK considers lost, RFC 8985 section 6.2
+    /// step 5. Returns whether anything new was marked, and when the
+    /// segments that are merely late will be lost if nothing arrives.
+    pub fn mark_rack_losses(
+        &mut self,
+        rack: &Rack,
+        reo_wnd: Duration,
+        now: Instant,
+    ) -> (bool, Option<Instant>) {
+        let mut marked = false;
+        let mut timeout = None;
+        for segment in &mut self.segments {
+            if segment.sacked || segment.lost {
+                continue;
+            }
+            match rack.loss_deadline(segment, reo_wnd) {
+                Some(deadline) if deadline <= now => {
+                    segment.lost = true;
+                    marked = true;
+                }
+                Some(deadline) => timeout = timeout.max(Some(deadline)),
+                None => {}
+            }
+        }
+        (marked, timeout)
+    }
+
+    /// Drops everything before `ack`, trimming a partially acknowledged
+    /// segment at the front. Segments not SACKed before are passed on to
+    /// `rack` as delivered.
+    pub fn acknowledge(
+        &mut self,
+        ack: u32,
+        rack: &mut Rack,
+        now: Instant,
+    ) -> Acked {
+        let mut acked = Acked::default();
+        while let Some(front) = self.segments.front_mut() {
+            if seq_le(front.end(), ack) {
+                if front.flags.contains(TcpFlags::SYN) && front.retransmitted {
+                    acked.syn_retransmitted = true;
+                }
+                acked.rtt_sent_at =
+                    (!front.retransmitted).then_some(front.sent_at);
+                if acked
+                    .newest
+                    .is_none_or(|(sent_at, _, _)| sent_at <= front.sent_at)
+                {
+                    acked.newest = Some((
+                        front.sent_at,
+                        front.retransmitted,
+                        front.delivery,
+                    ));
+                }
+                if !front.sacked {
+                    rack.on_delivered(front, now);
+                }
+                self.segments.pop_front();
+            } else {
+                if seq_lt(front.seq, ack) {
+                    let n = ack.wrapping_sub(front.seq);
+                    front.flags.remove(TcpFlags::SYN);
+                    front.seq = ack;
+                    front.len -= n;
+                }
+                break;
+            }
+        }
+        acked
+    }
+}
+
+#[test]
+fn test_rto_estimation() {
+    let mut rtt = RttEstimator::default();
+    assert_eq!(rtt.rto(), INITIAL_RTO);
+
+    rtt.sample(Duration::from_millis(400));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(400)));
+    // 400ms + 4 * 200ms
+    assert_eq!(rtt.rto(), Duration::from_millis(1200));
+
+    rtt.sample(Duration::from_millis(800));
+    // srtt = 350 + 100, rttvar = 150 + 100
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(450)));
+    assert_eq!(rtt.rto(), Duration::from_millis(1450));
+
+    rtt.backoff();
+    assert_eq!(rtt.rto(), Duration::from_millis(2900));
+    for _ in 0..10 {
+        rtt.backoff();
+    }
+    assert_eq!(rtt.rto(), MAX_RTO);
+
+    let mut rtt = RttEstimator::default();
+    rtt.sample(Duration::from_millis(10));
+    assert_eq!(rtt.rto(), MIN_RTO);
+}
+
+#[test]
+fn test_karn() {
+    let now = Instant::now();
+    let mut queue = RetransmitQueue::default();
+    let delivery = super::rate::DeliveryRate::new(now).on_send(0, now);
+    for (i, retransmitted) in [false, true].into_iter().enumerate() {
+        queue.push(TxSegment {
+            seq: 100 + i as u32 * 10,
+            len: 10,
+            flags: TcpFlags::empty(),
+            sent_at: now,
+            retransmitted,
+            lost: false,
+            sacked: false,
+            delivery,
+        });
+    }
+
+    let mut rack = Rack::default();
+    let acked = queue.acknowledge(115, &mut rack, now);
+    assert_eq!(acked.rtt_sent_at, Some(now));
+    assert_eq!(queue.front_mut().unwrap().seq, 115);
+    assert_eq!(queue.front_mut().unwrap().len, 5);
+
+    let acked = queue.acknowledge(120, &mut rack, now);
+    assert_eq!(acked.rtt_sent_at, None);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_sack_scoreboard() {
+    let now = Instant::now();
+    let mut queue = RetransmitQueue::default();
+    let delivery = super::rate::DeliveryRate::new(now).on_send(0, now);
+    for i in 0..8 {
+        queue.push(TxSegment {
+            seq: i * 100,
+            len: 100,
+            flags: TcpFlags::empty(),
+            sent_at: now,
+            retransmitted: false,
+            lost: false,
+            sacked: false,
+            delivery,
+        });
+    }
+
+    // Segments 0 and 3 missing
+    let mut rack = Rack::default();
+    queue.sack(&[(100, 300), (400, 550)], &mut rack, now);
+    assert_eq!(queue.in_flight(), 5 * 100);
+    assert!(queue.mark_sack_losses(3, 100));
+    let lost: Vec<u32> = queue
+        .segments
+        .iter()
+        .filter(|segment| segment.lost)
+        .map(|segment| segment.seq)
+        .collect();
+    assert_eq!(lost, [0]);
+
+    // Enough above segment 3 now too
+    queue.sack(&[(400, 700)], &mut rack, now);
+    assert!(queue.mark_sack_losses(3, 100));
+    assert!(queue.segments[3].lost);
+    assert!(!queue.mark_sack_losses(3, 100));
+    assert_eq!(queue.in_flight(), 100);
+}
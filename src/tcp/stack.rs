@@ -0,0 +1,900 @@
+use super::fastopen::FastOpenCookies;
+use super::pmtu::RESEARCH_INTERVAL;
+use super::syncookie::{SynCookie, SynCookies};
+use super::tcb::{reset_for, DEFAULT_MTU};
+use super::{
+    AuthKey, CongestionAlgorithm, Keepalive, Quad, Segment, Tcb, TcpFlags,
+};
+use crate::packet::Packet;
+use crate::timer::{TimerId, TimerWheel};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{ErrorKind, Result};
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+
+struct Listener {
+    /// Bounds both queues, like the backlog of Linux's `listen`.
+    backlog: usize,
+    /// Connections still in the handshake. Once there are `backlog` of
+    /// them, further SYNs are answered with cookies.
+    syn_queue: HashSet<Quad>,
+    /// Connections that completed the handshake and are waiting for `accept`.
+    /// Once there are `backlog` of them, further handshakes are ignored
+    /// until the application catches up.
+    accept_queue: VecDeque<Quad>,
+    /// Whether SYNs may carry data with a Fast Open cookie, off unless
+    /// asked for like Linux's `TCP_FASTOPEN`.
+    fast_open: bool,
+    /// When a SYN was last answered with a SYN cookie.
+    last_syn_cookie: Option<Instant>,
+}
+
+impl Listener {
+    fn accept_queue_full(&self) -> bool {
+        self.accept_queue.len() >= self.backlog
+    }
+}
+
+/// All TCP connections and listeners of the stack.
+pub struct TcpStack {
+    connections: HashMap<Quad, Tcb>,
+    listeners: HashMap<u16, Listener>,
+    outgoing: VecDeque<Packet>,
+    timers: TimerWheel<TcpStack>,
+    /// The wheel timer standing in for each connection's `next_deadline`.
+    scheduled: HashMap<Quad, (Instant, TimerId)>,
+    /// Used by new connections unless told otherwise.
+    congestion_algorithm: CongestionAlgorithm,
+    syn_cookies: SynCookies,
+    fast_open_cookies: FastOpenCookies,
+    /// The Fast Open cookies servers handed out, by their address.
+    fast_open_cache: HashMap<u32, Vec<u8>>,
+    /// Keys to sign the segments to and from each peer address with.
+    auth_keys: HashMap<u32, AuthKey>,
+    /// The interface's, which the MSS we advertise is derived from.
+    mtu: usize,
+    /// Path MTUs learned from ICMP, by peer address, and when, RFC 1191
+    /// section 5. New connections start out with them.
+    path_mtus: HashMap<u32, (usize, Instant)>,
+}
+
+impl Default for TcpStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpStack {
+    pub fn new() -> Self {
+        Self {
+            connections: HashMap::new(),
+            listeners: HashMap::new(),
+            outgoing: VecDeque::new(),
+            timers: TimerWheel::new(Instant::now()),
+            scheduled: HashMap::new(),
+            congestion_algorithm: CongestionAlgorithm::default(),
+            syn_cookies: SynCookies::new(Instant::now()),
+            fast_open_cookies: FastOpenCookies::new(Instant::now()),
+            fast_open_cache: HashMap::new(),
+            auth_keys: HashMap::new(),
+            mtu: DEFAULT_MTU,
+            path_mtus: HashMap::new(),
+        }
+    }
+
+    /// Tells the stack the MTU of its interface, for connections made from
+    /// now on.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
+    pub fn default_congestion_algorithm(&self) -> CongestionAlgorithm {
+        self.congestion_algorithm
+    }
+
+    pub fn set_default_congestion_algorithm(
+        &mut self,
+        algorithm: CongestionAlgorithm,
+    ) {
+        self.congestion_algorithm = algorithm;
+    }
+
+    pub fn set_congestion_algorithm(
+        &mut self,
+        quad: Quad,
+        algorithm: CongestionAlgorithm,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_congestion_algorithm(algorithm))
+    }
+
+    pub fn set_nodelay(
+        &mut self,
+        quad: Quad,
+        nodelay: bool,
+        now: Instant,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_nodelay(nodelay, now))
+    }
+
+    pub fn set_cork(
+        &mut self,
+        quad: Quad,
+        cork: bool,
+        now: Instant,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_cork(cork, now))
+    }
+
+    pub fn set_rack_tlp(&mut self, quad: Quad, enabled: bool) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_rack_tlp(enabled))
+    }
+
+    pub fn set_linger(
+        &mut self,
+        quad: Quad,
+        linger: Option<Duration>,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_linger(linger))
+    }
+
+    pub fn set_keepalive(
+        &mut self,
+        quad: Quad,
+        keepalive: Option<Keepalive>,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.set_keepalive(keepalive))
+    }
+
+    /// Signs all connections to and from `remote_addr` made from now on,
+    /// like Linux's `TCP_MD5SIG`. Connections without the key are not
+    /// accepted from that peer.
+    pub fn set_auth_key(&mut self, remote_addr: u32, key: Option<AuthKey>) {
+        match key {
+            Some(key) => self.auth_keys.insert(remote_addr, key),
+            None => self.auth_keys.remove(&remote_addr),
+        };
+    }
+
+    /// Listening again on the same port changes the backlog.
+    pub fn listen(&mut self, port: u16, backlog: usize) {
+        let listener = self.listeners.entry(port).or_insert_with(|| Listener {
+            backlog,
+            syn_queue: HashSet::new(),
+            accept_queue: VecDeque::new(),
+            fast_open: false,
+            last_syn_cookie: None,
+        });
+        listener.backlog = backlog;
+    }
+
+    /// Stops listening on `port`. Connections nobody has accepted yet are
+    /// reset, like when closing a listening socket on Linux.
+    pub fn unlisten(&mut self, port: u16, now: Instant) {
+        let Some(listener) = self.listeners.remove(&port) else {
+            return;
+        };
+        for quad in listener.syn_queue.into_iter().chain(listener.accept_queue)
+        {
+            let _ = self.with_tcb(quad, |tcb| tcb.abort(now));
+        }
+        self.reap();
+    }
+
+    /// Lets clients of the listener on `port` send data on their SYN, RFC
+    /// 7413. Such connections are ready to `accept` before the handshake
+    /// completes.
+    pub fn set_fast_open(&mut self, port: u16, enabled: bool) -> Result<()> {
+        let listener = self
+            .listeners
+            .get_mut(&port)
+            .ok_or(ErrorKind::InvalidInput)?;
+        listener.fast_open = enabled;
+        Ok(())
+    }
+
+    pub fn accept(&mut self, port: u16) -> Option<Quad> {
+        let listener = self.listeners.get_mut(&port)?;
+        let quad = listener.accept_queue.pop_front()?;
+        self.connections.get_mut(&quad).unwrap().owned = true;
+        Some(quad)
+    }
+
+    /// Connects to `quad.remote_*`. A connection that lingers in TIME-WAIT
+    /// on the same 4-tuple gives way if timestamps were in use.
+    pub fn connect(&mut self, quad: Quad, now: Instant) -> Result<()> {
+        self.claim_quad(quad)?;
+        let auth = self.auth_keys.get(&quad.remote_addr).cloned();
+        self.insert_connection(Tcb::connect(quad, self.mtu, auth, now), now);
+        Ok(())
+    }
+
+    /// Connects with TCP Fast Open. If the server handed out a cookie
+    /// before, the SYN goes out with the data of the first `send`,
+    /// otherwise it asks for a cookie for next time. Like `connect`, it may
+    /// take over a 4-tuple in TIME-WAIT.
+    pub fn connect_fast_open(
+        &mut self,
+        quad: Quad,
+        now: Instant,
+    ) -> Result<()> {
+        self.claim_quad(quad)?;
+        let cookie = self.fast_open_cache.get(&quad.remote_addr).cloned();
+        let auth = self.auth_keys.get(&quad.remote_addr).cloned();
+        let tcb = Tcb::connect_fast_open(quad, cookie, self.mtu, auth, now);
+        self.insert_connection(tcb, now);
+        Ok(())
+    }
+
+    /// Makes way for a new connection on `quad`: a connection that lingers
+    /// in TIME-WAIT on it gives way if timestamps were in use.
+    fn claim_quad(&mut self, quad: Quad) -> Result<()> {
+        match self.connections.get(&quad) {
+            Some(tcb) if tcb.allows_reuse() => {
+                println!("tcp {}: reusing connection in TIME-WAIT", quad);
+                self.connections.remove(&quad);
+                Ok(())
+            }
+            Some(_) => Err(ErrorKind::AddrInUse.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn insert_connection(&mut self, mut tcb: Tcb, now: Instant) {
+        let quad = tcb.quad();
+        tcb.owned = true;
+        tcb.set_congestion_algorithm(self.congestion_algorithm);
+        self.apply_path_mtu(&mut tcb, now);
+        self.connections.insert(quad, tcb);
+        self.sync_timer(quad);
+    }
+
+    pub fn connection(&self, quad: Quad) -> Option<&Tcb> {
+        self.connections.get(&quad)
+    }
+
+    pub fn send(
+        &mut self,
+        quad: Quad,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<usize> {
+        self.with_tcb(quad, |tcb| tcb.send(data, now))?
+    }
+
+    pub fn recv(
+        &mut self,
+        quad: Quad,
+        buf: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        self.with_tcb(quad, |tcb| tcb.recv(buf, now))?
+    }
+
+    pub fn send_urgent(
+        &mut self,
+        quad: Quad,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<usize> {
+        self.with_tcb(quad, |tcb| tcb.send_urgent(data, now))?
+    }
+
+    pub fn recv_oob(&mut self, quad: Quad) -> Result<u8> {
+        self.with_tcb(quad, |tcb| tcb.recv_oob())?
+    }
+
+    pub fn close(&mut self, quad: Quad, now: Instant) {
+        let _ = self.with_tcb(quad, |tcb| tcb.close(now));
+        self.reap();
+    }
+
+    pub fn shutdown(
+        &mut self,
+        quad: Quad,
+        how: Shutdown,
+        now: Instant,
+    ) -> Result<()> {
+        self.with_tcb(quad, |tcb| tcb.shutdown(how, now))?
+    }
+
+    /// Resets the connection and forgets about it.
+    pub fn abort(&mut self, quad: Quad, now: Instant) {
+        let _ = self.with_tcb(quad, |tcb| tcb.abort(now));
+        self.reap();
+    }
+
+    pub fn on_segment(&mut self, seg: Segment, now: Instant) {
+        let quad = seg.quad;
+        let listening = self.listeners.contains_key(&quad.local_port);
+        if listening
+            && self
+                .connections
+                .get(&quad)
+                .is_some_and(|tcb| tcb.accepts_reincarnation(&seg))
+        {
+            println!("tcp {}: new connection replaces TIME-WAIT", quad);
+            self.connections.remove(&quad);
+        }
+        if let Some(tcb) = self.connections.get_mut(&quad) {
+            // Fast Open connections skip the SYN queue
+            let listener = self
+                .listeners
+                .get_mut(&quad.local_port)
+                .filter(|listener| listener.syn_queue.contains(&quad));
+            if seg.flags.contains(TcpFlags::ACK)
+                && listener.as_ref().is_some_and(|l| l.accept_queue_full())
+            {
+                println!("tcp {}: accept queue full, dropping ACK", quad);
+                return;
+            }
+            tcb.on_segment(seg, now);
+            if let Some(cookie) = tcb.take_fast_open_cookie() {
+                self.fast_open_cache.insert(quad.remote_addr, cookie);
+            }
+            if let Some(listener) = listener {
+                if tcb.state().is_synchronized() {
+                    listener.syn_queue.remove(&quad);
+                    listener.accept_queue.push_back(quad);
+                }
+            }
+        } else if listening {
+            self.on_segment_listen(seg, now);
+        } else {
+            println!("tcp {}: no connection or listener, resetting", quad);
+            self.outgoing.extend(reset_for(&seg));
+        }
+        self.sync_timer(quad);
+        self.reap();
+    }
+
+    /// Runs every timer that is due.
+    pub fn on_tick(&mut self, now: Instant) {
+        for callback in self.timers.expire(now) {
+            callback(self, now);
+        }
+        self.reap();
+    }
+
+    /// When `on_tick` next needs to be called.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_expiry()
+    }
+
+    /// Takes every packet the connections have queued for the interface.
+    pub fn take_outgoing(&mut self) -> Vec<Packet> {
+        let mut packets: Vec<Packet> = self.outgoing.drain(..).collect();
+        for tcb in self.connections.values_mut() {
+            packets.extend(tcb.outgoing.drain(..));
+        }
+        packets
+    }
+
+    // RFC 9293 section 3.10.7.2
+    fn on_segment_listen(&mut self, seg: Segment, now: Instant) {
+        if seg.flags.contains(TcpFlags::RST) {
+            return;
+        }
+        let auth = self.auth_keys.get(&seg.quad.remote_addr).cloned();
+        if !is_authentic_for_listener(&seg, auth.as_ref()) {
+            println!("tcp {}: bad or missing signature, dropping", seg.quad);
+            return;
+        }
+        if seg.flags.contains(TcpFlags::ACK) {
+            let overflowed = self.listeners[&seg.quad.local_port]
+                .last_syn_cookie
+                .is_some_and(|sent| SynCookies::is_recent(sent, now));
+            if overflowed && !seg.flags.contains(TcpFlags::SYN) {
+                if let Some(cookie) = self.syn_cookies.decode(&seg, now) {
+                    self.accept_syn_cookie(seg, cookie, auth, now);
+                    return;
+                }
+            }
+            self.outgoing.extend(reset_for(&seg));
+            return;
+        }
+        if !seg.flags.contains(TcpFlags::SYN) {
+            return;
+        }
+        let listener = self.listeners.get_mut(&seg.quad.local_port).unwrap();
+        if listener.accept_queue_full() {
+            println!("tcp {}: accept queue full, dropping SYN", seg.quad);
+            return;
+        }
+        if listener.syn_queue.len() >= listener.backlog {
+            listener.last_syn_cookie = Some(now);
+            let (iss, ts_val) = self.syn_cookies.encode(&seg, now);
+            self.outgoing.push_back(Tcb::syn_cookie_reply(
+                &seg, iss, ts_val, self.mtu, auth, now,
+            ));
+            return;
+        }
+        let mut tcb = match seg.fast_open().filter(|_| listener.fast_open) {
+            Some(cookie)
+                if self.fast_open_cookies.is_valid(
+                    seg.quad.remote_addr,
+                    cookie,
+                    now,
+                ) =>
+            {
+                listener.accept_queue.push_back(seg.quad);
+                Tcb::accept_fast_open(&seg, None, self.mtu, auth, now)
+            }
+            Some(_) => {
+                listener.syn_queue.insert(seg.quad);
+                let cookie =
+                    self.fast_open_cookies.cookie(seg.quad.remote_addr, now);
+                Tcb::accept_fast_open(&seg, Some(cookie), self.mtu, auth, now)
+            }
+            None => {
+                listener.syn_queue.insert(seg.quad);
+                Tcb::accept(&seg, self.mtu, auth, now)
+            }
+        };
+        tcb.set_congestion_algorithm(self.congestion_algorithm);
+        self.apply_path_mtu(&mut tcb, now);
+        self.connections.insert(seg.quad, tcb);
+    }
+
+    fn accept_syn_cookie(
+        &mut self,
+        ack: Segment,
+        cookie: SynCookie,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) {
+        let quad = ack.quad;
+        let listener = self.listeners.get_mut(&quad.local_port).unwrap();
+        if listener.accept_queue_full() {
+            println!("tcp {}: accept queue full, dropping ACK", quad);
+            return;
+        }
+        listener.accept_queue.push_back(quad);
+        let mut tcb = Tcb::from_syn_cookie(ack, cookie, self.mtu, auth, now);
+        tcb.set_congestion_algorithm(self.congestion_algorithm);
+        self.apply_path_mtu(&mut tcb, now);
+        self.connections.insert(quad, tcb);
+    }
+
+    /// Starts a new connection out with the path MTU learned for its peer,
+    /// unless it is old enough that larger sizes should be tried again.
+    fn apply_path_mtu(&mut self, tcb: &mut Tcb, now: Instant) {
+        let remote_addr = tcb.quad().remote_addr;
+        match self.path_mtus.get(&remote_addr) {
+            Some(&(_, at))
+                if now.saturating_duration_since(at) >= RESEARCH_INTERVAL =>
+            {
+                self.path_mtus.remove(&remote_addr);
+            }
+            Some(&(mtu, _)) => tcb.set_path_mtu(mtu, now),
+            None => {}
+        }
+    }
+
+    /// Handles an ICMP "fragmentation needed" quoting a segment we sent on
+    /// `quad` starting at `seq`, `len` bytes with its headers, RFC 1191.
+    /// Every connection to the same peer takes on the lower path MTU.
+    pub fn on_packet_too_big(
+        &mut self,
+        quad: Quad,
+        seq: u32,
+        next_hop: usize,
+        len: usize,
+        now: Instant,
+    ) {
+        let mtu = self
+            .connections
+            .get_mut(&quad)
+            .and_then(|tcb| tcb.on_packet_too_big(seq, next_hop, len, now));
+        let Some(mtu) = mtu else {
+            return;
+        };
+        self.path_mtus.insert(quad.remote_addr, (mtu, now));
+        let peers: Vec<Quad> = self
+            .connections
+            .keys()
+            .filter(|other| other.remote_addr == quad.remote_addr)
+            .copied()
+            .collect();
+        for peer in peers {
+            self.connections
+                .get_mut(&peer)
+                .unwrap()
+                .set_path_mtu(mtu, now);
+            self.sync_timer(peer);
+        }
+    }
+
+    /// Runs `f` on the connection and then brings its timer up to date.
+    fn with_tcb<R>(
+        &mut self,
+        quad: Quad,
+        f: impl FnOnce(&mut Tcb) -> R,
+    ) -> Result<R> {
+        let tcb = self
+            .connections
+            .get_mut(&quad)
+            .ok_or(ErrorKind::NotConnected)?;
+        let result = f(tcb);
+        self.sync_timer(quad);
+        Ok(result)
+    }
+
+    fn on_timer(&mut self, quad: Quad, now: Instant) {
+        self.scheduled.remove(&quad);
+        let _ = self.with_tcb(quad, |tcb| tcb.on_tick(now));
+    }
+
+    /// Reschedules the connection's wheel timer if its deadline moved.
+    fn sync_timer(&mut self, quad: Quad) {
+        let deadline = self.connections.get(&quad).and_then(Tcb::next_deadline);
+        let scheduled = self.scheduled.get(&quad).copied();
+        if scheduled.map(|(at, _)| at) == deadline {
+            return;
+        }
+        if let Some((_, id)) = scheduled {
+            self.timers.cancel(id);
+            self.scheduled.remove(&quad);
+        }
+        if let Some(deadline) = deadline {
+            let id =
+                self.timers.schedule(deadline, move |tcp: &mut Self, now| {
+                    tcp.on_timer(quad, now)
+                });
+            self.scheduled.insert(quad, (deadline, id));
+        }
+    }
+
+    fn reap(&mut self) {
+        let outgoing = &mut self.outgoing;
+        let timers = &mut self.timers;
+        let scheduled = &mut self.scheduled;
+        self.connections.retain(|quad, tcb| {
+            if tcb.is_reapable() {
+                // A final RST or ACK may still need to go out
+                outgoing.extend(tcb.outgoing.drain(..));
+                if let Some((_, id)) = scheduled.remove(quad) {
+                    timers.cancel(id);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        let connections = &self.connections;
+        for listener in self.listeners.values_mut() {
+            listener
+                .syn_queue
+                .retain(|quad| connections.contains_key(quad));
+            listener
+                .accept_queue
+                .retain(|quad| connections.contains_key(quad));
+        }
+    }
+}
+
+/// A listener has no ISNs of its own to check signatures against: a SYN
+/// brings the peer's, and the ACK completing a cookie handshake both.
+fn is_authentic_for_listener(seg: &Segment, key: Option<&AuthKey>) -> bool {
+    let Some(key) = key else {
+        return !seg.is_signed();
+    };
+    let isns = if seg.flags.contains(TcpFlags::SYN) {
+        (seg.seq, 0)
+    } else {
+        (seg.seq.wrapping_sub(1), seg.ack.wrapping_sub(1))
+    };
+    key.verify(seg, isns, 0)
+}
+
+#[test]
+fn test_timers_drive_retransmission() {
+    let mut tcp = TcpStack::new();
+    let now = Instant::now();
+    let quad = Quad {
+        local_addr: 0x0a00_0002,
+        local_port: 40000,
+        remote_addr: 0x0a00_0001,
+        remote_port: 7,
+    };
+    tcp.connect(quad, now).unwrap();
+    assert_eq!(tcp.take_outgoing().len(), 1);
+
+    // The wheel may wake us early to cascade timers, the event loop just
+    // goes around again.
+    let mut outgoing = Vec::new();
+    let mut deadline = now;
+    while outgoing.is_empty() {
+        deadline = tcp.next_deadline().unwrap();
+        tcp.on_tick(deadline);
+        outgoing = tcp.take_outgoing();
+    }
+    assert!(deadline >= now + std::time::Duration::from_secs(1));
+    assert_eq!(outgoing.len(), 1);
+
+    tcp.close(quad, deadline);
+    assert!(tcp.connection(quad).is_none());
+    assert_eq!(tcp.next_deadline(), None);
+}
+
+#[test]
+fn test_listen_backlog() {
+    use super::loopback;
+    use super::tcb::State;
+
+    let mut tcp = TcpStack::new();
+    let now = Instant::now();
+    tcp.listen(7, 1);
+    let client_quad = |port| Quad {
+        local_addr: 0x0a00_0001,
+        local_port: port,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+    let server_quad = |port| Quad {
+        local_addr: 0x0a00_0002,
+        local_port: 7,
+        remote_addr: 0x0a00_0001,
+        remote_port: port,
+    };
+    let mut clients = [
+        Tcb::connect(client_quad(40000), DEFAULT_MTU, None, now),
+        Tcb::connect(client_quad(40001), DEFAULT_MTU, None, now),
+    ];
+    let exchange = |tcp: &mut TcpStack, clients: &mut [Tcb; 2]| {
+        for client in clients.iter_mut() {
+            for packet in client.outgoing.drain(..) {
+                tcp.on_segment(loopback(&packet), now);
+            }
+        }
+        for packet in tcp.take_outgoing() {
+            let seg = loopback(&packet);
+            let i = (seg.quad.local_port - 40000) as usize;
+            clients[i].on_segment(seg, now);
+        }
+    };
+
+    // The second SYN overflows the SYN queue and is answered with a cookie,
+    // which still gets it connected
+    exchange(&mut tcp, &mut clients);
+    assert_eq!(tcp.connections.len(), 1);
+    assert!(clients.iter().all(|c| c.state() == State::Established));
+
+    // The first handshake fills the accept queue, so the ACK carrying the
+    // cookie is dropped until the client's data retries it
+    exchange(&mut tcp, &mut clients);
+    assert_eq!(tcp.connections.len(), 1);
+    assert_eq!(tcp.accept(7), Some(server_quad(40000)));
+    clients[0].send(b"hello", now).unwrap();
+    clients[1].send(b"cookie", now).unwrap();
+    exchange(&mut tcp, &mut clients);
+    assert_eq!(tcp.accept(7), Some(server_quad(40001)));
+    let mut buf = [0; 16];
+    assert_eq!(tcp.recv(server_quad(40000), &mut buf, now).unwrap(), 5);
+    assert_eq!(tcp.recv(server_quad(40001), &mut buf, now).unwrap(), 6);
+    assert_eq!(&buf[..6], b"cookie");
+}
+
+#[test]
+fn test_syn_cookie_needs_overflow() {
+    use super::{loopback, make_packet, TcpHeader};
+    use crate::ip::Ecn;
+
+    let mut tcp = TcpStack::new();
+    let now = Instant::now();
+    tcp.listen(7, 8);
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+    let mut client = Tcb::connect(quad, DEFAULT_MTU, None, now);
+    let syn = loopback(&client.outgoing.pop_front().unwrap());
+
+    // A correct guess at a cookie the listener never sent gets a RST
+    let (iss, _) = tcp.syn_cookies.encode(&syn, now);
+    let mut header = TcpHeader::new(quad.local_port, quad.remote_port);
+    header.seq = syn.seq.wrapping_add(1);
+    header.ack = iss.wrapping_add(1);
+    header.flags = TcpFlags::ACK;
+    let ack = make_packet(quad, header, &[], &[], Ecn::NotEct);
+    assert!(tcp.syn_cookies.decode(&loopback(&ack), now).is_some());
+    tcp.on_segment(loopback(&ack), now);
+    assert!(tcp.connections.is_empty());
+    let rst = loopback(&tcp.take_outgoing().pop().unwrap());
+    assert!(rst.flags.contains(TcpFlags::RST));
+}
+
+#[test]
+fn test_closed_port_reset() {
+    let mut tcp = TcpStack::new();
+    let now = Instant::now();
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 9,
+    };
+    let mut client = Tcb::connect(quad, DEFAULT_MTU, None, now);
+    let syn = client.outgoing.pop_front().unwrap();
+    tcp.on_segment(super::loopback(&syn), now);
+    let rst = super::loopback(&tcp.take_outgoing().pop().unwrap());
+    client.on_segment(rst, now);
+    assert_eq!(client.error(), Some(ErrorKind::ConnectionRefused));
+}
+
+/// Delivers everything two stacks send each other until both go quiet,
+/// returning how many segments were exchanged.
+#[cfg(test)]
+fn exchange(a: &mut TcpStack, b: &mut TcpStack, now: Instant) -> usize {
+    use super::loopback;
+
+    let mut segments = 0;
+    loop {
+        let to_b = a.take_outgoing();
+        let to_a = b.take_outgoing();
+        if to_b.is_empty() && to_a.is_empty() {
+            return segments;
+        }
+        segments += to_b.len() + to_a.len();
+        for packet in to_b {
+            b.on_segment(loopback(&packet), now);
+        }
+        for packet in to_a {
+            a.on_segment(loopback(&packet), now);
+        }
+    }
+}
+
+#[test]
+fn test_fast_open() {
+    use super::loopback;
+
+    let now = Instant::now();
+    let mut client = TcpStack::new();
+    let mut server = TcpStack::new();
+    server.listen(7, 8);
+    server.set_fast_open(7, true).unwrap();
+    let quad = |port| Quad {
+        local_addr: 0x0a00_0001,
+        local_port: port,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+
+    // The first connection gets a cookie
+    client.connect_fast_open(quad(40000), now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert!(client.fast_open_cache.contains_key(&0x0a00_0002));
+    assert!(server.accept(7).is_some());
+
+    // With which the next one's data arrives along with its SYN, and can
+    // be read before the handshake completes
+    client.connect_fast_open(quad(40001), now).unwrap();
+    assert!(client.take_outgoing().is_empty());
+    client.send(quad(40001), b"hello", now).unwrap();
+    let syn = client.take_outgoing();
+    assert_eq!(syn.len(), 1);
+    server.on_segment(loopback(&syn[0]), now);
+    let accepted = server.accept(7).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(accepted, &mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(exchange(&mut client, &mut server, now), 2);
+
+    // A cookie that does not check out costs a round trip, not the data
+    client.fast_open_cache.insert(0x0a00_0002, vec![0; 8]);
+    client.connect_fast_open(quad(40002), now).unwrap();
+    client.send(quad(40002), b"again", now).unwrap();
+    exchange(&mut client, &mut server, now);
+    let accepted = server.accept(7).unwrap();
+    assert_eq!(server.recv(accepted, &mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"again");
+    assert_ne!(client.fast_open_cache[&0x0a00_0002], [0; 8]);
+
+    // If the SYN-ACK to a rejected cookie is lost, the SYN may come again
+    // with its data and gets another SYN-ACK
+    client.fast_open_cache.insert(0x0a00_0002, vec![0; 8]);
+    client.connect_fast_open(quad(40003), now).unwrap();
+    client.send(quad(40003), b"third", now).unwrap();
+    let syn = client.take_outgoing();
+    assert_eq!(syn.len(), 1);
+    assert_eq!(loopback(&syn[0]).data(), b"third");
+    server.on_segment(loopback(&syn[0]), now);
+    assert_eq!(server.take_outgoing().len(), 1);
+    let later = now + Duration::from_secs(1);
+    server.on_segment(loopback(&syn[0]), later);
+    exchange(&mut client, &mut server, later);
+    let accepted = server.accept(7).unwrap();
+    assert_eq!(server.recv(accepted, &mut buf, later).unwrap(), 5);
+    assert_eq!(&buf[..5], b"third");
+}
+
+#[test]
+fn test_signed_connections() {
+    let now = Instant::now();
+    let quad = |port| Quad {
+        local_addr: 0x0a00_0001,
+        local_port: port,
+        remote_addr: 0x0a00_0002,
+        remote_port: 179,
+    };
+    let keys = [
+        AuthKey::Md5(b"bgp".to_vec()),
+        AuthKey::Ao {
+            send_id: 3,
+            recv_id: 7,
+            key: b"bgp".to_vec(),
+        },
+    ];
+    for key in keys {
+        let mut server = TcpStack::new();
+        server.listen(179, 8);
+        let server_key = match &key {
+            AuthKey::Ao { key, .. } => AuthKey::Ao {
+                send_id: 7,
+                recv_id: 3,
+                key: key.clone(),
+            },
+            key => key.clone(),
+        };
+        server.set_auth_key(0x0a00_0001, Some(server_key));
+
+        let mut client = TcpStack::new();
+        client.set_auth_key(0x0a00_0002, Some(key));
+        client.connect(quad(40000), now).unwrap();
+        client.send(quad(40000), b"OPEN", now).unwrap();
+        exchange(&mut client, &mut server, now);
+        let accepted = server.accept(179).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(server.recv(accepted, &mut buf, now).unwrap(), 4);
+
+        // Peers without the key get nowhere, not even a RST
+        let mut unsigned = TcpStack::new();
+        unsigned.connect(quad(40001), now).unwrap();
+        assert_eq!(exchange(&mut unsigned, &mut server, now), 1);
+        assert!(server.accept(179).is_none());
+    }
+}
+
+#[test]
+fn test_time_wait_reuse() {
+    use super::tcb::State;
+
+    let now = Instant::now();
+    let mut client = TcpStack::new();
+    let mut server = TcpStack::new();
+    server.listen(7, 8);
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+
+    // The side that closes first is left in TIME-WAIT
+    client.connect(quad, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    let accepted = server.accept(7).unwrap();
+    client.close(quad, now);
+    exchange(&mut client, &mut server, now);
+    server.close(accepted, now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.connection(quad).unwrap().state(), State::TimeWait);
+    assert!(server.connection(accepted).is_none());
+
+    // Which does not keep the port from being connected again, with or
+    // without Fast Open
+    client.connect(quad, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.connection(quad).unwrap().state(), State::Established);
+    assert_eq!(server.accept(7), Some(accepted));
+    client.close(quad, now);
+    exchange(&mut client, &mut server, now);
+    server.close(accepted, now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.connection(quad).unwrap().state(), State::TimeWait);
+    client.connect_fast_open(quad, now).unwrap();
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.connection(quad).unwrap().state(), State::Established);
+}
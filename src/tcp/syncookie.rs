@@ -0,0 +1,183 @@
+use super::{Quad, Segment};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use std::time::{Duration, Instant};
+
+/// The MSS values a cookie can encode, Linux's `msstab`.
+const MSS_TABLE: [u16; 4] = [536, 1300, 1440, 1460];
+/// Cookies are stamped with a counter that ticks this often, and stay valid
+/// for `MAX_AGE` ticks.
+const COUNTER_PERIOD: Duration = Duration::from_secs(64);
+const MAX_AGE: u32 = 2;
+/// The counter takes the top 8 bits of the cookie, the MSS the rest.
+const COUNTER_SHIFT: u32 = 24;
+const DATA_MASK: u32 = (1 << COUNTER_SHIFT) - 1;
+/// The low bits of our timestamp carry the options a cookie has no room
+/// for, as Linux does: the window scale, or `TS_NO_WSCALE`, whether SACK
+/// was permitted and whether ECN was asked for.
+const TS_WSCALE_MASK: u32 = 0xf;
+const TS_NO_WSCALE: u32 = 0xf;
+const TS_SACK: u32 = 0x10;
+const TS_ECN: u32 = 0x20;
+const TS_OPTION_BITS: u32 = 0x3f;
+
+/// What a valid cookie remembers of the SYN it answered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SynCookie {
+    pub mss: u16,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub ecn: bool,
+}
+
+/// Stateless SYN-ACKs for listeners whose SYN queue is full, RFC 4987
+/// section 3.6. The handshake state lives in our ISN, which the peer's ACK
+/// hands back.
+pub struct SynCookies {
+    secret: [u8; 16],
+    start: Instant,
+}
+
+impl SynCookies {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+            start: now,
+        }
+    }
+
+    /// The ISN to answer `syn` with, and the TSval if it has timestamps.
+    pub fn encode(&self, syn: &Segment, now: Instant) -> (u32, Option<u32>) {
+        let mss = syn.mss().unwrap_or(MSS_TABLE[0]);
+        let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0);
+        let count = self.counter(now);
+        let isn = self
+            .hash(syn.quad, 0)
+            .wrapping_add(syn.seq)
+            .wrapping_add(count << COUNTER_SHIFT)
+            .wrapping_add(
+                self.hash(syn.quad, count).wrapping_add(index as u32)
+                    & DATA_MASK,
+            );
+        let ts_val = syn.timestamps().map(|_| {
+            let clock = now.saturating_duration_since(self.start).as_millis();
+            let window_scale = syn
+                .window_scale()
+                .map_or(TS_NO_WSCALE, |shift| shift.min(14) as u32);
+            let sack = if syn.sack_permitted() { TS_SACK } else { 0 };
+            let ecn = if syn.is_ecn_setup() { TS_ECN } else { 0 };
+            (clock as u32 & !TS_OPTION_BITS) | window_scale | sack | ecn
+        });
+        (isn, ts_val)
+    }
+
+    /// Whether a cookie sent at `sent` may still come back. Only then are
+    /// ACKs checked for one, like with Linux's
+    /// `tcp_synq_no_recent_overflow`, so that a listener that never ran out
+    /// of room is not open to guessed cookies.
+    pub fn is_recent(sent: Instant, now: Instant) -> bool {
+        now <= sent + COUNTER_PERIOD * MAX_AGE
+    }
+
+    /// Checks the cookie in the ACK completing a handshake.
+    pub fn decode(&self, ack: &Segment, now: Instant) -> Option<SynCookie> {
+        let isn = ack.ack.wrapping_sub(1);
+        let syn_seq = ack.seq.wrapping_sub(1);
+        let cookie = isn
+            .wrapping_sub(self.hash(ack.quad, 0))
+            .wrapping_sub(syn_seq);
+        let count = cookie >> COUNTER_SHIFT;
+        let age = self.counter(now).wrapping_sub(count) & 0xff;
+        if age > MAX_AGE {
+            return None;
+        }
+        let index = cookie.wrapping_sub(self.hash(ack.quad, count)) & DATA_MASK;
+        let mss = *MSS_TABLE.get(index as usize)?;
+        let (window_scale, sack_permitted, ecn) = match ack.timestamps() {
+            Some((_, echo)) => {
+                let window_scale = echo & TS_WSCALE_MASK;
+                (
+                    (window_scale != TS_NO_WSCALE)
+                        .then_some(window_scale as u8),
+                    echo & TS_SACK != 0,
+                    echo & TS_ECN != 0,
+                )
+            }
+            None => (None, false, false),
+        };
+        Some(SynCookie {
+            mss,
+            window_scale,
+            sack_permitted,
+            ecn,
+        })
+    }
+
+    /// The low 8 bits of the counter, all that fits in a cookie.
+    fn counter(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.start);
+        (elapsed.as_secs() / COUNTER_PERIOD.as_secs()) as u32 & 0xff
+    }
+
+    /// HMAC-SHA1 of the 4-tuple and counter, truncated to 32 bits.
+    fn hash(&self, quad: Quad, count: u32) -> u32 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).unwrap();
+        mac.update(&quad.to_be_bytes());
+        mac.update(&count.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        u32::from_be_bytes(hash[..4].try_into().unwrap())
+    }
+}
+
+#[test]
+fn test_syn_cookie() {
+    use super::{loopback, make_packet, TcpFlags, TcpHeader, TcpOption};
+    use crate::ip::Ecn;
+
+    let now = Instant::now();
+    let cookies = SynCookies::new(now);
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+    let mut header = TcpHeader::new(quad.local_port, quad.remote_port);
+    header.seq = 1000;
+    header.flags = TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR;
+    let options = [
+        TcpOption::Mss(1400),
+        TcpOption::SackPermitted,
+        TcpOption::Timestamps { value: 5, echo: 0 },
+        TcpOption::WindowScale(7),
+    ];
+    let syn = loopback(&make_packet(quad, header, &options, &[], Ecn::NotEct));
+    let (iss, ts_val) = cookies.encode(&syn, now);
+
+    let ack_for = |ack: u32| {
+        let mut header = TcpHeader::new(quad.local_port, quad.remote_port);
+        header.seq = 1001;
+        header.ack = ack;
+        header.flags = TcpFlags::ACK;
+        let options = [TcpOption::Timestamps {
+            value: 6,
+            echo: ts_val.unwrap(),
+        }];
+        loopback(&make_packet(quad, header, &options, &[], Ecn::NotEct))
+    };
+    let ack = ack_for(iss.wrapping_add(1));
+    let later = now + COUNTER_PERIOD * MAX_AGE;
+    assert_eq!(
+        cookies.decode(&ack, later),
+        Some(SynCookie {
+            mss: 1300,
+            window_scale: Some(7),
+            sack_permitted: true,
+            ecn: true,
+        })
+    );
+    assert_eq!(cookies.decode(&ack, later + COUNTER_PERIOD), None);
+    let forged = ack_for(iss.wrapping_add(1 + (1 << 20)));
+    assert_eq!(cookies.decode(&forged, now), None);
+}
@@ -0,0 +1,3209 @@
+use super::auth::Sne;
+use super::congestion::{Ack, CongestionAlgorithm, CongestionControl};
+use super::isn::isn;
+use super::options::MAX_OPTIONS_LEN;
+use super::pmtu::PathMtu;
+use super::rack::Rack;
+use super::rate::DeliveryRate;
+use super::reassembly::ReassemblyQueue;
+use super::retransmit::{RetransmitQueue, RttEstimator, TxSegment};
+use super::syncookie::SynCookie;
+use super::{
+    make_packet, seq_ge, seq_gt, seq_le, seq_lt, AuthKey, Quad, Segment,
+    TcpFlags, TcpHeader, TcpOption,
+};
+use crate::ip::Ecn;
+use crate::packet::Packet;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+
+/// Connection states from RFC 9293 section 3.3.2. LISTEN is handled by the
+/// listeners in `TcpStack`, which create a `Tcb` in `SynReceived` for each
+/// incoming SYN.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl State {
+    /// Whether the three-way handshake has completed.
+    pub fn is_synchronized(self) -> bool {
+        !matches!(self, Self::Closed | Self::SynSent | Self::SynReceived)
+    }
+}
+
+/// When to probe an idle connection, like Linux's `TCP_KEEPIDLE`,
+/// `TCP_KEEPINTVL` and `TCP_KEEPCNT`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the connection is idle before the first probe.
+    pub idle: Duration,
+    /// The time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes before the connection is given up.
+    pub count: u32,
+}
+
+impl Default for Keepalive {
+    /// Linux's defaults, RFC 1122 section 4.2.3.6 asks for at least two
+    /// hours of idle time.
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(75),
+            count: 9,
+        }
+    }
+}
+
+const MSL: Duration = Duration::from_secs(30);
+/// Timeouts in a row before full-sized segments are suspected too large.
+const BLACK_HOLE_RETRANSMITS: u32 = 2;
+/// Assumed when the peer's SYN has no MSS option.
+const DEFAULT_MSS: usize = 536;
+/// Ethernet's, for interfaces that don't say.
+pub(super) const DEFAULT_MTU: usize = 1500;
+/// What the MSS leaves out of the MTU: IP and TCP headers without options.
+const HEADERS_LEN: usize = 40;
+/// Like Linux, don't let a peer talk us into tiny segments.
+const MIN_MSS: usize = 88;
+const SEND_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+const RECV_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// Just enough window scaling to advertise the whole receive buffer.
+const RCV_WSCALE: u8 = {
+    let mut shift = 0;
+    while RECV_BUFFER_SIZE >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+};
+/// RFC 7323 section 2.3.
+const MAX_WSCALE: u8 = 14;
+/// Room for a full window of out-of-order segments along with the rest of
+/// the packets they came in.
+const REASSEMBLY_LIMIT: usize = 2 * RECV_BUFFER_SIZE;
+/// After this long without an update TS.Recent is too old to compare
+/// against, RFC 7323 section 5.5.
+const PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+/// Give up on a connection after this many retransmissions of the same
+/// segment, like Linux's `tcp_retries2` and `tcp_syn_retries`.
+const MAX_RETRANSMITS: u32 = 15;
+const MAX_SYN_RETRANSMITS: u32 = 6;
+/// Duplicate ACKs that trigger a fast retransmit, RFC 5681 section 3.2.
+const DUPACK_THRESHOLD: u32 = 3;
+/// The worst case delayed ACK timer of RFC 8985 section 7.2, by which a
+/// single outstanding segment may be acknowledged late.
+const TLP_DELAYED_ACK: Duration = Duration::from_millis(200);
+/// Like Linux, never probe for a tail loss sooner than this.
+const MIN_PTO: Duration = Duration::from_millis(10);
+/// The probe timeout before there is an RTT sample.
+const INITIAL_PTO: Duration = Duration::from_secs(1);
+/// The timer wheel ticks in milliseconds, so pacing releases up to a
+/// millisecond's worth of segments at a time.
+const PACING_GRANULARITY: Duration = Duration::from_millis(1);
+/// How long an ACK may be delayed, Linux's minimum. RFC 1122 section
+/// 4.2.3.2 allows up to 500ms.
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(40);
+/// Segments acknowledged right away at the start of a connection, like
+/// Linux's quickack mode, so that the peer's slow start is not held back.
+const QUICK_ACKS: u32 = 16;
+/// Like Linux, corked partial segments go out after this long regardless.
+const CORK_TIMEOUT: Duration = Duration::from_millis(200);
+/// Window probes back off up to this interval, like retransmissions.
+const MAX_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// At most one challenge ACK this often, like Linux's
+/// `tcp_invalid_ratelimit`.
+const CHALLENGE_ACK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Transmission Control Block: the state of one connection.
+pub struct Tcb {
+    quad: Quad,
+    state: State,
+    passive: bool,
+    pub(super) owned: bool,
+    closed_by_user: bool,
+
+    // Send sequence space, RFC 9293 section 3.3.1
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// The largest window the peer has offered, RFC 5961 section 5.
+    max_snd_wnd: u32,
+
+    // Receive sequence space
+    irs: u32,
+    rcv_nxt: u32,
+    /// The right edge of the window we last advertised.
+    rcv_adv: u32,
+
+    /// The largest segment the peer and the path take, less headers.
+    mss: usize,
+    /// Advertised in our SYNs: the interface MTU less headers, RFC 9293
+    /// section 3.7.1.
+    local_mss: usize,
+    path_mtu: PathMtu,
+    /// Unacknowledged and unsent data, starting at `send_buffer_seq`.
+    send_buffer: VecDeque<u8>,
+    send_buffer_seq: u32,
+    recv_buffer: VecDeque<u8>,
+    /// Data received beyond `rcv_nxt`.
+    reassembly: ReassemblyQueue,
+    /// Whether selective acknowledgements are in use; until the peer's SYN
+    /// arrives, whether we offer them.
+    sack_permitted: bool,
+    /// Whether window scaling is in use, RFC 7323 section 2; until the
+    /// peer's SYN arrives, whether we offer it.
+    window_scaling: bool,
+    /// Shift applied to the windows the peer advertises.
+    snd_wscale: u8,
+    /// Shift applied to the windows we advertise.
+    rcv_wscale: u8,
+    /// Whether timestamps are in use, RFC 7323 section 3, or offered.
+    timestamps: bool,
+    /// Whether ECN is in use, RFC 3168 section 6.1.1, or asked for.
+    ecn: bool,
+    /// Set by a Congestion Experienced mark until the peer's CWR, our ACKs
+    /// carry ECE meanwhile.
+    ece: bool,
+    /// Set when the congestion window was reduced for an ECE, until the
+    /// next new data tells the peer with CWR.
+    cwr: bool,
+    /// Our timestamp clock counts milliseconds from a random offset.
+    ts_base: Instant,
+    ts_offset: u32,
+    /// The peer's timestamp to echo, and when it was last updated.
+    ts_recent: u32,
+    ts_recent_age: Instant,
+    /// The acknowledgement number of the last ACK we sent.
+    last_ack_sent: u32,
+    /// Bytes received since our last ACK, and when the delayed ACK for
+    /// them is due.
+    unacked_bytes: usize,
+    delayed_ack_deadline: Option<Instant>,
+    quick_acks: u32,
+    /// The largest segment received, to recognize full-sized ones.
+    rcv_mss: usize,
+    /// Disables Nagle's algorithm, like `TCP_NODELAY`.
+    nodelay: bool,
+    /// Holds back partial segments, like `TCP_CORK`.
+    cork: bool,
+    /// When corked partial data goes out anyway.
+    cork_deadline: Option<Instant>,
+    /// Signs our segments and checks the peer's, RFC 2385 or RFC 5925.
+    auth: Option<AuthKey>,
+    /// TCP-AO sequence number extensions of both directions.
+    snd_sne: Sne,
+    rcv_sne: Sne,
+    /// The Fast Open option of our SYN: a cookie to present or hand out,
+    /// empty to ask for one.
+    fast_open: Option<Vec<u8>>,
+    /// Set while our SYN waits for the first `send`, to carry its data.
+    syn_deferred: bool,
+    /// A cookie the server handed out, until the stack takes it.
+    received_cookie: Option<Vec<u8>>,
+    fin_queued: bool,
+    /// Set by `shutdown(Shutdown::Read)`: data still arrives, but is thrown
+    /// away.
+    read_shutdown: bool,
+    /// Like `SO_LINGER`: how long `close` may take to deliver what is
+    /// queued before the connection is aborted, zero to abort right away.
+    linger: Option<Duration>,
+    linger_deadline: Option<Instant>,
+    /// One past the last urgent byte queued by `send_urgent`, until it is
+    /// acknowledged. Like BSD, the urgent pointer of our segments points
+    /// there, RFC 6093 section 3.
+    snd_up: Option<u32>,
+    /// The peer's urgent pointer, until the stream reaches it.
+    rcv_up: Option<u32>,
+    /// The urgent byte, taken out of the stream, until `recv_oob` reads it.
+    oob: Option<u8>,
+    /// Bytes in the receive buffer before the urgent byte's place.
+    urgent_mark: Option<usize>,
+    fin_seq: Option<u32>,
+    fin_received: bool,
+    time_wait_deadline: Option<Instant>,
+    error: Option<ErrorKind>,
+
+    last_challenge_ack: Option<Instant>,
+    /// Keepalives are off unless asked for.
+    keepalive: Option<Keepalive>,
+    /// When the peer was last heard from.
+    last_received: Instant,
+    /// Keepalive probes sent since then.
+    keepalive_probes: u32,
+
+    /// Set while the peer's window is closed, RFC 9293 section 3.8.6.1.
+    persist_deadline: Option<Instant>,
+    /// Window probes sent since the window closed, for their backoff.
+    persist_probes: u32,
+    /// Window probes the peer has not answered.
+    unanswered_probes: u32,
+
+    rtx_queue: RetransmitQueue,
+    rtt: RttEstimator,
+    rtx_deadline: Option<Instant>,
+    /// Consecutive retransmissions without new data being acknowledged.
+    retransmits: u32,
+
+    congestion_algorithm: CongestionAlgorithm,
+    cc: Box<dyn CongestionControl>,
+    /// Duplicate ACKs received in a row.
+    dupacks: u32,
+    /// `snd_nxt` when fast recovery last started, RFC 6582 section 3.2.
+    recover: u32,
+    /// The inflated window used instead of `cwnd` during fast recovery.
+    recovery_cwnd: Option<usize>,
+    /// `snd_nxt` when the congestion window was last reduced for an ECE.
+    ecn_recover: u32,
+    /// Whether losses are detected by RACK-TLP, RFC 8985, rather than by
+    /// counting duplicate ACKs. Only used along with SACK.
+    rack_tlp: bool,
+    rack: Rack,
+    /// When segments RACK considers late would be lost.
+    reorder_deadline: Option<Instant>,
+    /// When the tail loss probe is due, RFC 8985 section 7.
+    tlp_deadline: Option<Instant>,
+    /// `snd_nxt` after the outstanding probe, and whether it was a
+    /// retransmission.
+    tlp_end: Option<u32>,
+    tlp_retransmitted: bool,
+    delivery: DeliveryRate,
+    /// When pacing allows the next segment to be sent.
+    next_send_at: Option<Instant>,
+    /// Set while output is held back by pacing.
+    pacing_deadline: Option<Instant>,
+
+    pub(super) outgoing: VecDeque<Packet>,
+}
+
+impl Tcb {
+    fn new(
+        quad: Quad,
+        state: State,
+        iss: u32,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        Self {
+            quad,
+            state,
+            passive: false,
+            owned: false,
+            closed_by_user: false,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            max_snd_wnd: 0,
+            irs: 0,
+            rcv_nxt: 0,
+            rcv_adv: 0,
+            mss: DEFAULT_MSS,
+            local_mss: mtu.saturating_sub(HEADERS_LEN).max(MIN_MSS),
+            path_mtu: PathMtu::new(mtu),
+            send_buffer: VecDeque::new(),
+            send_buffer_seq: iss.wrapping_add(1),
+            recv_buffer: VecDeque::new(),
+            reassembly: ReassemblyQueue::new(REASSEMBLY_LIMIT),
+            sack_permitted: true,
+            window_scaling: true,
+            snd_wscale: 0,
+            rcv_wscale: 0,
+            timestamps: true,
+            ecn: true,
+            ece: false,
+            cwr: false,
+            ts_base: now,
+            ts_offset: rand::thread_rng().gen(),
+            ts_recent: 0,
+            ts_recent_age: now,
+            last_ack_sent: 0,
+            unacked_bytes: 0,
+            delayed_ack_deadline: None,
+            quick_acks: QUICK_ACKS,
+            rcv_mss: DEFAULT_MSS,
+            nodelay: false,
+            cork: false,
+            cork_deadline: None,
+            auth,
+            snd_sne: Sne::new(iss),
+            rcv_sne: Sne::default(),
+            fast_open: None,
+            syn_deferred: false,
+            received_cookie: None,
+            fin_queued: false,
+            read_shutdown: false,
+            linger: None,
+            linger_deadline: None,
+            snd_up: None,
+            rcv_up: None,
+            oob: None,
+            urgent_mark: None,
+            fin_seq: None,
+            fin_received: false,
+            time_wait_deadline: None,
+            error: None,
+            last_challenge_ack: None,
+            keepalive: None,
+            last_received: now,
+            keepalive_probes: 0,
+            persist_deadline: None,
+            persist_probes: 0,
+            unanswered_probes: 0,
+            rtx_queue: RetransmitQueue::default(),
+            rtt: RttEstimator::default(),
+            rtx_deadline: None,
+            retransmits: 0,
+            congestion_algorithm: CongestionAlgorithm::default(),
+            cc: CongestionAlgorithm::default().build(DEFAULT_MSS),
+            dupacks: 0,
+            recover: iss,
+            recovery_cwnd: None,
+            ecn_recover: iss,
+            rack_tlp: true,
+            rack: Rack::default(),
+            reorder_deadline: None,
+            tlp_deadline: None,
+            tlp_end: None,
+            tlp_retransmitted: false,
+            delivery: DeliveryRate::new(now),
+            next_send_at: None,
+            pacing_deadline: None,
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    /// Active open: sends a SYN to `quad.remote_*` from an interface with
+    /// `mtu`, signed with `auth` if the peer wants it.
+    pub fn connect(
+        quad: Quad,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let iss = isn(quad, now);
+        let mut tcb = Self::new(quad, State::SynSent, iss, mtu, auth, now);
+        println!("tcp {}: connecting", quad);
+        tcb.transmit(tcb.iss, tcb.syn_flags(), &[], now);
+        tcb
+    }
+
+    /// Active open with TCP Fast Open, RFC 7413. With a `cookie` from an
+    /// earlier connection the SYN waits for the first `send` and carries its
+    /// data, like Linux's `TCP_FASTOPEN_CONNECT`. Without one, the SYN asks
+    /// the server for a cookie.
+    pub fn connect_fast_open(
+        quad: Quad,
+        cookie: Option<Vec<u8>>,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let iss = isn(quad, now);
+        let mut tcb = Self::new(quad, State::SynSent, iss, mtu, auth, now);
+        println!("tcp {}: connecting with fast open", quad);
+        match cookie {
+            Some(cookie) => {
+                tcb.fast_open = Some(cookie);
+                tcb.syn_deferred = true;
+            }
+            None => {
+                tcb.fast_open = Some(Vec::new());
+                tcb.transmit(tcb.iss, tcb.syn_flags(), &[], now);
+            }
+        }
+        tcb
+    }
+
+    /// Passive open: a listener on an interface with `mtu` received `syn`.
+    pub fn accept(
+        syn: &Segment,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let mut tcb = Self::passive_open(syn, mtu, auth, now);
+        tcb.transmit(tcb.iss, tcb.syn_flags(), &[], now);
+        tcb
+    }
+
+    /// Passive open of a SYN with a Fast Open option, RFC 7413 section
+    /// 4.2.2. `cookie` is `None` if the SYN's cookie checked out, and its
+    /// data is taken before the handshake completes. Otherwise the data is
+    /// left for the client to send again and the SYN-ACK hands out `cookie`.
+    pub(super) fn accept_fast_open(
+        syn: &Segment,
+        cookie: Option<Vec<u8>>,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let mut tcb = Self::passive_open(syn, mtu, auth, now);
+        match cookie {
+            Some(cookie) => tcb.fast_open = Some(cookie),
+            None => {
+                let data = syn.data();
+                let n = data.len().min(tcb.rcv_window() as usize);
+                println!("tcp {}: {} bytes of fast open data", syn.quad, n);
+                tcb.recv_buffer.extend(&data[..n]);
+                tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(n as u32);
+            }
+        }
+        tcb.transmit(tcb.iss, tcb.syn_flags(), &[], now);
+        tcb
+    }
+
+    fn passive_open(
+        syn: &Segment,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let iss = isn(syn.quad, now);
+        let mut tcb =
+            Self::new(syn.quad, State::SynReceived, iss, mtu, auth, now);
+        println!("tcp {}: incoming connection", syn.quad);
+        tcb.passive = true;
+        tcb.negotiate(syn, now);
+        tcb.irs = syn.seq;
+        tcb.rcv_nxt = syn.seq.wrapping_add(1);
+        tcb.rcv_sne = Sne::new(syn.seq);
+        tcb
+    }
+
+    /// The SYN-ACK answering `syn` with a cookie, which leaves no
+    /// connection behind. `ts_val` stands in for our timestamp clock, it
+    /// carries the options that did not fit in the cookie.
+    pub(super) fn syn_cookie_reply(
+        syn: &Segment,
+        iss: u32,
+        ts_val: Option<u32>,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Packet {
+        let mut tcb =
+            Self::new(syn.quad, State::SynReceived, iss, mtu, auth, now);
+        // Only the timestamp has room to remember ECN
+        tcb.ecn = ts_val.is_some();
+        tcb.negotiate(syn, now);
+        tcb.irs = syn.seq;
+        tcb.rcv_nxt = syn.seq.wrapping_add(1);
+        if let Some(ts_val) = ts_val {
+            tcb.ts_offset = ts_val;
+        }
+        tcb.send_syn(now);
+        tcb.outgoing.pop_front().unwrap()
+    }
+
+    /// Completes a handshake that `syn_cookie_reply` answered, from what the
+    /// cookie and the options of `ack` remember of it.
+    pub(super) fn from_syn_cookie(
+        ack: Segment,
+        cookie: SynCookie,
+        mtu: usize,
+        auth: Option<AuthKey>,
+        now: Instant,
+    ) -> Self {
+        let iss = ack.ack.wrapping_sub(1);
+        let mut tcb =
+            Self::new(ack.quad, State::Established, iss, mtu, auth, now);
+        println!("tcp {}: connection from a SYN cookie", ack.quad);
+        tcb.passive = true;
+        tcb.snd_una = ack.ack;
+        tcb.irs = ack.seq.wrapping_sub(1);
+        tcb.rcv_nxt = ack.seq;
+        tcb.rcv_sne = Sne::new(tcb.irs);
+        tcb.path_mtu.set_max(cookie.mss as usize + HEADERS_LEN);
+        tcb.update_mss();
+        tcb.cc = tcb.congestion_algorithm.build(tcb.mss);
+        tcb.sack_permitted = cookie.sack_permitted;
+        tcb.ecn = cookie.ecn;
+        match cookie.window_scale {
+            Some(shift) => {
+                tcb.snd_wscale = shift.min(MAX_WSCALE);
+                tcb.rcv_wscale = RCV_WSCALE;
+            }
+            None => tcb.window_scaling = false,
+        }
+        match ack.timestamps() {
+            Some((value, echo)) => {
+                tcb.ts_recent = value;
+                // Carry on from the timestamp in the SYN-ACK
+                tcb.ts_offset = echo;
+            }
+            None => tcb.timestamps = false,
+        }
+        tcb.set_window(&ack);
+        tcb.on_segment(ack, now);
+        tcb
+    }
+
+    pub fn quad(&self) -> Quad {
+        self.quad
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn is_passive(&self) -> bool {
+        self.passive
+    }
+
+    /// The Fast Open cookie from the peer's SYN-ACK, for the stack to use
+    /// on the next connection to the same server.
+    pub(super) fn take_fast_open_cookie(&mut self) -> Option<Vec<u8>> {
+        self.received_cookie.take()
+    }
+
+    pub fn error(&self) -> Option<ErrorKind> {
+        self.error
+    }
+
+    pub fn congestion_algorithm(&self) -> CongestionAlgorithm {
+        self.congestion_algorithm
+    }
+
+    /// Switches to another congestion control algorithm, which starts over
+    /// from its initial window.
+    pub fn set_congestion_algorithm(&mut self, algorithm: CongestionAlgorithm) {
+        self.congestion_algorithm = algorithm;
+        self.cc = algorithm.build(self.mss);
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool, now: Instant) {
+        self.nodelay = nodelay;
+        self.output(now);
+    }
+
+    pub fn cork(&self) -> bool {
+        self.cork
+    }
+
+    /// Removing the cork sends whatever it held back.
+    pub fn set_cork(&mut self, cork: bool, now: Instant) {
+        self.cork = cork;
+        self.cork_deadline = None;
+        self.output(now);
+    }
+
+    pub fn rack_tlp(&self) -> bool {
+        self.rack_tlp
+    }
+
+    /// Switches between RACK-TLP and duplicate ACK counting, on by default
+    /// like on Linux. Without SACK the duplicate ACKs are counted anyway.
+    pub fn set_rack_tlp(&mut self, enabled: bool) {
+        self.rack_tlp = enabled;
+        if !enabled {
+            self.reorder_deadline = None;
+            self.tlp_deadline = None;
+            self.tlp_end = None;
+        }
+    }
+
+    pub fn linger(&self) -> Option<Duration> {
+        self.linger
+    }
+
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn cwnd(&self) -> usize {
+        self.cc.cwnd()
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.cc.ssthresh()
+    }
+
+    pub fn path_mtu(&self) -> usize {
+        self.path_mtu.mtu()
+    }
+
+    /// Handles an ICMP "fragmentation needed" quoting a segment of ours
+    /// starting at `seq`, RFC 1191. Only one that quotes data in flight is
+    /// believed, RFC 5927 section 4.1. Returns the new path MTU, if it went
+    /// down.
+    pub(super) fn on_packet_too_big(
+        &mut self,
+        seq: u32,
+        next_hop: usize,
+        len: usize,
+        now: Instant,
+    ) -> Option<usize> {
+        if !self.state.is_synchronized()
+            || seq_lt(seq, self.snd_una)
+            || seq_ge(seq, self.snd_nxt)
+        {
+            return None;
+        }
+        if !self.path_mtu.on_too_big(next_hop, len) {
+            return None;
+        }
+        self.on_path_mtu_lowered(now);
+        Some(self.path_mtu.mtu())
+    }
+
+    /// Takes on a path MTU that another connection to the same peer
+    /// learned.
+    pub(super) fn set_path_mtu(&mut self, mtu: usize, now: Instant) {
+        if self.path_mtu.lower(mtu) {
+            self.on_path_mtu_lowered(now);
+        }
+    }
+
+    /// Whatever was sent too large for the new path MTU was dropped on
+    /// the way, and goes out again in smaller pieces. This says nothing
+    /// about congestion, RFC 1191 section 6.5.
+    fn on_path_mtu_lowered(&mut self, now: Instant) {
+        self.update_mss();
+        println!("tcp {}: path MTU {}", self.quad, self.path_mtu.mtu());
+        if self.rtx_queue.mark_oversized_lost(self.max_payload()) {
+            self.output(now);
+        }
+    }
+
+    /// Whether the stack can forget about this connection: it is closed and
+    /// nobody holds on to it.
+    pub(super) fn is_reapable(&self) -> bool {
+        self.state == State::Closed && self.is_detached()
+    }
+
+    fn is_detached(&self) -> bool {
+        self.closed_by_user || !self.owned
+    }
+
+    /// Whether `syn` may start a new incarnation of this connection while
+    /// it is in TIME-WAIT, RFC 6191 section 2: its timestamp, or without
+    /// timestamps its sequence number, has to be beyond anything seen
+    /// from the old one, so that nothing old is taken for the new.
+    pub(super) fn accepts_reincarnation(&self, syn: &Segment) -> bool {
+        if self.state != State::TimeWait
+            || !self.is_detached()
+            || !syn.flags.contains(TcpFlags::SYN)
+            || syn.flags.contains(TcpFlags::ACK)
+            || syn.flags.contains(TcpFlags::RST)
+        {
+            return false;
+        }
+        match syn.timestamps() {
+            Some((value, _)) if self.timestamps => {
+                seq_gt(value, self.ts_recent)
+            }
+            _ => seq_gt(syn.seq, self.rcv_nxt),
+        }
+    }
+
+    /// Whether a connection of our own may take over the 4-tuple while this
+    /// one is in TIME-WAIT, like Linux's `tcp_tw_reuse`. Timestamps have to
+    /// be in use, for PAWS to keep old duplicates out of the new one.
+    pub(super) fn allows_reuse(&self) -> bool {
+        self.state == State::TimeWait && self.is_detached() && self.timestamps
+    }
+
+    /// How many bytes `send` would currently accept.
+    pub fn send_space(&self) -> usize {
+        SEND_BUFFER_SIZE - self.send_buffer.len()
+    }
+
+    pub fn send(&mut self, data: &[u8], now: Instant) -> Result<usize> {
+        self.send_data(data, false, now)
+    }
+
+    /// Like `send`, with the last byte sent as urgent data like
+    /// `MSG_OOB`: the peer is told about it through the urgent pointer
+    /// ahead of the data before it, RFC 9293 section 3.8.5.
+    pub fn send_urgent(&mut self, data: &[u8], now: Instant) -> Result<usize> {
+        self.send_data(data, true, now)
+    }
+
+    fn send_data(
+        &mut self,
+        data: &[u8],
+        urgent: bool,
+        now: Instant,
+    ) -> Result<usize> {
+        if let Some(error) = self.error {
+            return Err(error.into());
+        }
+        match self.state {
+            State::SynSent
+            | State::SynReceived
+            | State::Established
+            | State::CloseWait
+                if !self.fin_queued => {}
+            _ => return Err(ErrorKind::BrokenPipe.into()),
+        }
+
+        let n = data.len().min(self.send_space());
+        if n == 0 && !data.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.send_buffer.extend(&data[..n]);
+        if urgent && n > 0 {
+            let end = self
+                .send_buffer_seq
+                .wrapping_add(self.send_buffer.len() as u32);
+            self.snd_up = Some(end);
+        }
+        if self.syn_deferred {
+            self.syn_deferred = false;
+            self.send_fast_open_syn(now);
+        }
+        self.output(now);
+        Ok(n)
+    }
+
+    /// Returns `Ok(0)` once the peer has closed its side of the connection
+    /// and everything before its FIN has been read.
+    pub fn recv(&mut self, buf: &mut [u8], now: Instant) -> Result<usize> {
+        if self.read_shutdown {
+            return Ok(0);
+        }
+        if self.recv_buffer.is_empty() {
+            if let Some(error) = self.error {
+                return Err(error.into());
+            }
+            if self.fin_received || self.state == State::Closed {
+                return Ok(0);
+            }
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = buf.len().min(self.recv_buffer.len());
+        // A read stops at the urgent mark, so `at_mark` can tell where the
+        // urgent byte was
+        match self.urgent_mark {
+            Some(0) => self.urgent_mark = None,
+            Some(mark) => {
+                n = n.min(mark);
+                self.urgent_mark = Some(mark - n);
+            }
+            None => {}
+        }
+        for (dst, src) in buf.iter_mut().zip(self.recv_buffer.drain(..n)) {
+            *dst = src;
+        }
+        // Let the peer know once the window has opened up far enough
+        let window = self.rcv_adv.wrapping_sub(self.rcv_nxt);
+        if matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        ) && self.advertised_window() > window
+        {
+            self.send_ack(now);
+        }
+        Ok(n)
+    }
+
+    /// Whether the next `recv` starts right after the urgent byte, like
+    /// `SIOCATMARK`.
+    pub fn at_mark(&self) -> bool {
+        self.urgent_mark == Some(0)
+    }
+
+    /// Reads the urgent byte out of band, like `MSG_OOB`. Only the latest
+    /// one is kept. Fails with `WouldBlock` while the peer's urgent pointer
+    /// is known but the byte has not arrived yet.
+    pub fn recv_oob(&mut self) -> Result<u8> {
+        if let Some(byte) = self.oob.take() {
+            return Ok(byte);
+        }
+        if self.rcv_up.is_some() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Err(ErrorKind::InvalidInput.into())
+    }
+
+    /// Gives up on the connection: the FIN goes out once everything queued
+    /// has been sent, and the connection is fully closed when the peer does
+    /// the same. Data that arrives after that has nobody to read it, so it
+    /// resets the connection, as does unread data, RFC 1122 section
+    /// 4.2.2.13. So does a linger of zero, and a linger that runs out
+    /// before the FIN is acknowledged.
+    pub fn close(&mut self, now: Instant) {
+        self.closed_by_user = true;
+        if self.linger == Some(Duration::ZERO) || !self.recv_buffer.is_empty() {
+            self.abort(now);
+            return;
+        }
+        if self.state == State::SynSent {
+            self.set_state(State::Closed);
+            return;
+        }
+        self.shutdown_write(now);
+        if let Some(linger) = self.linger {
+            self.linger_deadline = Some(now + linger);
+        }
+    }
+
+    /// Half-closes the connection like `shutdown(2)`. Shutting down the
+    /// write side sends a FIN once everything queued has been sent, while
+    /// the peer can go on sending. Shutting down the read side makes `recv`
+    /// return end of file and throws away whatever the peer sends.
+    pub fn shutdown(&mut self, how: Shutdown, now: Instant) -> Result<()> {
+        if matches!(self.state, State::Closed | State::SynSent) {
+            return Err(ErrorKind::NotConnected.into());
+        }
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.read_shutdown = true;
+            self.recv_buffer.clear();
+            self.urgent_mark = None;
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.shutdown_write(now);
+        }
+        Ok(())
+    }
+
+    fn shutdown_write(&mut self, now: Instant) {
+        match self.state {
+            // The FIN goes out once the handshake completes
+            State::SynReceived => self.fin_queued = true,
+            State::Established => {
+                self.fin_queued = true;
+                self.set_state(State::FinWait1);
+            }
+            State::CloseWait => {
+                self.fin_queued = true;
+                self.set_state(State::LastAck);
+            }
+            _ => {}
+        }
+        self.output(now);
+    }
+
+    /// Abortive close, RFC 9293 section 3.10.5: everything queued is thrown
+    /// away and a RST tells the peer, if it knows about the connection.
+    pub fn abort(&mut self, now: Instant) {
+        self.closed_by_user = true;
+        if matches!(
+            self.state,
+            State::SynReceived
+                | State::Established
+                | State::FinWait1
+                | State::FinWait2
+                | State::CloseWait
+        ) {
+            self.send_reset(self.snd_nxt, now);
+        }
+        if self.state != State::Closed {
+            self.fail(ErrorKind::ConnectionAborted);
+        }
+    }
+
+    pub fn on_segment(&mut self, seg: Segment, now: Instant) {
+        if !self.is_authentic(&seg) {
+            println!("tcp {}: bad or missing signature, dropping", self.quad);
+            return;
+        }
+        match self.state {
+            State::Closed => {}
+            State::SynSent => self.on_segment_syn_sent(seg, now),
+            _ => self.on_segment_synchronized(seg, now),
+        }
+        self.output(now);
+    }
+
+    /// The earliest time `on_tick` has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [
+            self.rtx_deadline,
+            self.time_wait_deadline,
+            self.pacing_deadline,
+            self.delayed_ack_deadline,
+            self.cork_deadline,
+            self.keepalive_deadline(),
+            self.persist_deadline,
+            self.linger_deadline,
+            self.reorder_deadline,
+            self.tlp_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    pub fn on_tick(&mut self, now: Instant) {
+        if self.rtx_deadline.is_some_and(|t| t <= now) {
+            self.on_retransmit_timeout(now);
+        }
+        if self.reorder_deadline.is_some_and(|t| t <= now) {
+            self.reorder_deadline = None;
+            if self.uses_rack() && self.detect_losses(now) {
+                self.enter_recovery(now);
+            }
+            self.output(now);
+        }
+        if self.tlp_deadline.is_some_and(|t| t <= now) {
+            self.on_loss_probe_timeout(now);
+        }
+        if self.pacing_deadline.is_some_and(|t| t <= now) {
+            self.output(now);
+        }
+        if self.delayed_ack_deadline.is_some_and(|t| t <= now) {
+            self.send_ack(now);
+        }
+        if self.cork_deadline.is_some_and(|t| t <= now) {
+            self.output(now);
+            // Still blocked by the window, wait for it to open
+            if self.cork_deadline.is_some_and(|t| t <= now) {
+                self.cork_deadline = None;
+            }
+        }
+        if self.persist_deadline.is_some_and(|t| t <= now) {
+            self.on_persist_timeout(now);
+        }
+        if self.keepalive_deadline().is_some_and(|t| t <= now) {
+            self.on_keepalive_timeout(now);
+        }
+        if self.linger_deadline.is_some_and(|t| t <= now) {
+            self.linger_deadline = None;
+            if self.state != State::Closed && !self.fin_acked() {
+                println!("tcp {}: linger timed out", self.quad);
+                self.abort(now);
+            }
+        }
+        if self.state == State::TimeWait
+            && self.time_wait_deadline.is_some_and(|t| t <= now)
+        {
+            self.set_state(State::Closed);
+        }
+    }
+
+    // RFC 9293 section 3.10.7.3
+    fn on_segment_syn_sent(&mut self, seg: Segment, now: Instant) {
+        let has_ack = seg.flags.contains(TcpFlags::ACK);
+        let ack_acceptable = has_ack
+            && seq_gt(seg.ack, self.iss)
+            && seq_le(seg.ack, self.snd_nxt);
+
+        if has_ack && !ack_acceptable {
+            self.send_reset_for(&seg, now);
+            return;
+        }
+
+        if seg.flags.contains(TcpFlags::RST) {
+            if ack_acceptable {
+                self.fail(ErrorKind::ConnectionRefused);
+            }
+            return;
+        }
+
+        if !seg.flags.contains(TcpFlags::SYN) {
+            return;
+        }
+
+        self.irs = seg.seq;
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.rcv_sne = Sne::new(seg.seq);
+        self.negotiate(&seg, now);
+        if self.fast_open.take().is_some() {
+            self.received_cookie = seg
+                .fast_open()
+                .filter(|cookie| !cookie.is_empty())
+                .map(<[u8]>::to_vec);
+        }
+        if has_ack {
+            self.acknowledge(&seg, now);
+            if seq_lt(self.snd_una, self.snd_nxt) {
+                // The server did not take the data on our SYN, RFC 7413
+                // section 4.2.1: send it again right away
+                if let Some(front) = self.rtx_queue.front_mut() {
+                    front.lost = true;
+                }
+            }
+        }
+
+        if seq_gt(self.snd_una, self.iss) {
+            self.set_state(State::Established);
+            self.set_window(&seg);
+            self.send_ack(now);
+            self.receive(seg, now);
+        } else {
+            // Simultaneous open
+            self.set_state(State::SynReceived);
+            self.send_syn(now);
+        }
+    }
+
+    // RFC 9293 section 3.10.7.4
+    fn on_segment_synchronized(&mut self, seg: Segment, now: Instant) {
+        if self.is_old_duplicate(&seg, now) {
+            self.send_ack(now);
+            return;
+        }
+        if self.state == State::SynReceived
+            && seg.flags.contains(TcpFlags::SYN)
+            && !seg.flags.contains(TcpFlags::RST)
+            && seg.seq == self.irs
+        {
+            // Our SYN-ACK was lost. Data on the SYN that we did not take may
+            // well reach into the window, which makes it no less a
+            // retransmission.
+            self.send_syn(now);
+            return;
+        }
+        if !self.is_acceptable(&seg) {
+            if seg.flags.contains(TcpFlags::RST) {
+                return;
+            }
+            self.send_ack(now);
+            if self.state == State::TimeWait
+                && seg.flags.contains(TcpFlags::FIN)
+            {
+                self.enter_time_wait(now);
+            }
+            return;
+        }
+        self.update_ts_recent(&seg, now);
+        self.last_received = now;
+        self.keepalive_probes = 0;
+        self.unanswered_probes = 0;
+        // RFC 3168 section 6.1.3: echo CE marks until the peer has reduced
+        // its congestion window
+        if self.ecn && !seg.flags.contains(TcpFlags::SYN) {
+            if seg.flags.contains(TcpFlags::CWR) {
+                self.ece = false;
+            }
+            if seg.ecn() == Ecn::Ce {
+                self.ece = true;
+            }
+        }
+
+        if seg.flags.contains(TcpFlags::RST) {
+            // RFC 1337 section 3: a RST must not cut TIME-WAIT short, or
+            // old duplicates could reach a new incarnation
+            if self.state == State::TimeWait {
+                return;
+            }
+            // RFC 5961 section 3.2: only a RST right at `rcv_nxt` is taken
+            // at its word, anywhere else in the window it could be a blind
+            // guess.
+            if seg.seq != self.rcv_nxt {
+                self.send_challenge_ack(now);
+                return;
+            }
+            match self.state {
+                State::SynReceived if self.passive => {
+                    self.set_state(State::Closed)
+                }
+                State::SynReceived => self.fail(ErrorKind::ConnectionRefused),
+                State::Established
+                | State::FinWait1
+                | State::FinWait2
+                | State::CloseWait => self.fail(ErrorKind::ConnectionReset),
+                _ => self.set_state(State::Closed),
+            }
+            return;
+        }
+
+        if seg.flags.contains(TcpFlags::SYN) {
+            if self.state == State::SynReceived && self.passive {
+                self.set_state(State::Closed);
+            } else {
+                // RFC 5961 section 4.2: a peer that really restarted
+                // answers this with a RST
+                self.send_challenge_ack(now);
+            }
+            return;
+        }
+
+        if !seg.flags.contains(TcpFlags::ACK) {
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_nxt) {
+                self.set_window(&seg);
+                if self.fin_queued {
+                    self.set_state(State::FinWait1);
+                } else {
+                    self.set_state(State::Established);
+                }
+            } else {
+                self.send_reset_for(&seg, now);
+                return;
+            }
+        }
+
+        // RFC 5961 section 5.2: the ACK can't be for data not sent yet, nor
+        // older than any window the peer offered
+        let oldest = self.snd_una.wrapping_sub(self.max_snd_wnd);
+        if seq_gt(seg.ack, self.snd_nxt) || seq_lt(seg.ack, oldest) {
+            self.send_challenge_ack(now);
+            return;
+        }
+        if self.sack_permitted {
+            self.rtx_queue.sack(seg.sack_blocks(), &mut self.rack, now);
+        }
+        if seq_lt(self.snd_una, seg.ack) {
+            self.acknowledge(&seg, now);
+        } else if self.is_duplicate_ack(&seg) {
+            self.on_duplicate_ack(now);
+        }
+        if self.sack_permitted && self.detect_losses(now) {
+            self.enter_recovery(now);
+        }
+        if self.ecn && seg.flags.contains(TcpFlags::ECE) {
+            self.on_congestion_echo(now);
+        }
+        if seq_le(self.snd_una, seg.ack)
+            && (seq_lt(self.snd_wl1, seg.seq)
+                || (self.snd_wl1 == seg.seq && seq_le(self.snd_wl2, seg.ack)))
+        {
+            self.set_window(&seg);
+        }
+
+        match self.state {
+            State::FinWait1 if self.fin_acked() => {
+                self.set_state(State::FinWait2)
+            }
+            State::Closing => {
+                if !self.fin_acked() {
+                    return;
+                }
+                self.enter_time_wait(now);
+            }
+            State::LastAck => {
+                if self.fin_acked() {
+                    self.set_state(State::Closed);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        self.receive_urgent(&seg);
+        self.receive(seg, now);
+    }
+
+    /// RFC 2385 section 2.0 and RFC 5925 section 7.6: with a key, segments
+    /// without a valid signature are dropped, RSTs included. Without one,
+    /// signed segments are.
+    fn is_authentic(&mut self, seg: &Segment) -> bool {
+        let Some(key) = &self.auth else {
+            return !seg.is_signed();
+        };
+        let syn = seg.flags.contains(TcpFlags::SYN);
+        let isns = (
+            if syn { seg.seq } else { self.irs },
+            if syn && !seg.flags.contains(TcpFlags::ACK) {
+                0
+            } else {
+                self.iss
+            },
+        );
+        let sne = if syn { 0 } else { self.rcv_sne.get(seg.seq) };
+        if !key.verify(seg, isns, sne) {
+            return false;
+        }
+        self.rcv_sne.update(seg.seq);
+        true
+    }
+
+    /// The sequence number check, RFC 9293 section 3.10.7.4 "First".
+    fn is_acceptable(&self, seg: &Segment) -> bool {
+        let len = seg.len();
+        let wnd = self.rcv_window();
+        let in_window = |seq: u32| {
+            seq_le(self.rcv_nxt, seq)
+                && seq_lt(seq, self.rcv_nxt.wrapping_add(wnd))
+        };
+        match (len, wnd) {
+            (0, 0) => seg.seq == self.rcv_nxt,
+            (0, _) => in_window(seg.seq),
+            // Let ACKs and RSTs through a closed window, `receive` will not
+            // take any of the data.
+            (_, 0) => seg.seq == self.rcv_nxt,
+            _ => in_window(seg.seq) || in_window(seg.seq.wrapping_add(len - 1)),
+        }
+    }
+
+    /// RFC 5681 section 2: an ACK that acknowledges nothing new, carries
+    /// nothing and leaves the window unchanged while data is outstanding.
+    fn is_duplicate_ack(&self, seg: &Segment) -> bool {
+        seg.ack == self.snd_una
+            && seg.is_empty()
+            && (seg.window as u32) << self.snd_wscale == self.snd_wnd
+            && !self.rtx_queue.is_empty()
+    }
+
+    fn on_duplicate_ack(&mut self, now: Instant) {
+        self.dupacks += 1;
+        if let Some(cwnd) = &mut self.recovery_cwnd {
+            // Another segment has left the network. With SACK the
+            // scoreboard keeps track of that instead.
+            if !self.sack_permitted {
+                *cwnd += self.mss;
+            }
+            return;
+        }
+        if self.dupacks == DUPACK_THRESHOLD && !self.uses_rack() {
+            self.enter_recovery(now);
+        }
+    }
+
+    fn uses_rack(&self) -> bool {
+        self.rack_tlp && self.sack_permitted
+    }
+
+    /// Marks what the SACK scoreboard shows to be lost, by RACK or by the
+    /// duplicate ACK threshold of RFC 6675. Returns whether anything new
+    /// was marked.
+    fn detect_losses(&mut self, now: Instant) -> bool {
+        if !self.rack_tlp {
+            return self.rtx_queue.mark_sack_losses(DUPACK_THRESHOLD, self.mss);
+        }
+        let recovering = self.recovery_cwnd.is_some()
+            || self.rtx_queue.sacked_segments() >= DUPACK_THRESHOLD as usize;
+        let reo_wnd = self.rack.reo_wnd(recovering, self.rtt.srtt());
+        let (marked, timeout) =
+            self.rtx_queue.mark_rack_losses(&self.rack, reo_wnd, now);
+        self.reorder_deadline = timeout;
+        marked
+    }
+
+    /// Starts fast recovery by retransmitting the first unacknowledged
+    /// segment, at most once per window of data. Without SACK this is
+    /// RFC 6582 NewReno, with SACK the RFC 6675 scoreboard finds the rest of
+    /// what was lost.
+    fn enter_recovery(&mut self, now: Instant) {
+        if self.recovery_cwnd.is_some() || seq_lt(self.snd_una, self.recover) {
+            return;
+        }
+        let flight_size = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        self.cc.on_loss(flight_size, now);
+        self.recover = self.snd_nxt;
+        self.tlp_deadline = None;
+        self.tlp_end = None;
+        let inflation = if self.sack_permitted {
+            0
+        } else {
+            DUPACK_THRESHOLD as usize * self.mss
+        };
+        self.recovery_cwnd = Some(self.cc.cwnd() + inflation);
+        println!(
+            "tcp {}: fast retransmit of seq {}, cwnd {} ssthresh {}",
+            self.quad,
+            self.snd_una,
+            self.cc.cwnd(),
+            self.cc.ssthresh()
+        );
+        self.retransmit_front(now);
+    }
+
+    /// Reduces the congestion window for an ECE, RFC 3168 section 6.1.2:
+    /// like for a loss, but nothing needs to be retransmitted. At most once
+    /// per window of data, and not on top of fast recovery.
+    fn on_congestion_echo(&mut self, now: Instant) {
+        if self.cwr
+            || self.recovery_cwnd.is_some()
+            || seq_lt(self.snd_una, self.recover)
+            || seq_lt(self.snd_una, self.ecn_recover)
+        {
+            return;
+        }
+        let flight_size = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        self.cc.on_congestion_echo(flight_size, now);
+        self.ecn_recover = self.snd_nxt;
+        self.cwr = true;
+        println!(
+            "tcp {}: congestion experienced, cwnd {} ssthresh {}",
+            self.quad,
+            self.cc.cwnd(),
+            self.cc.ssthresh()
+        );
+    }
+
+    fn congestion_window(&self) -> usize {
+        self.recovery_cwnd.unwrap_or_else(|| self.cc.cwnd())
+    }
+
+    /// Notes the urgent pointer, RFC 9293 section 3.10.7.4 "Sixth". Only
+    /// one that moves past what was seen before counts.
+    fn receive_urgent(&mut self, seg: &Segment) {
+        if !seg.flags.contains(TcpFlags::URG)
+            || seg.urgent == 0
+            || !matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            return;
+        }
+        let up = seg.seq.wrapping_add(seg.urgent as u32);
+        if seq_gt(up, self.rcv_nxt)
+            && self.rcv_up.is_none_or(|rcv_up| seq_gt(up, rcv_up))
+        {
+            self.rcv_up = Some(up);
+        }
+    }
+
+    /// Appends in-order data at `rcv_nxt` to the receive buffer. The urgent
+    /// byte is taken out of the stream, leaving a mark in its place.
+    fn deliver(&mut self, data: &[u8]) {
+        let end = self.rcv_nxt.wrapping_add(data.len() as u32);
+        match self.rcv_up {
+            Some(up) if seq_gt(up, self.rcv_nxt) && seq_le(up, end) => {
+                let i = up.wrapping_sub(self.rcv_nxt) as usize - 1;
+                self.recv_buffer.extend(&data[..i]);
+                self.urgent_mark = Some(self.recv_buffer.len());
+                self.oob = Some(data[i]);
+                self.recv_buffer.extend(&data[i + 1..]);
+                self.rcv_up = None;
+            }
+            _ => self.recv_buffer.extend(data),
+        }
+        self.rcv_nxt = end;
+    }
+
+    /// Segment text and FIN processing, RFC 9293 section 3.10.7.4 "Seventh"
+    /// and "Eighth".
+    fn receive(&mut self, seg: Segment, now: Instant) {
+        let mut seq = seg
+            .seq
+            .wrapping_add(seg.flags.contains(TcpFlags::SYN) as u32);
+        // The part of the segment's data that is new and fits the window
+        let mut start = 0;
+        let mut end = seg.data().len();
+        let mut fin = seg.flags.contains(TcpFlags::FIN);
+        let needs_ack = end > 0 || fin;
+
+        if seq_lt(seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if skip > end {
+                return;
+            }
+            start = skip;
+            seq = self.rcv_nxt;
+        }
+        let filled_hole = !self.reassembly.is_empty();
+        let accepts_data = matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        );
+        let offset = seq.wrapping_sub(self.rcv_nxt) as usize;
+        let window = (self.rcv_window() as usize).saturating_sub(offset);
+        if end - start > window {
+            end = start + window;
+            fin = false;
+        }
+
+        if seq != self.rcv_nxt {
+            // Out of order: hold on to it and let the peer know where we are
+            if accepts_data {
+                if fin {
+                    let fin_seq = seq.wrapping_add((end - start) as u32);
+                    self.reassembly.insert_fin(fin_seq);
+                }
+                self.reassembly.insert(seq, seg.packet, start..end);
+            }
+            if start < end || fin {
+                self.send_ack(now);
+            }
+            return;
+        }
+
+        if start < end {
+            if !accepts_data {
+                return;
+            }
+            if self.closed_by_user {
+                println!("tcp {}: data after close, resetting", self.quad);
+                self.abort(now);
+                return;
+            }
+            self.deliver(&seg.data()[start..end]);
+            while let Some(fragment) = self.reassembly.pop(self.rcv_nxt) {
+                self.deliver(fragment.data());
+            }
+            fin |= self.reassembly.take_fin(self.rcv_nxt);
+            if self.read_shutdown {
+                self.recv_buffer.clear();
+                self.urgent_mark = None;
+            }
+        }
+
+        if fin {
+            self.receive_fin(now);
+        }
+        if !needs_ack {
+            return;
+        }
+        // RFC 5681 section 4.2: duplicates and segments that fill a hole
+        // are acknowledged immediately.
+        if fin || filled_hole || start == end {
+            self.send_ack(now);
+        } else {
+            self.delay_ack(end - start, now);
+        }
+    }
+
+    /// RFC 1122 section 4.2.3.2: acknowledges at least every second
+    /// full-sized segment, anything less waits for the delayed ACK timer.
+    fn delay_ack(&mut self, len: usize, now: Instant) {
+        self.rcv_mss = self.rcv_mss.max(len);
+        self.unacked_bytes += len;
+        if self.quick_acks > 0 {
+            self.quick_acks -= 1;
+            self.send_ack(now);
+        } else if self.unacked_bytes >= 2 * self.rcv_mss {
+            self.send_ack(now);
+        } else if self.delayed_ack_deadline.is_none() {
+            self.delayed_ack_deadline = Some(now + DELAYED_ACK_TIMEOUT);
+        }
+    }
+
+    fn receive_fin(&mut self, now: Instant) {
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        self.fin_received = true;
+        match self.state {
+            State::SynReceived | State::Established => {
+                self.set_state(State::CloseWait)
+            }
+            State::FinWait1 if self.fin_acked() => self.enter_time_wait(now),
+            State::FinWait1 => self.set_state(State::Closing),
+            State::FinWait2 | State::TimeWait => self.enter_time_wait(now),
+            _ => {}
+        }
+    }
+
+    /// Sends whatever queued data and FIN the peer's window and the
+    /// congestion window allow, after retransmitting what was lost.
+    fn output(&mut self, now: Instant) {
+        if !matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::LastAck
+        ) {
+            return;
+        }
+        self.pacing_deadline = None;
+
+        while let Some(len) = self.rtx_queue.first_lost_mut().map(|s| s.len) {
+            let in_flight = self.rtx_queue.in_flight();
+            if in_flight > 0
+                && in_flight + len as usize > self.congestion_window()
+            {
+                return;
+            }
+            if self.pacing_wait(now) {
+                return;
+            }
+            self.retransmit_lost(now);
+            self.paced(len as usize, now);
+        }
+
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.send_buffer_seq) as usize;
+            let unsent = self.send_buffer.len().saturating_sub(sent);
+            let window_end = self.snd_una.wrapping_add(self.snd_wnd);
+            let usable = if seq_lt(self.snd_nxt, window_end) {
+                window_end.wrapping_sub(self.snd_nxt) as usize
+            } else {
+                0
+            };
+            let usable = usable.min(
+                self.congestion_window()
+                    .saturating_sub(self.rtx_queue.in_flight()),
+            );
+
+            if unsent > 0 && usable > 0 {
+                let max_payload = self.max_payload();
+                if unsent < max_payload && self.holds_partial(now) {
+                    return;
+                }
+                if self.pacing_wait(now) {
+                    return;
+                }
+                if let Some(len) = self.send_mtu_probe(unsent.min(usable), now)
+                {
+                    self.paced(len, now);
+                    continue;
+                }
+                let len = unsent.min(usable).min(max_payload);
+                if len < max_payload {
+                    self.cork_deadline = None;
+                }
+                self.send_new_data(len, now);
+                self.paced(len, now);
+                continue;
+            }
+
+            let in_flight = self.rtx_queue.in_flight();
+            if unsent == 0 && in_flight < self.congestion_window() {
+                self.delivery.set_app_limited(in_flight);
+            }
+            if unsent > 0
+                && self.snd_wnd == 0
+                && self.rtx_queue.is_empty()
+                && self.persist_deadline.is_none()
+            {
+                self.persist_deadline = Some(now + self.persist_interval());
+            }
+
+            if unsent == 0 && self.fin_queued && self.fin_seq.is_none() {
+                let flags = TcpFlags::FIN | TcpFlags::ACK;
+                self.transmit(self.snd_nxt, flags, &[], now);
+                self.fin_seq = Some(self.snd_nxt);
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            }
+            break;
+        }
+    }
+
+    /// Sends the next `len` bytes of queued data.
+    fn send_new_data(&mut self, len: usize, now: Instant) {
+        let sent = self.snd_nxt.wrapping_sub(self.send_buffer_seq) as usize;
+        let mut flags = TcpFlags::ACK;
+        if sent + len == self.send_buffer.len() {
+            flags |= TcpFlags::PSH;
+        }
+        let data: Vec<u8> =
+            self.send_buffer.range(sent..sent + len).copied().collect();
+        self.transmit(self.snd_nxt, flags, &data, now);
+        self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+    }
+
+    /// Sends new data in a segment larger than the MSS, to see if the path
+    /// takes it, RFC 4821 section 7.4, if a probe is due and `available`
+    /// bytes fill it. Not during recovery, where losses are expected
+    /// anyway. Returns how much was sent.
+    fn send_mtu_probe(
+        &mut self,
+        available: usize,
+        now: Instant,
+    ) -> Option<usize> {
+        if self.state != State::Established
+            || self.recovery_cwnd.is_some()
+            || seq_lt(self.snd_una, self.recover)
+        {
+            return None;
+        }
+        let size = self.path_mtu.probe_size(now)?;
+        let len = size - HEADERS_LEN - self.options_len();
+        if len > available {
+            return None;
+        }
+        println!("tcp {}: probing path MTU {}", self.quad, size);
+        self.send_new_data(len, now);
+        self.path_mtu.on_probe_sent(size, self.snd_nxt);
+        Some(len)
+    }
+
+    /// Whether the last of the queued data, less than a full segment,
+    /// should wait for more. Nagle's algorithm, RFC 1122 section 4.2.3.4,
+    /// holds it while earlier data is unacknowledged, corking until the
+    /// cork is removed or times out. Neither delays a FIN or urgent data.
+    fn holds_partial(&mut self, now: Instant) -> bool {
+        if self.fin_queued || self.snd_up.is_some() {
+            return false;
+        }
+        if self.cork {
+            let deadline =
+                *self.cork_deadline.get_or_insert(now + CORK_TIMEOUT);
+            return deadline > now;
+        }
+        !self.nodelay && self.snd_una != self.snd_nxt
+    }
+
+    /// Whether pacing holds back the next segment, in which case `on_tick`
+    /// tries again when it is due. Paced segments go out with everything
+    /// else through `outgoing`.
+    fn pacing_wait(&mut self, now: Instant) -> bool {
+        match self.next_send_at {
+            Some(at) if at > now + PACING_GRANULARITY => {
+                self.pacing_deadline = Some(at);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Spaces the next segment after one of `len` bytes was sent.
+    fn paced(&mut self, len: usize, now: Instant) {
+        self.next_send_at = self.cc.pacing_rate().map(|rate| {
+            let start = self.next_send_at.map_or(now, |at| at.max(now));
+            start + Duration::from_secs_f64(len as f64 / rate)
+        });
+    }
+
+    /// Sends a segment that occupies sequence space and queues it for
+    /// retransmission.
+    fn transmit(
+        &mut self,
+        seq: u32,
+        flags: TcpFlags,
+        data: &[u8],
+        now: Instant,
+    ) {
+        let mut header = self.header(seq, flags);
+        if self.cwr && !data.is_empty() && !flags.contains(TcpFlags::SYN) {
+            header.flags |= TcpFlags::CWR;
+            self.cwr = false;
+        }
+        self.emit(header, data, now);
+
+        let control = flags & (TcpFlags::SYN | TcpFlags::FIN);
+        let len = data.len() as u32
+            + control.contains(TcpFlags::SYN) as u32
+            + control.contains(TcpFlags::FIN) as u32;
+        let delivery = self.delivery.on_send(self.rtx_queue.in_flight(), now);
+        self.rtx_queue.push(TxSegment {
+            seq,
+            len,
+            flags: control,
+            sent_at: now,
+            retransmitted: false,
+            lost: false,
+            sacked: false,
+            delivery,
+        });
+        if self.rtx_deadline.is_none() {
+            self.rtx_deadline = Some(now + self.rtt.rto());
+        }
+        self.schedule_loss_probe(now);
+    }
+
+    /// Arms the tail loss probe, RFC 8985 section 7.2, whenever new data is
+    /// sent or acknowledged: should the ACKs stop before the RTO, a probe
+    /// gets the peer to SACK what is missing at the tail.
+    fn schedule_loss_probe(&mut self, now: Instant) {
+        self.tlp_deadline = None;
+        if !self.uses_rack()
+            || !self.state.is_synchronized()
+            || self.recovery_cwnd.is_some()
+            || self.tlp_end.is_some()
+            || self.rtx_queue.is_empty()
+        {
+            return;
+        }
+        let pto = match self.rtt.srtt() {
+            Some(srtt) if self.rtx_queue.in_flight() <= self.mss => {
+                2 * srtt + TLP_DELAYED_ACK
+            }
+            Some(srtt) => 2 * srtt,
+            None => INITIAL_PTO,
+        };
+        let deadline = now + pto.max(MIN_PTO);
+        if self.rtx_deadline.is_none_or(|rto| deadline < rto) {
+            self.tlp_deadline = Some(deadline);
+        }
+    }
+
+    /// Sends the tail loss probe, RFC 8985 section 7.3: new data if the
+    /// peer's window has room for it, the last segment again otherwise.
+    fn on_loss_probe_timeout(&mut self, now: Instant) {
+        self.tlp_deadline = None;
+        if self.rtx_queue.is_empty() {
+            return;
+        }
+        let sent = self.snd_nxt.wrapping_sub(self.send_buffer_seq) as usize;
+        let unsent = self.send_buffer.len().saturating_sub(sent);
+        let window_end = self.snd_una.wrapping_add(self.snd_wnd);
+        let usable = if seq_lt(self.snd_nxt, window_end) {
+            window_end.wrapping_sub(self.snd_nxt) as usize
+        } else {
+            0
+        };
+        if unsent > 0 && usable > 0 {
+            self.send_new_data(unsent.min(usable).min(self.max_payload()), now);
+            self.tlp_retransmitted = false;
+        } else {
+            let in_flight = self.rtx_queue.in_flight();
+            let segment = self.rtx_queue.back_mut().unwrap();
+            if segment.sacked {
+                return;
+            }
+            segment.lost = false;
+            segment.retransmitted = true;
+            segment.sent_at = now;
+            segment.delivery = self.delivery.on_send(in_flight, now);
+            let segment = segment.clone();
+            self.retransmit(&segment, now);
+            self.tlp_retransmitted = true;
+        }
+        println!("tcp {}: tail loss probe", self.quad);
+        self.tlp_end = Some(self.snd_nxt);
+        self.tlp_deadline = None;
+        self.rtx_deadline = Some(now + self.rtt.rto());
+    }
+
+    /// When the next keepalive probe is due. Nothing is probed while there
+    /// is data to send, the retransmission timer watches over that.
+    fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        let idle = matches!(self.state, State::Established | State::CloseWait)
+            && self.rtx_queue.is_empty()
+            && self.send_buffer.is_empty();
+        idle.then(|| {
+            self.last_received
+                + keepalive.idle
+                + keepalive.interval * self.keepalive_probes
+        })
+    }
+
+    /// RFC 1122 section 4.2.3.6: a probe carries an old sequence number, so
+    /// that the peer answers with an ACK.
+    fn on_keepalive_timeout(&mut self, now: Instant) {
+        let keepalive = self.keepalive.unwrap();
+        if self.keepalive_probes >= keepalive.count {
+            self.fail(ErrorKind::TimedOut);
+            return;
+        }
+        self.keepalive_probes += 1;
+        println!(
+            "tcp {}: keepalive probe {} of {}",
+            self.quad, self.keepalive_probes, keepalive.count
+        );
+        let header = self.header(self.snd_una.wrapping_sub(1), TcpFlags::ACK);
+        self.emit(header, &[], now);
+    }
+
+    /// Backs off exponentially from the RTO.
+    fn persist_interval(&self) -> Duration {
+        let backoff = 1 << self.persist_probes.min(16);
+        (self.rtt.rto() * backoff).min(MAX_PERSIST_INTERVAL)
+    }
+
+    /// Probes the closed window with a segment the peer has to answer, like
+    /// a keepalive. The peer may keep its window closed for as long as it
+    /// likes, RFC 1122 section 4.2.2.17, but not stop answering.
+    fn on_persist_timeout(&mut self, now: Instant) {
+        if self.snd_wnd > 0 || !self.rtx_queue.is_empty() {
+            self.persist_deadline = None;
+            return;
+        }
+        if self.unanswered_probes >= MAX_RETRANSMITS {
+            self.fail(ErrorKind::TimedOut);
+            return;
+        }
+        self.unanswered_probes += 1;
+        self.persist_probes += 1;
+        let header = self.header(self.snd_una.wrapping_sub(1), TcpFlags::ACK);
+        self.emit(header, &[], now);
+        self.persist_deadline = Some(now + self.persist_interval());
+    }
+
+    /// Resends the oldest unacknowledged segment, RFC 6298 section 5.
+    /// Everything else outstanding is presumed lost as well and goes out
+    /// again as the congestion window reopens.
+    fn on_retransmit_timeout(&mut self, now: Instant) {
+        let max_payload = self.max_payload();
+        let Some(front) = self.rtx_queue.front_mut() else {
+            self.rtx_deadline = None;
+            return;
+        };
+        let syn = front.flags.contains(TcpFlags::SYN);
+        let full_sized = front.data_len() >= max_payload;
+        let limit = if syn {
+            MAX_SYN_RETRANSMITS
+        } else {
+            MAX_RETRANSMITS
+        };
+        if self.retransmits >= limit {
+            self.fail(ErrorKind::TimedOut);
+            return;
+        }
+
+        let seq = front.seq;
+        if !syn {
+            self.cc.on_timeout(self.rtx_queue.in_flight(), now);
+        } else if self.state == State::SynSent {
+            // RFC 3168 section 6.1.1.1: a middlebox may be dropping
+            // ECN-setup SYNs, try again without
+            self.ecn = false;
+        }
+        self.recover = self.snd_nxt;
+        self.recovery_cwnd = None;
+        self.dupacks = 0;
+        self.reorder_deadline = None;
+        self.tlp_deadline = None;
+        self.tlp_end = None;
+        self.rtx_queue.mark_all_lost();
+        self.retransmits += 1;
+        self.rtt.backoff();
+        // RFC 4821 section 7.5: full-sized segments that keep timing out
+        // may be too large for the path, with the ICMP lost or filtered
+        if full_sized
+            && self.retransmits == BLACK_HOLE_RETRANSMITS
+            && self.path_mtu.on_black_hole()
+        {
+            self.update_mss();
+            println!(
+                "tcp {}: possible black hole, path MTU {}",
+                self.quad,
+                self.path_mtu.mtu()
+            );
+        }
+        println!(
+            "tcp {}: retransmitting seq {} (attempt {}, next in {:?})",
+            self.quad,
+            seq,
+            self.retransmits,
+            self.rtt.rto()
+        );
+        self.retransmit_lost(now);
+        self.rtx_deadline = Some(now + self.rtt.rto());
+    }
+
+    fn retransmit_front(&mut self, now: Instant) {
+        if let Some(front) = self.rtx_queue.front_mut() {
+            front.lost = true;
+        }
+        self.retransmit_lost(now);
+    }
+
+    /// Resends the oldest segment marked lost, if there is one, cut down
+    /// to the current MSS.
+    fn retransmit_lost(&mut self, now: Instant) {
+        let in_flight = self.rtx_queue.in_flight();
+        let Some(end) = self.rtx_queue.first_lost_mut().map(|s| s.end()) else {
+            return;
+        };
+        if self.path_mtu.probe_end() == Some(end) {
+            println!("tcp {}: path MTU probe lost", self.quad);
+            self.path_mtu.on_probe_lost();
+        }
+        self.rtx_queue.split_first_lost(self.max_payload());
+        let segment = self.rtx_queue.first_lost_mut().unwrap();
+        segment.lost = false;
+        segment.retransmitted = true;
+        segment.sent_at = now;
+        segment.delivery = self.delivery.on_send(in_flight, now);
+        let segment = segment.clone();
+        self.retransmit(&segment, now);
+    }
+
+    fn retransmit(&mut self, segment: &TxSegment, now: Instant) {
+        if segment.flags.contains(TcpFlags::SYN) {
+            self.send_syn(now);
+            return;
+        }
+        let offset =
+            segment.data_seq().wrapping_sub(self.send_buffer_seq) as usize;
+        let data: Vec<u8> = self
+            .send_buffer
+            .range(offset..offset + segment.data_len())
+            .copied()
+            .collect();
+        let header = self.header(segment.seq, segment.flags | TcpFlags::ACK);
+        self.emit(header, &data, now);
+    }
+
+    fn acknowledge(&mut self, seg: &Segment, now: Instant) {
+        let ack = seg.ack;
+        let flight_size = self.rtx_queue.in_flight();
+        let acked_bytes = ack.wrapping_sub(self.snd_una) as usize;
+        let syn_acked = self.snd_una == self.iss;
+        let acked = self.rtx_queue.acknowledge(ack, &mut self.rack, now);
+        if acked.syn_retransmitted {
+            self.rtt.syn_timed_out();
+        }
+        let rtt = self.timestamp_rtt(seg, now).or_else(|| {
+            acked
+                .rtt_sent_at
+                .map(|sent_at| now.saturating_duration_since(sent_at))
+        });
+        if let Some(rtt) = rtt {
+            self.rtt.sample(rtt);
+        }
+        self.retransmits = 0;
+        self.rtx_deadline = if self.rtx_queue.is_empty() {
+            None
+        } else {
+            Some(now + self.rtt.rto())
+        };
+
+        if seq_gt(ack, self.send_buffer_seq) {
+            let acked = (ack.wrapping_sub(self.send_buffer_seq) as usize)
+                .min(self.send_buffer.len());
+            self.send_buffer.drain(..acked);
+            self.send_buffer_seq =
+                self.send_buffer_seq.wrapping_add(acked as u32);
+        }
+        self.snd_una = ack;
+        if self.path_mtu.on_ack(ack) {
+            self.update_mss();
+            println!("tcp {}: path MTU {}", self.quad, self.path_mtu.mtu());
+        }
+        if self.snd_up.is_some_and(|up| seq_ge(ack, up)) {
+            self.snd_up = None;
+        }
+
+        let in_flight = self.rtx_queue.in_flight();
+        let rate =
+            self.delivery
+                .on_ack(acked_bytes, acked.newest, in_flight, now);
+        if let Some(rate) = rate.filter(|_| !syn_acked) {
+            self.cc.on_rate_sample(&rate);
+        }
+
+        self.dupacks = 0;
+        match self.recovery_cwnd {
+            Some(_) if seq_ge(ack, self.recover) => {
+                self.recovery_cwnd = None;
+                self.cc.on_recovery_end(self.rtx_queue.in_flight(), now);
+            }
+            Some(cwnd) => {
+                // A partial ACK, RFC 6582 section 3.2 step 5: the next
+                // segment was lost too.
+                if !self.sack_permitted {
+                    let mut cwnd = cwnd.saturating_sub(acked_bytes);
+                    if acked_bytes >= self.mss {
+                        cwnd += self.mss;
+                    }
+                    self.recovery_cwnd = Some(cwnd);
+                    self.retransmit_front(now);
+                } else if self
+                    .rtx_queue
+                    .front_mut()
+                    .is_some_and(|front| !front.retransmitted)
+                {
+                    self.retransmit_front(now);
+                }
+            }
+            None if syn_acked => {}
+            None => self.cc.on_ack(&Ack {
+                acked: acked_bytes,
+                flight_size,
+                srtt: self.rtt.srtt(),
+                now,
+            }),
+        }
+
+        // RFC 8985 section 7.4: without DSACK there is no telling whether
+        // the original arrived too, so a retransmitted probe is taken to
+        // have repaired a loss
+        if self.tlp_end.is_some_and(|end| seq_ge(ack, end)) {
+            self.tlp_end = None;
+            if self.tlp_retransmitted && self.recovery_cwnd.is_none() {
+                self.cc.on_loss(flight_size, now);
+                self.cc.on_recovery_end(in_flight, now);
+            }
+        }
+        self.schedule_loss_probe(now);
+    }
+
+    /// The window field of a SYN is never scaled, RFC 7323 section 2.2.
+    fn set_window(&mut self, seg: &Segment) {
+        let shift = if seg.flags.contains(TcpFlags::SYN) {
+            0
+        } else {
+            self.snd_wscale
+        };
+        self.snd_wnd = (seg.window as u32) << shift;
+        self.max_snd_wnd = self.max_snd_wnd.max(self.snd_wnd);
+        if self.snd_wnd > 0 {
+            self.persist_deadline = None;
+            self.persist_probes = 0;
+        }
+        self.snd_wl1 = seg.seq;
+        self.snd_wl2 = seg.ack;
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_seq.is_some_and(|fin| seq_gt(self.snd_una, fin))
+    }
+
+    fn rcv_window(&self) -> u32 {
+        (RECV_BUFFER_SIZE - self.recv_buffer.len())
+            .min((u16::MAX as usize) << self.rcv_wscale) as u32
+    }
+
+    /// Receiver silly window syndrome avoidance, RFC 9293 section
+    /// 3.8.6.2.2: the right edge of the window only moves once it can move
+    /// by a full segment or half the buffer, so the peer is not invited to
+    /// send tiny segments.
+    fn advertised_window(&self) -> u32 {
+        let available = self.rcv_window();
+        let current = self.rcv_adv.wrapping_sub(self.rcv_nxt).min(available);
+        let threshold = (RECV_BUFFER_SIZE / 2).min(self.rcv_mss) as u32;
+        if available - current >= threshold {
+            available
+        } else {
+            current
+        }
+    }
+
+    /// Our timestamp clock, RFC 7323 section 5.4.
+    fn ts_val(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.ts_base);
+        self.ts_offset.wrapping_add(elapsed.as_millis() as u32)
+    }
+
+    /// PAWS, RFC 7323 section 5.3: a segment carrying an older timestamp
+    /// than one the peer already sent is an old duplicate, even if its
+    /// sequence number looks fine after wrapping around.
+    fn is_old_duplicate(&mut self, seg: &Segment, now: Instant) -> bool {
+        if !self.timestamps || seg.flags.contains(TcpFlags::RST) {
+            return false;
+        }
+        let Some((value, _)) = seg.timestamps() else {
+            return false;
+        };
+        if !seq_lt(value, self.ts_recent) {
+            return false;
+        }
+        if now.saturating_duration_since(self.ts_recent_age) > PAWS_IDLE {
+            self.ts_recent = value;
+            self.ts_recent_age = now;
+            return false;
+        }
+        true
+    }
+
+    /// Remembers the timestamp to echo, RFC 7323 section 4.3: the one of
+    /// the oldest segment that we have not acknowledged yet.
+    fn update_ts_recent(&mut self, seg: &Segment, now: Instant) {
+        let Some((value, _)) = seg.timestamps() else {
+            return;
+        };
+        if self.timestamps
+            && seq_ge(value, self.ts_recent)
+            && seq_le(seg.seq, self.last_ack_sent)
+        {
+            self.ts_recent = value;
+            self.ts_recent_age = now;
+        }
+    }
+
+    /// RTTM, RFC 7323 section 4: the echoed timestamp dates the segment
+    /// being acknowledged, retransmitted or not.
+    fn timestamp_rtt(&self, seg: &Segment, now: Instant) -> Option<Duration> {
+        if !self.timestamps {
+            return None;
+        }
+        let (_, echo) = seg.timestamps()?;
+        let elapsed = self.ts_val(now).wrapping_sub(echo);
+        (elapsed as i32 >= 0).then(|| Duration::from_millis(elapsed as u64))
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.time_wait_deadline = Some(now + 2 * MSL);
+        if self.state != State::TimeWait {
+            self.set_state(State::TimeWait);
+        }
+    }
+
+    fn fail(&mut self, error: ErrorKind) {
+        println!("tcp {}: {}", self.quad, Error::from(error));
+        self.error = Some(error);
+        self.send_buffer.clear();
+        self.recv_buffer.clear();
+        self.urgent_mark = None;
+        self.reassembly.clear();
+        self.set_state(State::Closed);
+    }
+
+    fn set_state(&mut self, state: State) {
+        println!("tcp {}: {:?} -> {:?}", self.quad, self.state, state);
+        self.state = state;
+        if state == State::Closed {
+            self.rtx_queue.clear();
+            self.rtx_deadline = None;
+            self.linger_deadline = None;
+            self.reorder_deadline = None;
+            self.tlp_deadline = None;
+            self.time_wait_deadline = None;
+        }
+    }
+
+    fn header(&self, seq: u32, flags: TcpFlags) -> TcpHeader {
+        let mut header =
+            TcpHeader::new(self.quad.local_port, self.quad.remote_port);
+        header.seq = seq;
+        header.flags = flags;
+        if flags.contains(TcpFlags::ACK) {
+            header.ack = self.rcv_nxt;
+            if self.ece && !flags.contains(TcpFlags::SYN) {
+                header.flags |= TcpFlags::ECE;
+            }
+        }
+        let urgent = flags.contains(TcpFlags::ACK)
+            && !flags.contains(TcpFlags::SYN)
+            && !flags.contains(TcpFlags::RST);
+        if let Some(up) = self.snd_up.filter(|&up| urgent && seq_lt(seq, up)) {
+            header.flags |= TcpFlags::URG;
+            header.urgent = up.wrapping_sub(seq).min(u16::MAX as u32) as u16;
+        }
+        let shift = if flags.contains(TcpFlags::SYN) {
+            0
+        } else {
+            self.rcv_wscale
+        };
+        header.window =
+            (self.advertised_window() >> shift).min(u16::MAX as u32) as u16;
+        header
+    }
+
+    /// Settles the options of the handshake once the peer's SYN arrives:
+    /// only what both sides offered is used.
+    fn negotiate(&mut self, syn: &Segment, now: Instant) {
+        self.sack_permitted &= syn.sack_permitted();
+        self.ecn &= syn.is_ecn_setup();
+        self.set_peer_mss(syn);
+        match syn.window_scale() {
+            Some(shift) if self.window_scaling => {
+                self.snd_wscale = shift.min(MAX_WSCALE);
+                self.rcv_wscale = RCV_WSCALE;
+            }
+            _ => self.window_scaling = false,
+        }
+        match syn.timestamps() {
+            Some((value, _)) if self.timestamps => {
+                self.ts_recent = value;
+                self.ts_recent_age = now;
+            }
+            _ => self.timestamps = false,
+        }
+    }
+
+    /// Takes the MSS option of the peer's SYN into account, RFC 9293
+    /// section 3.7.1.
+    fn set_peer_mss(&mut self, syn: &Segment) {
+        let mss = syn.mss().map_or(DEFAULT_MSS, |mss| mss as usize);
+        self.path_mtu.set_max(mss.max(MIN_MSS) + HEADERS_LEN);
+        self.update_mss();
+        self.cc = self.congestion_algorithm.build(self.mss);
+    }
+
+    /// Sizes segments for the path MTU, which the peer's MSS caps. The
+    /// congestion controller keeps the MSS it was built with.
+    fn update_mss(&mut self) {
+        self.mss = self.path_mtu.mtu().saturating_sub(HEADERS_LEN).max(MIN_MSS);
+    }
+
+    /// The MSS counts neither IP nor TCP options, so they come out of the
+    /// data, RFC 6691.
+    fn max_payload(&self) -> usize {
+        self.mss - self.options_len()
+    }
+
+    fn options_len(&self) -> usize {
+        TcpOption::encoded_len(&self.options(TcpFlags::ACK, 0))
+    }
+
+    fn options(&self, flags: TcpFlags, ts_val: u32) -> Vec<TcpOption> {
+        let mut options: Vec<TcpOption> =
+            self.auth.iter().map(AuthKey::option).collect();
+        if flags.contains(TcpFlags::RST) {
+            return options;
+        }
+        let timestamps = TcpOption::Timestamps {
+            value: ts_val,
+            echo: if flags.contains(TcpFlags::ACK) {
+                self.ts_recent
+            } else {
+                0
+            },
+        };
+        if flags.contains(TcpFlags::SYN) {
+            let mss = self.local_mss.min(u16::MAX as usize);
+            options.push(TcpOption::Mss(mss as u16));
+            if self.sack_permitted {
+                options.push(TcpOption::SackPermitted);
+            }
+            if self.timestamps {
+                options.push(timestamps);
+            }
+            if self.window_scaling {
+                options.push(TcpOption::WindowScale(RCV_WSCALE));
+            }
+            if let Some(cookie) = &self.fast_open {
+                options.push(TcpOption::FastOpen(cookie.clone()));
+                if TcpOption::encoded_len(&options) > MAX_OPTIONS_LEN {
+                    // Like Linux, signatures take precedence
+                    options.pop();
+                }
+            }
+            return options;
+        }
+        if self.timestamps {
+            options.push(timestamps);
+        }
+        if flags.contains(TcpFlags::ACK)
+            && self.sack_permitted
+            && !self.reassembly.is_empty()
+        {
+            // As many as fit next to the other options
+            let max =
+                (MAX_OPTIONS_LEN - TcpOption::encoded_len(&options) - 2) / 8;
+            let blocks = self.reassembly.sack_blocks(max);
+            options.push(TcpOption::Sack(blocks));
+        }
+        options
+    }
+
+    fn emit(&mut self, header: TcpHeader, data: &[u8], now: Instant) {
+        if header.flags.contains(TcpFlags::ACK) {
+            let shift = if header.flags.contains(TcpFlags::SYN) {
+                0
+            } else {
+                self.rcv_wscale
+            };
+            self.rcv_adv =
+                header.ack.wrapping_add((header.window as u32) << shift);
+            self.last_ack_sent = header.ack;
+            self.unacked_bytes = 0;
+            self.delayed_ack_deadline = None;
+        }
+        let options = self.options(header.flags, self.ts_val(now));
+        // RFC 3168 sections 6.1.4 and 6.1.5: only new data is ECN-capable,
+        // not pure ACKs, SYNs or retransmissions
+        let ecn = if self.ecn
+            && !data.is_empty()
+            && !header.flags.contains(TcpFlags::SYN)
+            && header.seq == self.snd_nxt
+        {
+            Ecn::Ect0
+        } else {
+            Ecn::NotEct
+        };
+        let mut packet = make_packet(self.quad, header, &options, data, ecn);
+        if let Some(key) = &self.auth {
+            let syn = header.flags.contains(TcpFlags::SYN);
+            let isns = (
+                self.iss,
+                if syn && !header.flags.contains(TcpFlags::ACK) {
+                    0
+                } else {
+                    self.irs
+                },
+            );
+            let sne = if syn { 0 } else { self.snd_sne.get(header.seq) };
+            self.snd_sne.update(header.seq);
+            key.sign(&mut packet, self.quad, isns, sne);
+        }
+        self.outgoing.push_back(packet);
+    }
+
+    /// An ECN-setup SYN carries ECE and CWR, the SYN-ACK agreeing to it
+    /// only ECE, RFC 3168 section 6.1.1.
+    fn syn_flags(&self) -> TcpFlags {
+        match (self.state == State::SynReceived, self.ecn) {
+            (true, true) => TcpFlags::SYN | TcpFlags::ACK | TcpFlags::ECE,
+            (true, false) => TcpFlags::SYN | TcpFlags::ACK,
+            (false, true) => TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR,
+            (false, false) => TcpFlags::SYN,
+        }
+    }
+
+    /// Our SYN with as much queued data as fits next to its options.
+    fn send_fast_open_syn(&mut self, now: Instant) {
+        let options = self.options(TcpFlags::SYN, 0);
+        let len = self
+            .send_buffer
+            .len()
+            .min(self.mss - TcpOption::encoded_len(&options));
+        let data: Vec<u8> = self.send_buffer.range(..len).copied().collect();
+        self.transmit(self.iss, self.syn_flags(), &data, now);
+        self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+    }
+
+    fn send_syn(&mut self, now: Instant) {
+        let header = self.header(self.iss, self.syn_flags());
+        self.emit(header, &[], now);
+    }
+
+    fn send_ack(&mut self, now: Instant) {
+        let header = self.header(self.snd_nxt, TcpFlags::ACK);
+        self.emit(header, &[], now);
+    }
+
+    /// RFC 5961 section 7: rate limited, so that the challenges cannot be
+    /// used to amplify an attack.
+    fn send_challenge_ack(&mut self, now: Instant) {
+        if self
+            .last_challenge_ack
+            .is_some_and(|at| now < at + CHALLENGE_ACK_INTERVAL)
+        {
+            return;
+        }
+        self.last_challenge_ack = Some(now);
+        self.send_ack(now);
+    }
+
+    fn send_reset(&mut self, seq: u32, now: Instant) {
+        let header = self.header(seq, TcpFlags::RST);
+        self.emit(header, &[], now);
+    }
+
+    /// Like `reset_for`, signed if the connection is.
+    fn send_reset_for(&mut self, seg: &Segment, now: Instant) {
+        if let Some(header) = reset_header(seg) {
+            self.emit(header, &[], now);
+        }
+    }
+}
+
+/// The RST answering `seg` when it does not belong to any connection we
+/// know, RFC 9293 section 3.10.7.1. Nothing is sent in reply to a RST.
+pub(super) fn reset_for(seg: &Segment) -> Option<Packet> {
+    let header = reset_header(seg)?;
+    Some(make_packet(seg.quad, header, &[], &[], Ecn::NotEct))
+}
+
+fn reset_header(seg: &Segment) -> Option<TcpHeader> {
+    if seg.flags.contains(TcpFlags::RST) {
+        return None;
+    }
+    let mut header = TcpHeader::new(seg.quad.local_port, seg.quad.remote_port);
+    if seg.flags.contains(TcpFlags::ACK) {
+        header.seq = seg.ack;
+        header.flags = TcpFlags::RST;
+    } else {
+        header.ack = seg.seq.wrapping_add(seg.len());
+        header.flags = TcpFlags::RST | TcpFlags::ACK;
+    }
+    Some(header)
+}
+
+/// Delivers everything `from` has queued to `to`, returning how many
+/// segments were exchanged.
+#[cfg(test)]
+fn deliver(from: &mut Tcb, to: &mut Tcb, now: Instant) -> usize {
+    let mut n = 0;
+    while let Some(packet) = from.outgoing.pop_front() {
+        to.on_segment(super::loopback(&packet), now);
+        n += 1;
+    }
+    n
+}
+
+#[cfg(test)]
+fn open_connection(now: Instant) -> (Tcb, Tcb) {
+    open_connection_with_rtt(now, Duration::ZERO)
+}
+
+/// The handshake completes at the server `rtt * 3 / 2` after `now`.
+#[cfg(test)]
+fn open_connection_with_rtt(now: Instant, rtt: Duration) -> (Tcb, Tcb) {
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40000,
+        remote_addr: 0x0a00_0002,
+        remote_port: 7,
+    };
+    let mut client = Tcb::connect(quad, DEFAULT_MTU, None, now);
+    let syn = super::loopback(&client.outgoing.pop_front().unwrap());
+    let mut server = Tcb::accept(&syn, DEFAULT_MTU, None, now + rtt / 2);
+    while deliver(&mut server, &mut client, now + rtt)
+        + deliver(&mut client, &mut server, now + rtt * 3 / 2)
+        > 0
+    {}
+    (client, server)
+}
+
+#[test]
+fn test_handshake_data_and_close() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    assert_eq!(client.state(), State::Established);
+    assert_eq!(server.state(), State::Established);
+
+    assert_eq!(client.send(b"hello", now).unwrap(), 5);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(client.snd_una, client.snd_nxt);
+
+    client.close(now);
+    assert_eq!(client.state(), State::FinWait1);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.state(), State::FinWait2);
+    assert_eq!(server.state(), State::CloseWait);
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 0);
+
+    server.close(now);
+    deliver(&mut server, &mut client, now);
+    deliver(&mut client, &mut server, now);
+    assert_eq!(client.state(), State::TimeWait);
+    assert_eq!(server.state(), State::Closed);
+
+    client.on_tick(now + 2 * MSL);
+    assert_eq!(client.state(), State::Closed);
+}
+
+#[test]
+fn test_time_wait() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    client.close(now);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    server.close(now);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.state(), State::TimeWait);
+
+    // A RST does not end TIME-WAIT early
+    let ack = super::loopback(client.outgoing.back().unwrap());
+    let rst = super::loopback(&reset_for(&ack).unwrap());
+    assert_eq!(rst.seq, client.rcv_nxt);
+    client.on_segment(rst, now);
+    assert_eq!(client.state(), State::TimeWait);
+
+    // A new SYN takes over with a newer timestamp, or without timestamps a
+    // higher sequence number
+    let later = now + Duration::from_secs(1);
+    let peer = Tcb::connect(server.quad, DEFAULT_MTU, None, later);
+    let mut syn = super::loopback(&peer.outgoing[0]);
+    let ts_recent = client.ts_recent;
+    let set_ts_val = |syn: &mut Segment, ts_val: u32| {
+        for option in &mut syn.options {
+            if let TcpOption::Timestamps { value, .. } = option {
+                *value = ts_val;
+            }
+        }
+    };
+    set_ts_val(&mut syn, ts_recent);
+    assert!(!client.accepts_reincarnation(&syn));
+    set_ts_val(&mut syn, ts_recent.wrapping_add(1000));
+    assert!(client.accepts_reincarnation(&syn));
+    syn.options.clear();
+    syn.seq = client.rcv_nxt;
+    assert!(!client.accepts_reincarnation(&syn));
+    syn.seq = client.rcv_nxt.wrapping_add(1);
+    assert!(client.accepts_reincarnation(&syn));
+
+    client.on_tick(now + 2 * MSL);
+    assert_eq!(client.state(), State::Closed);
+}
+
+#[test]
+fn test_connection_refused() {
+    let now = Instant::now();
+    let quad = Quad {
+        local_addr: 0x0a00_0001,
+        local_port: 40001,
+        remote_addr: 0x0a00_0002,
+        remote_port: 9,
+    };
+    let mut client = Tcb::connect(quad, DEFAULT_MTU, None, now);
+    let syn = super::loopback(&client.outgoing.pop_front().unwrap());
+    let rst = super::loopback(&reset_for(&syn).unwrap());
+    client.on_segment(rst, now);
+    assert_eq!(client.state(), State::Closed);
+    assert_eq!(client.error(), Some(ErrorKind::ConnectionRefused));
+}
+
+#[test]
+fn test_retransmission() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    // No tail loss probe before the timeout
+    client.set_rack_tlp(false);
+
+    client.send(b"lost", now).unwrap();
+    assert!(client.outgoing.pop_front().is_some());
+    client.on_tick(now + Duration::from_millis(500));
+    assert!(client.outgoing.is_empty());
+
+    let later = now + client.rtt.rto();
+    client.on_tick(later);
+    assert_eq!(deliver(&mut client, &mut server, later), 1);
+    deliver(&mut server, &mut client, later);
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 4);
+    assert!(client.rtx_queue.is_empty());
+    assert_eq!(client.rtx_deadline, None);
+}
+
+#[test]
+fn test_retransmission_gives_up() {
+    let mut now = Instant::now();
+    let (mut client, _server) = open_connection(now);
+
+    client.send(b"into the void", now).unwrap();
+    for _ in 0..=MAX_RETRANSMITS {
+        now += Duration::from_secs(60);
+        client.on_tick(now);
+    }
+    assert_eq!(client.outgoing.len(), 1 + MAX_RETRANSMITS as usize);
+    assert_eq!(client.state(), State::Closed);
+    assert_eq!(client.error(), Some(ErrorKind::TimedOut));
+}
+
+/// Sends `data` from `client` to `server`, dropping the segments at the
+/// indices in `lost`.
+#[cfg(test)]
+fn send_with_losses(
+    client: &mut Tcb,
+    server: &mut Tcb,
+    data: &[u8],
+    lost: &[usize],
+    now: Instant,
+) {
+    client.send(data, now).unwrap();
+    let segments = data.len().div_ceil(client.max_payload());
+    assert_eq!(client.outgoing.len(), segments);
+    for (i, packet) in client.outgoing.drain(..).enumerate() {
+        if !lost.contains(&i) {
+            server.on_segment(super::loopback(&packet), now);
+        }
+    }
+}
+
+#[test]
+fn test_fast_recovery() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    client.set_congestion_algorithm(CongestionAlgorithm::NewReno);
+    client.sack_permitted = false;
+
+    let data: Vec<u8> =
+        (0..6 * client.max_payload()).map(|i| i as u8).collect();
+    send_with_losses(&mut client, &mut server, &data, &[0, 2], now);
+    deliver(&mut server, &mut client, now);
+    assert!(client.recovery_cwnd.is_some());
+    assert_eq!(client.ssthresh(), data.len() / 2);
+
+    // The partial ACK for the first retransmission triggers the second,
+    // all without waiting for a timeout.
+    while deliver(&mut client, &mut server, now)
+        + deliver(&mut server, &mut client, now)
+        > 0
+    {}
+    let mut buf = vec![0; data.len()];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert_eq!(client.recovery_cwnd, None);
+    assert_eq!(client.retransmits, 0);
+    assert!(client.rtx_queue.is_empty());
+}
+
+#[test]
+fn test_sack_recovery() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    assert!(client.sack_permitted && server.sack_permitted);
+
+    assert_eq!(client.mss, DEFAULT_MTU - HEADERS_LEN);
+
+    let data: Vec<u8> =
+        (0..10 * client.max_payload()).map(|i| i as u8).collect();
+    send_with_losses(&mut client, &mut server, &data, &[0, 3], now);
+    deliver(&mut server, &mut client, now);
+    // Both holes are repaired in the same round trip
+    assert_eq!(client.outgoing.len(), 2);
+    assert_eq!(deliver(&mut client, &mut server, now), 2);
+    deliver(&mut server, &mut client, now);
+
+    let mut buf = vec![0; data.len()];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), data.len());
+    assert_eq!(buf, data);
+    assert_eq!(client.recovery_cwnd, None);
+    assert_eq!(client.retransmits, 0);
+    assert!(client.rtx_queue.is_empty());
+}
+
+/// Paces at a fixed rate in bytes per second, with an unlimited window.
+#[cfg(test)]
+struct FixedRate(f64);
+
+#[cfg(test)]
+impl CongestionControl for FixedRate {
+    fn on_ack(&mut self, _ack: &Ack) {}
+    fn on_loss(&mut self, _flight_size: usize, _now: Instant) {}
+    fn on_recovery_end(&mut self, _flight_size: usize, _now: Instant) {}
+    fn on_timeout(&mut self, _flight_size: usize, _now: Instant) {}
+
+    fn cwnd(&self) -> usize {
+        usize::MAX
+    }
+
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        Some(self.0)
+    }
+}
+
+#[test]
+fn test_pacing() {
+    let start = Instant::now();
+    let (mut client, mut server) = open_connection(start);
+    // One segment every 10ms
+    client.cc = Box::new(FixedRate(client.max_payload() as f64 * 100.0));
+    client.set_rack_tlp(false);
+
+    client
+        .send(&vec![0; 4 * client.max_payload()], start)
+        .unwrap();
+    assert_eq!(client.outgoing.len(), 1);
+    let gap = Duration::from_millis(10);
+    assert_eq!(client.next_deadline(), Some(start + gap));
+
+    // Every pacing deadline releases the next segment
+    let mut now = start;
+    while client.outgoing.len() < 4 {
+        now = client.next_deadline().unwrap();
+        client.on_tick(now);
+    }
+    assert_eq!(now, start + 3 * gap);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    assert!(client.rtx_queue.is_empty());
+    assert_eq!(client.pacing_deadline, None);
+}
+
+#[test]
+fn test_window_scaling() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    assert!(client.window_scaling && server.window_scaling);
+    assert_eq!(client.snd_wscale, RCV_WSCALE);
+    assert_eq!(server.rcv_wscale, RCV_WSCALE);
+
+    // The SYN-ACK's window is unscaled, later ones open up the whole buffer
+    assert_eq!(client.snd_wnd, u16::MAX as u32);
+    client.send(b"hello", now).unwrap();
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    let window = RECV_BUFFER_SIZE as u32 - 5;
+    assert_eq!(client.snd_wnd, window >> RCV_WSCALE << RCV_WSCALE);
+}
+
+#[test]
+fn test_timestamps() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    assert!(client.timestamps && server.timestamps);
+
+    // Timestamps still measure the round trip of a retransmission
+    client.send(b"lost", now).unwrap();
+    client.outgoing.clear();
+    let later = now + client.rtt.rto();
+    client.on_tick(later);
+    deliver(&mut client, &mut server, later);
+    let rtt = Duration::from_millis(40);
+    deliver(&mut server, &mut client, later + rtt);
+    assert_eq!(client.rtt.srtt(), Some(rtt / 8));
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 4);
+
+    // PAWS drops a segment with an old timestamp and acknowledges it
+    client.send(b"old", later).unwrap();
+    let packet = client.outgoing.pop_front().unwrap();
+    let mut old = super::loopback(&packet);
+    for option in &mut old.options {
+        if let TcpOption::Timestamps { value, .. } = option {
+            *value = value.wrapping_sub(1000);
+        }
+    }
+    let old_seq = old.seq;
+    server.on_segment(old, later);
+    assert_eq!(
+        server.recv(&mut buf, now).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    let ack = super::loopback(&server.outgoing.pop_front().unwrap());
+    assert_eq!(ack.ack, old_seq);
+
+    server.on_segment(super::loopback(&packet), later);
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 3);
+}
+
+#[test]
+fn test_delayed_ack() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    server.quick_acks = 0;
+    client.nodelay = true;
+
+    // Every second full-sized segment is acknowledged right away
+    let full = vec![0; client.max_payload()];
+    client.send(&full, now).unwrap();
+    deliver(&mut client, &mut server, now);
+    assert!(server.outgoing.is_empty());
+    client.send(&full, now).unwrap();
+    deliver(&mut client, &mut server, now);
+    assert_eq!(deliver(&mut server, &mut client, now), 1);
+    assert_eq!(client.snd_una, client.snd_nxt);
+
+    // Anything less waits for the timer
+    client.send(b"small", now).unwrap();
+    deliver(&mut client, &mut server, now);
+    assert!(server.outgoing.is_empty());
+    assert_eq!(server.next_deadline(), Some(now + DELAYED_ACK_TIMEOUT));
+    server.on_tick(now + DELAYED_ACK_TIMEOUT);
+    assert_eq!(deliver(&mut server, &mut client, now), 1);
+    assert_eq!(server.next_deadline(), None);
+}
+
+#[test]
+fn test_nagle_and_cork() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+
+    // Small writes coalesce while one is unacknowledged
+    for data in [b"a", b"b", b"c"] {
+        client.send(data, now).unwrap();
+    }
+    assert_eq!(client.outgoing.len(), 1);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.outgoing.len(), 1);
+    assert_eq!(super::loopback(&client.outgoing[0]).data(), b"bc");
+    client.set_nodelay(true, now);
+    client.send(b"d", now).unwrap();
+    assert_eq!(deliver(&mut client, &mut server, now), 2);
+    deliver(&mut server, &mut client, now);
+
+    // The cork holds back partial segments until removed or timed out
+    client.set_cork(true, now);
+    client.send(b"corked", now).unwrap();
+    assert!(client.outgoing.is_empty());
+    client.on_tick(now + CORK_TIMEOUT);
+    assert_eq!(client.outgoing.len(), 1);
+    client.send(b"again", now).unwrap();
+    assert_eq!(client.outgoing.len(), 1);
+    client.set_cork(false, now);
+    assert_eq!(client.outgoing.len(), 2);
+}
+
+#[test]
+fn test_keepalive() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    let keepalive = Keepalive {
+        idle: Duration::from_secs(60),
+        interval: Duration::from_secs(10),
+        count: 3,
+    };
+    client.set_keepalive(Some(keepalive));
+    assert_eq!(client.next_deadline(), Some(now + keepalive.idle));
+
+    // An answered probe starts the idle period over
+    let mut now = now + keepalive.idle;
+    client.on_tick(now);
+    assert_eq!(deliver(&mut client, &mut server, now), 1);
+    assert_eq!(deliver(&mut server, &mut client, now), 1);
+    assert_eq!(client.next_deadline(), Some(now + keepalive.idle));
+
+    // Then the peer goes away
+    now += keepalive.idle;
+    for _ in 0..keepalive.count {
+        client.on_tick(now);
+        now += keepalive.interval;
+    }
+    assert_eq!(client.outgoing.len(), keepalive.count as usize);
+    assert_eq!(client.state(), State::Established);
+    client.on_tick(now);
+    assert_eq!(client.state(), State::Closed);
+    let mut buf = [0; 16];
+    assert_eq!(
+        client.recv(&mut buf, now).unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+}
+
+#[test]
+fn test_challenge_ack() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+
+    // A RST or SYN that is in the window but not exactly where expected
+    // only earns a challenge ACK
+    client.send_reset(client.snd_nxt.wrapping_add(100), now);
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.state(), State::Established);
+    let challenge = super::loopback(&server.outgoing.pop_front().unwrap());
+    assert_eq!(challenge.ack, server.rcv_nxt);
+
+    let header = client.header(client.snd_nxt, TcpFlags::SYN);
+    client.emit(header, &[], now);
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.state(), State::Established);
+    // Rate limited
+    assert!(server.outgoing.is_empty());
+
+    let later = now + CHALLENGE_ACK_INTERVAL;
+    client.send_reset(client.snd_nxt.wrapping_add(100), later);
+    deliver(&mut client, &mut server, later);
+    assert_eq!(server.outgoing.len(), 1);
+
+    client.send_reset(client.snd_nxt, later);
+    deliver(&mut client, &mut server, later);
+    assert_eq!(server.state(), State::Closed);
+    assert_eq!(server.error(), Some(ErrorKind::ConnectionReset));
+}
+
+#[test]
+fn test_zero_window() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    server.recv_buffer.extend(vec![0; RECV_BUFFER_SIZE]);
+    server.send_ack(now);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.snd_wnd, 0);
+
+    // Nothing fits, so the window gets probed instead, with backoff
+    client.send(b"hello", now).unwrap();
+    assert!(client.outgoing.is_empty());
+    let rto = client.rtt.rto();
+    assert_eq!(client.next_deadline(), Some(now + rto));
+    let now = now + rto;
+    client.on_tick(now);
+    assert_eq!(deliver(&mut client, &mut server, now), 1);
+    assert_eq!(deliver(&mut server, &mut client, now), 1);
+    assert_eq!(client.snd_wnd, 0);
+    assert_eq!(client.next_deadline(), Some(now + rto * 2));
+
+    // Reading a little does not reopen the window, reading it all does
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    assert_eq!(server.recv(&mut buf[..100], now).unwrap(), 100);
+    assert!(server.outgoing.is_empty());
+    server.recv(&mut buf, now).unwrap();
+    assert_eq!(deliver(&mut server, &mut client, now), 1);
+    assert_eq!(deliver(&mut client, &mut server, now), 1);
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 5);
+    assert!(client.persist_deadline.is_none());
+}
+
+#[test]
+fn test_ecn() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    assert!(client.ecn && server.ecn);
+
+    // A router marks the data instead of dropping it
+    client.send(b"hello", now).unwrap();
+    let mut seg = super::loopback(&client.outgoing.pop_front().unwrap());
+    assert_eq!(seg.ecn(), Ecn::Ect0);
+    seg.packet.ip_header_mut().unwrap().set_ecn(Ecn::Ce);
+    server.on_segment(seg, now);
+    let ack = super::loopback(&server.outgoing.pop_front().unwrap());
+    assert!(ack.flags.contains(TcpFlags::ECE));
+    assert_eq!(ack.ecn(), Ecn::NotEct);
+
+    let cwnd = client.cwnd();
+    client.on_segment(ack, now);
+    assert!(client.cwnd() < cwnd);
+    let cwnd = client.cwnd();
+
+    // The next data says the window was reduced, which ends the echo
+    client.send(b"world", now).unwrap();
+    let seg = super::loopback(&client.outgoing.pop_front().unwrap());
+    assert!(seg.flags.contains(TcpFlags::CWR));
+    server.on_segment(seg, now);
+    let ack = super::loopback(&server.outgoing.pop_front().unwrap());
+    assert!(!ack.flags.contains(TcpFlags::ECE));
+    client.on_segment(ack, now);
+    assert_eq!(client.cwnd(), cwnd);
+}
+
+/// Sends `segments` full segments at `now`, of which the one at `late`
+/// arrives after the `by` following ones, and returns how many the client
+/// retransmits once the ACKs are in.
+#[cfg(test)]
+fn send_reordered(
+    client: &mut Tcb,
+    server: &mut Tcb,
+    (segments, late, by): (usize, usize, usize),
+    now: Instant,
+    rtt: Duration,
+) -> usize {
+    client
+        .send(&vec![0; segments * client.max_payload()], now)
+        .unwrap();
+    let mut packets: Vec<Packet> = client.outgoing.drain(..).collect();
+    assert_eq!(packets.len(), segments);
+    let packet = packets.remove(late);
+    packets.insert(late + by, packet);
+    for packet in packets {
+        server.on_segment(super::loopback(&packet), now + rtt / 2);
+    }
+    deliver(server, client, now + rtt);
+    let retransmits = client.outgoing.len();
+    deliver(client, server, now + rtt * 3 / 2);
+    deliver(server, client, now + rtt * 2);
+    assert!(client.rtx_queue.is_empty());
+    retransmits
+}
+
+#[test]
+fn test_rack_reordering() {
+    let rtt = Duration::from_millis(20);
+    let mut retransmits = Vec::new();
+    for rack_tlp in [false, true] {
+        let mut now = Instant::now();
+        let (mut client, mut server) = open_connection_with_rtt(now, rtt);
+        client.set_rack_tlp(rack_tlp);
+        now += rtt * 2;
+        // Not deep enough for either to retransmit, but RACK takes note
+        assert_eq!(
+            send_reordered(&mut client, &mut server, (6, 1, 2), now, rtt),
+            0
+        );
+        now += rtt * 2;
+        retransmits.push(send_reordered(
+            &mut client,
+            &mut server,
+            (8, 1, 4),
+            now,
+            rtt,
+        ));
+    }
+    // Three SACKed segments are enough for a spurious fast retransmit,
+    // RACK gives the segment a quarter RTT to show up
+    assert!(retransmits[0] > 0);
+    assert_eq!(retransmits[1], 0);
+}
+
+#[test]
+fn test_rack_loss_and_tail_loss_probe() {
+    let rtt = Duration::from_millis(20);
+    let mut now = Instant::now();
+    let (mut client, mut server) = open_connection_with_rtt(now, rtt);
+    now += rtt * 2;
+    send_reordered(&mut client, &mut server, (4, 0, 2), now, rtt);
+
+    // A real loss is caught by the reordering timer
+    now += rtt * 2;
+    let data = vec![0; 4 * client.max_payload()];
+    send_with_losses(&mut client, &mut server, &data, &[0], now);
+    deliver(&mut server, &mut client, now + rtt);
+    assert!(client.outgoing.is_empty());
+    let deadline = client.reorder_deadline.unwrap();
+    assert!(deadline < now + rtt * 2);
+    client.on_tick(deadline);
+    assert_eq!(client.outgoing.len(), 1);
+    deliver(&mut client, &mut server, deadline);
+    deliver(&mut server, &mut client, deadline);
+    assert!(client.rtx_queue.is_empty());
+
+    // Losing the tail leaves nothing to SACK, the probe sends it again
+    // well before the RTO
+    now = deadline + rtt;
+    let cwnd = client.cwnd();
+    let data = vec![0; 2 * client.max_payload()];
+    send_with_losses(&mut client, &mut server, &data, &[1], now);
+    deliver(&mut server, &mut client, now + rtt);
+    let probe_at = client.tlp_deadline.unwrap();
+    assert!(probe_at < client.rtx_deadline.unwrap());
+    client.on_tick(probe_at);
+    assert_eq!(deliver(&mut client, &mut server, probe_at), 1);
+    deliver(&mut server, &mut client, probe_at + rtt);
+    assert!(client.rtx_queue.is_empty());
+    assert!(client.cwnd() < cwnd);
+}
+
+#[test]
+fn test_half_close() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+
+    // The end of the request, the response can still come back
+    client.send(b"request", now).unwrap();
+    client.shutdown(Shutdown::Write, now).unwrap();
+    assert_eq!(
+        client.send(b"more", now).unwrap_err().kind(),
+        ErrorKind::BrokenPipe
+    );
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.state(), State::FinWait2);
+    let mut buf = [0; 16];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 7);
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 0);
+
+    server.send(b"response", now).unwrap();
+    server.close(now);
+    deliver(&mut server, &mut client, now);
+    deliver(&mut client, &mut server, now);
+    assert_eq!(client.recv(&mut buf, now).unwrap(), 8);
+    assert_eq!(&buf[..8], b"response");
+    assert_eq!(client.recv(&mut buf, now).unwrap(), 0);
+    assert_eq!(client.state(), State::TimeWait);
+    assert_eq!(server.state(), State::Closed);
+}
+
+#[test]
+fn test_fin_out_of_order() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+
+    // The FIN overtakes the data before it
+    client.send(b"hello", now).unwrap();
+    client.close(now);
+    let data = client.outgoing.pop_front().unwrap();
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.state(), State::Established);
+    let mut buf = [0; 16];
+    assert_eq!(
+        server.recv(&mut buf, now).unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+
+    server.on_segment(super::loopback(&data), now);
+    assert_eq!(server.state(), State::CloseWait);
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.state(), State::FinWait2);
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 0);
+}
+
+#[test]
+fn test_abortive_close() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+
+    // Linger zero: queued data is thrown away, the peer is reset
+    client.set_linger(Some(Duration::ZERO));
+    client.set_cork(true, now);
+    client.send(b"never mind", now).unwrap();
+    client.close(now);
+    assert_eq!(client.state(), State::Closed);
+    assert!(client.is_reapable());
+    let rst = super::loopback(&client.outgoing[0]);
+    assert!(rst.flags.contains(TcpFlags::RST));
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.error(), Some(ErrorKind::ConnectionReset));
+
+    // Nobody is left to read what arrives after a close
+    let (mut client, mut server) = open_connection(now);
+    client.close(now);
+    deliver(&mut client, &mut server, now);
+    deliver(&mut server, &mut client, now);
+    server.send(b"late", now).unwrap();
+    deliver(&mut server, &mut client, now);
+    assert_eq!(client.state(), State::Closed);
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.error(), Some(ErrorKind::ConnectionReset));
+}
+
+#[test]
+fn test_urgent_data() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    client.set_nodelay(true, now);
+
+    client.send(b"hello", now).unwrap();
+    client.send_urgent(b"x", now).unwrap();
+    let urgent = super::loopback(client.outgoing.back().unwrap());
+    assert!(urgent.flags.contains(TcpFlags::URG));
+    assert_eq!(urgent.urgent, 1);
+    client.send(b"world", now).unwrap();
+    deliver(&mut client, &mut server, now);
+
+    // Reads stop at the mark, where the urgent byte was taken out
+    let mut buf = [0; 16];
+    assert_eq!(server.recv_oob().unwrap(), b'x');
+    assert!(!server.at_mark());
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    assert!(server.at_mark());
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 5);
+    assert_eq!(&buf[..5], b"world");
+    assert_eq!(
+        server.recv_oob().unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    // Acknowledged, so no longer urgent
+    deliver(&mut server, &mut client, now);
+    client.send(b"!", now).unwrap();
+    let segment = super::loopback(client.outgoing.back().unwrap());
+    assert!(!segment.flags.contains(TcpFlags::URG));
+}
+
+#[test]
+fn test_path_mtu_discovery() {
+    let now = Instant::now();
+    let (mut client, mut server) = open_connection(now);
+    let payload = client.max_payload();
+    client.send(&vec![1; 2 * payload], now).unwrap();
+    let dropped: Vec<Packet> = client.outgoing.drain(..).collect();
+    assert_eq!(dropped.len(), 2);
+
+    // Only an ICMP error quoting data in flight counts, and what did not
+    // fit goes out again in smaller segments
+    let seq = client.snd_nxt;
+    assert_eq!(client.on_packet_too_big(seq, 1280, 1500, now), None);
+    let seq = client.snd_una;
+    assert_eq!(client.on_packet_too_big(seq, 1280, 1500, now), Some(1280));
+    let max_payload = 1280 - HEADERS_LEN - client.options_len();
+    assert!(client
+        .outgoing
+        .iter()
+        .all(|packet| super::loopback(packet).data().len() <= max_payload));
+    while deliver(&mut client, &mut server, now)
+        + deliver(&mut server, &mut client, now)
+        > 0
+    {}
+    let mut buf = vec![0; 4 * payload];
+    assert_eq!(server.recv(&mut buf, now).unwrap(), 2 * payload);
+
+    // Without ICMP, full-sized segments timing out give the path away
+    let (mut client, mut server) = open_connection(now);
+    client.set_rack_tlp(false);
+    client.send(&vec![2; 2 * payload], now).unwrap();
+    client.outgoing.clear();
+    let mut at = now;
+    for _ in 0..BLACK_HOLE_RETRANSMITS {
+        assert_eq!(client.path_mtu(), DEFAULT_MTU);
+        client.outgoing.clear();
+        at = client.rtx_deadline.unwrap();
+        client.on_tick(at);
+    }
+    assert_eq!(client.path_mtu(), 1024);
+    let retransmission = super::loopback(&client.outgoing[0]);
+    assert_eq!(retransmission.data().len(), client.max_payload());
+    while deliver(&mut client, &mut server, at)
+        + deliver(&mut server, &mut client, at)
+        > 0
+    {}
+    assert_eq!(server.recv(&mut buf, at).unwrap(), 2 * payload);
+
+    // Probing finds out how much more the path takes
+    client.send(&vec![3; 4 * payload], at).unwrap();
+    assert!(client.path_mtu.probe_end().is_some());
+    while deliver(&mut client, &mut server, at)
+        + deliver(&mut server, &mut client, at)
+        > 0
+    {}
+    assert_eq!(client.path_mtu(), 1262);
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+/// Deadlines further out than this are clamped, about 795 days with 1ms
+/// ticks.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+// Ticks are counted in milliseconds since `start`
+const TICK: Duration = Duration::from_millis(1);
+
+pub type Callback<T> = Box<dyn FnOnce(&mut T, Instant)>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Timer<T> {
+    tick: u64,
+    level: usize,
+    slot: usize,
+    callback: Callback<T>,
+}
+
+/// A hierarchical timing wheel with 1ms resolution. Each level has 64 slots,
+/// every slot of level `n` spanning 64^n ticks; timers on the upper levels
+/// cascade down as the wheel turns until they fire from level 0.
+///
+/// Callbacks receive a `&mut T`, which lets the owner of the wheel keep it as
+/// one of its own fields: `expire` hands back the due callbacks, and the owner
+/// then runs them on itself.
+pub struct TimerWheel<T> {
+    start: Instant,
+    /// The last tick that has been processed.
+    current: u64,
+    levels: [[Vec<TimerId>; SLOTS]; LEVELS],
+    timers: HashMap<TimerId, Timer<T>>,
+    next_id: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            current: 0,
+            levels: std::array::from_fn(|_| {
+                std::array::from_fn(|_| Vec::new())
+            }),
+            timers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// Arranges for `callback` to be run by the first `expire` at or after
+    /// `deadline`.
+    pub fn schedule(
+        &mut self,
+        deadline: Instant,
+        callback: impl FnOnce(&mut T, Instant) + 'static,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // Round up so that timers never fire early, and never into the slot
+        // for the tick that was already processed.
+        let elapsed = deadline.saturating_duration_since(self.start);
+        let tick = elapsed.as_nanos().div_ceil(TICK.as_nanos()) as u64;
+        let tick = tick.clamp(self.current + 1, self.current + MAX_DELTA);
+        self.timers.insert(
+            id,
+            Timer {
+                tick,
+                level: 0,
+                slot: 0,
+                callback: Box::new(callback),
+            },
+        );
+        self.place(id);
+        id
+    }
+
+    /// Returns whether the timer was still pending.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.timers.remove(&id) {
+            Some(timer) => {
+                self.levels[timer.level][timer.slot].retain(|&t| t != id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When `expire` next needs to be called. This is exact for timers in
+    /// the next 64 ticks, and otherwise the time the next timer cascades
+    /// down a level.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let mut next: Option<u64> = None;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let base = self.current >> shift;
+            for i in 1..=SLOTS as u64 {
+                let slot = ((base + i) & (SLOTS as u64 - 1)) as usize;
+                if !self.levels[level][slot].is_empty() {
+                    let tick = (base + i) << shift;
+                    next = Some(next.map_or(tick, |n| n.min(tick)));
+                    break;
+                }
+            }
+        }
+        next.map(|tick| self.start + Duration::from_millis(tick))
+    }
+
+    /// Turns the wheel up to `now` and returns the callbacks of every timer
+    /// that came due, in deadline order.
+    pub fn expire(&mut self, now: Instant) -> Vec<Callback<T>> {
+        let target = (now.saturating_duration_since(self.start).as_nanos()
+            / TICK.as_nanos()) as u64;
+        let mut expired = Vec::new();
+        while self.current < target {
+            let tick = self.next_tick();
+            if tick > target {
+                self.current = target;
+                break;
+            }
+            self.current = tick;
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if tick & ((1 << shift) - 1) == 0 {
+                    self.cascade(
+                        level,
+                        ((tick >> shift) as usize) & (SLOTS - 1),
+                    );
+                }
+            }
+            let slot = (tick as usize) & (SLOTS - 1);
+            for id in std::mem::take(&mut self.levels[0][slot]) {
+                expired.push(self.timers.remove(&id).unwrap().callback);
+            }
+        }
+        expired
+    }
+
+    /// The next tick at which anything can happen: the very next one if
+    /// level 0 has timers, otherwise the next point where a non-empty level
+    /// cascades.
+    fn next_tick(&self) -> u64 {
+        for level in 0..LEVELS {
+            if self.levels[level].iter().any(|slot| !slot.is_empty()) {
+                let shift = SLOT_BITS * level as u32;
+                return ((self.current >> shift) + 1) << shift;
+            }
+        }
+        u64::MAX
+    }
+
+    fn cascade(&mut self, level: usize, slot: usize) {
+        for id in std::mem::take(&mut self.levels[level][slot]) {
+            self.place(id);
+        }
+    }
+
+    fn place(&mut self, id: TimerId) {
+        let timer = self.timers.get_mut(&id).unwrap();
+        let delta = timer.tick.saturating_sub(self.current);
+        let mut level = 0;
+        while level < LEVELS - 1
+            && delta >> (SLOT_BITS * (level as u32 + 1)) != 0
+        {
+            level += 1;
+        }
+        let slot =
+            ((timer.tick >> (SLOT_BITS * level as u32)) as usize) & (SLOTS - 1);
+        timer.level = level;
+        timer.slot = slot;
+        self.levels[level][slot].push(id);
+    }
+}
+
+#[test]
+fn test_timer_wheel() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::<Vec<u64>>::new(start);
+    let delays = [5, 63, 64, 65, 4095, 4097, 300_000, 7_200_000];
+    for delay in delays {
+        wheel
+            .schedule(start + Duration::from_millis(delay), move |fired, _| {
+                fired.push(delay)
+            });
+    }
+    let cancelled = wheel
+        .schedule(start + Duration::from_millis(100), |_, _| {
+            panic!("cancelled timer fired")
+        });
+    assert!(wheel.cancel(cancelled));
+    assert!(!wheel.cancel(cancelled));
+
+    let mut fired = Vec::new();
+    for delay in delays {
+        let now = start + Duration::from_millis(delay);
+        assert!(wheel.next_expiry().unwrap() <= now);
+
+        for callback in wheel.expire(now - TICK) {
+            callback(&mut fired, now);
+        }
+        assert!(!fired.contains(&delay), "{} fired early", delay);
+
+        for callback in wheel.expire(now) {
+            callback(&mut fired, now);
+        }
+        assert_eq!(fired.last(), Some(&delay));
+    }
+    assert_eq!(fired, delays);
+    assert!(wheel.is_empty());
+    assert_eq!(wheel.next_expiry(), None);
+}
+
+#[test]
+fn test_timer_wheel_late_expire() {
+    let start = Instant::now();
+    let mut wheel = TimerWheel::<Vec<u64>>::new(start);
+    for delay in [10, 1000, 100_000] {
+        wheel
+            .schedule(start + Duration::from_millis(delay), move |fired, _| {
+                fired.push(delay)
+            });
+    }
+
+    let mut fired = Vec::new();
+    for callback in wheel.expire(start + Duration::from_secs(3600)) {
+        callback(&mut fired, start);
+    }
+    assert_eq!(fired, [10, 1000, 100_000]);
+}
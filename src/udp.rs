@@ -1,6 +1,12 @@
-use crate::ip::IpProtocol;
-use crate::{network_checksum_2part, ones_complement_sum, AsSlice};
+use crate::ip::{IpHeader, IpProtocol};
+use crate::packet::Packet;
+use crate::{network_checksum_2part, AsSlice};
 use std::mem::size_of;
+use std::net::SocketAddrV4;
+
+mod stack;
+
+pub use stack::UdpStack;
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -93,17 +99,142 @@ impl UdpHeader {
         // )
     }
 
+    /// # Safety
+    ///
+    /// `self.len` has to be set and correct, and the data has to follow
+    /// `self`, with all fields big-endian.
     pub unsafe fn set_ip_checksum(
         &mut self,
         source_ip: u32,
         destination_ip: u32,
     ) {
-        self.checksum = self.ip_checksum(source_ip, destination_ip);
+        // Zero means there is no checksum, RFC 768
+        self.checksum = match self.ip_checksum(source_ip, destination_ip) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+    }
+
+    /// # Safety
+    ///
+    /// `self.len` has to be set and correct, and the data has to follow
+    /// `self`, with all fields big-endian.
+    pub unsafe fn verify_ip_checksum(
+        &self,
+        source_ip: u32,
+        destination_ip: u32,
+    ) -> bool {
+        match self.checksum {
+            0 => true,
+            0xffff => self.ip_checksum(source_ip, destination_ip) == 0,
+            checksum => self.ip_checksum(source_ip, destination_ip) == checksum,
+        }
     }
 }
 
 impl AsSlice for UdpHeader {}
 
+/// An incoming UDP datagram. The packet is left as it came in, with its
+/// `data_offset` pointing at the payload.
+pub struct Datagram {
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub packet: Packet,
+}
+
+impl Datagram {
+    /// Takes a packet with its IP header in native byte order and `l4_offset`
+    /// set, as `handle_ip` leaves it. Returns `None` if the datagram is
+    /// truncated or fails its checksum.
+    pub fn parse(mut packet: Packet) -> Option<Self> {
+        let ip = *packet.ip_header()?;
+        let l4_offset = packet.l4_offset? as usize;
+        let end = packet.data.len().min(ip.total_len as usize);
+        if l4_offset + size_of::<UdpHeader>() > end {
+            return None;
+        }
+        let udp = *packet.udp_header()?;
+        let udp_len = u16::from_be(udp.len) as usize;
+        if udp_len < size_of::<UdpHeader>() || l4_offset + udp_len > end {
+            return None;
+        }
+        packet.data.truncate(l4_offset + udp_len);
+        // SAFETY: `udp_len` bytes of the packet follow the header, checked
+        // above.
+        let valid = unsafe {
+            packet.udp_header()?.verify_ip_checksum(
+                ip.source.swap_bytes(),
+                ip.destination.swap_bytes(),
+            )
+        };
+        if !valid {
+            println!("udp: bad checksum, discarding");
+            return None;
+        }
+        packet.data_offset =
+            Some((l4_offset + size_of::<UdpHeader>()) as isize);
+        Some(Self {
+            source: SocketAddrV4::new(
+                ip.source.into(),
+                u16::from_be(udp.source_port),
+            ),
+            destination: SocketAddrV4::new(
+                ip.destination.into(),
+                u16::from_be(udp.destination_port),
+            ),
+            packet,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.packet.data().unwrap()
+    }
+}
+
+/// Builds a complete IP packet carrying a UDP datagram, in network byte
+/// order with checksums filled in.
+pub fn make_packet(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    data: &[u8],
+) -> Packet {
+    let udp_len = size_of::<UdpHeader>() + data.len();
+    let mut header = UdpHeader {
+        source_port: source.port(),
+        destination_port: destination.port(),
+        len: udp_len as u16,
+        checksum: 0,
+    };
+    header.bswap();
+    let (source_ip, destination_ip) =
+        (u32::from(*source.ip()), u32::from(*destination.ip()));
+    let mut packet = Packet::new_from_data(data);
+    packet.fill_l4(header);
+    packet.fill_l3(IpHeader::new(IpProtocol::UDP, source_ip, destination_ip));
+    packet.ip_header_mut().unwrap().total_len = packet.len().unwrap() as u16;
+    packet.ip_header_mut().unwrap().bswap();
+    // SAFETY: the packet was just built with the header's length of UDP
+    // header and data.
+    unsafe {
+        packet
+            .udp_header_mut()
+            .unwrap()
+            .set_ip_checksum(source_ip.to_be(), destination_ip.to_be());
+    }
+    packet.ip_header_mut().unwrap().set_checksum();
+    packet
+}
+
+/// Feeds a packet built by `make_packet` back through the receive path, as
+/// if it had been read from the interface by the peer.
+#[cfg(test)]
+pub fn loopback(packet: &Packet) -> Datagram {
+    let mut packet = Packet::new(packet.whole().unwrap().to_vec());
+    packet.ip_header_mut().unwrap().bswap();
+    packet.l4_offset = Some(packet.ip_header().unwrap().header_len() as isize);
+    Datagram::parse(packet).expect("valid datagram")
+}
+
 #[test]
 fn test_udp_checksum_1() {
     let buffer: &[u8] = &[
@@ -133,7 +264,7 @@ fn test_udp_checksum_2() {
         0x00, 0x0b, 0x8d, 0xbb, 0x68, 0x69, 0x0a,
     ];
 
-    let mut udp_header =
+    let udp_header =
         unsafe { &mut *(buffer[20..].as_mut_ptr() as *mut UdpHeader) };
     udp_header.checksum = 0;
     unsafe {
@@ -145,3 +276,17 @@ fn test_udp_checksum_2() {
         assert_eq!(udp_header.ip_checksum(0x100_000a, 0x300_000a), 0xbb8d);
     }
 }
+
+#[test]
+fn test_truncated_datagram() {
+    use std::net::Ipv4Addr;
+
+    let from = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6000);
+    let to = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5000);
+    let whole = make_packet(from, to, b"hello").whole().unwrap().to_vec();
+    // Cut off inside the UDP header, with the IP header still claiming it
+    let mut packet = Packet::new(whole[..24].to_vec());
+    packet.ip_header_mut().unwrap().bswap();
+    packet.l4_offset = Some(20);
+    assert!(Datagram::parse(packet).is_none());
+}
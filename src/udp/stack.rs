@@ -0,0 +1,225 @@
+use super::{make_packet, Datagram};
+use crate::packet::Packet;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Datagrams are dropped once this much is queued on a socket, like with
+/// Linux's default `SO_RCVBUF`. Each is charged for its whole packet, so
+/// that empty ones are not free.
+const RECV_QUEUE_SIZE: usize = 212992;
+/// The most that fits in an IP packet, RFC 791, after the IP and UDP
+/// headers.
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// A UDP socket bound to a port.
+struct Binding {
+    /// Unspecified for a wildcard bind, which also takes broadcasts.
+    addr: Ipv4Addr,
+    /// Once connected, only datagrams from the peer are taken, and `send`
+    /// goes to it.
+    remote: Option<SocketAddrV4>,
+    queue: VecDeque<Datagram>,
+    queued: usize,
+    /// Datagrams that found the queue full.
+    drops: u64,
+}
+
+/// All UDP sockets of the stack, by their port.
+pub struct UdpStack {
+    /// Where datagrams of wildcard binds are sent from.
+    addr: Ipv4Addr,
+    ports: HashMap<u16, Binding>,
+    outgoing: VecDeque<Packet>,
+}
+
+impl UdpStack {
+    pub fn new(addr: Ipv4Addr) -> Self {
+        Self {
+            addr,
+            ports: HashMap::new(),
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.ports.contains_key(&port)
+    }
+
+    /// Binds `local.port()`, which may not be zero. Binding to the
+    /// unspecified address takes datagrams for any address.
+    pub fn bind(&mut self, local: SocketAddrV4) -> Result<()> {
+        if local.port() == 0 {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if self.is_bound(local.port()) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        self.ports.insert(
+            local.port(),
+            Binding {
+                addr: *local.ip(),
+                remote: None,
+                queue: VecDeque::new(),
+                queued: 0,
+                drops: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sets the peer that `send` goes to and the only one datagrams are
+    /// taken from. What was queued from others before stays.
+    pub fn connect(&mut self, port: u16, remote: SocketAddrV4) -> Result<()> {
+        self.binding_mut(port)?.remote = Some(remote);
+        Ok(())
+    }
+
+    pub fn close(&mut self, port: u16) {
+        self.ports.remove(&port);
+    }
+
+    /// Sends one datagram to `to`, or to the connected peer.
+    pub fn send_to(
+        &mut self,
+        port: u16,
+        data: &[u8],
+        to: Option<SocketAddrV4>,
+    ) -> Result<usize> {
+        let addr = self.addr;
+        let binding = self.binding_mut(port)?;
+        let to = to.or(binding.remote).ok_or(ErrorKind::NotConnected)?;
+        if data.len() > MAX_DATAGRAM_LEN {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let source = match binding.addr {
+            ip if ip.is_unspecified() => addr,
+            ip => ip,
+        };
+        let source = SocketAddrV4::new(source, port);
+        self.outgoing.push_back(make_packet(source, to, data));
+        Ok(data.len())
+    }
+
+    /// Takes the next datagram. What does not fit into `buf` is lost, like
+    /// with `recvfrom(2)`.
+    pub fn recv_from(
+        &mut self,
+        port: u16,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddrV4)> {
+        let binding = self.binding_mut(port)?;
+        let datagram =
+            binding.queue.pop_front().ok_or(ErrorKind::WouldBlock)?;
+        binding.queued -= datagram.packet.data.len();
+        let n = buf.len().min(datagram.data().len());
+        buf[..n].copy_from_slice(&datagram.data()[..n]);
+        Ok((n, datagram.source))
+    }
+
+    /// How many datagrams were dropped for lack of space in the queue.
+    pub fn drops(&self, port: u16) -> Result<u64> {
+        Ok(self.binding(port)?.drops)
+    }
+
+    /// Queues the datagram on the socket it is for, or hands it back if
+    /// there is none, for the caller to answer with ICMP port unreachable.
+    pub fn on_datagram(&mut self, datagram: Datagram) -> Option<Datagram> {
+        let port = datagram.destination.port();
+        let Some(binding) = self.ports.get_mut(&port) else {
+            return Some(datagram);
+        };
+        if !binding.addr.is_unspecified()
+            && binding.addr != *datagram.destination.ip()
+            || binding.remote.is_some_and(|r| r != datagram.source)
+        {
+            return Some(datagram);
+        }
+        let size = datagram.packet.data.len();
+        if binding.queued + size > RECV_QUEUE_SIZE {
+            println!("udp {}: receive queue full, dropping", port);
+            binding.drops += 1;
+            return None;
+        }
+        binding.queued += size;
+        binding.queue.push_back(datagram);
+        None
+    }
+
+    /// Takes every packet the sockets have queued for the interface.
+    pub fn take_outgoing(&mut self) -> Vec<Packet> {
+        self.outgoing.drain(..).collect()
+    }
+
+    fn binding(&self, port: u16) -> Result<&Binding> {
+        Ok(self.ports.get(&port).ok_or(ErrorKind::InvalidInput)?)
+    }
+
+    fn binding_mut(&mut self, port: u16) -> Result<&mut Binding> {
+        Ok(self.ports.get_mut(&port).ok_or(ErrorKind::InvalidInput)?)
+    }
+}
+
+#[test]
+fn test_port_table() {
+    use super::loopback;
+
+    let host = Ipv4Addr::new(10, 0, 0, 2);
+    let peer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6000);
+    let stranger = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 6000);
+    let datagram =
+        |from, to, data: &[u8]| loopback(&make_packet(from, to, data));
+    let mut udp = UdpStack::new(host);
+    udp.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 5000))
+        .unwrap();
+    udp.bind(SocketAddrV4::new(host, 5001)).unwrap();
+    udp.connect(5001, peer).unwrap();
+    let error = udp.bind(SocketAddrV4::new(host, 5000)).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::AddrInUse);
+
+    // The wildcard bind takes broadcasts, the connected socket only what
+    // its peer sends to its address
+    let broadcast = |port| SocketAddrV4::new(Ipv4Addr::BROADCAST, port);
+    let to = |port| SocketAddrV4::new(host, port);
+    assert!(udp
+        .on_datagram(datagram(stranger, broadcast(5000), b"a"))
+        .is_none());
+    assert!(udp
+        .on_datagram(datagram(peer, broadcast(5001), b"b"))
+        .is_some());
+    assert!(udp
+        .on_datagram(datagram(stranger, to(5001), b"c"))
+        .is_some());
+    assert!(udp.on_datagram(datagram(peer, to(5001), b"d")).is_none());
+    assert!(udp.on_datagram(datagram(peer, to(5002), b"e")).is_some());
+    let mut buf = [0; 16];
+    assert_eq!(udp.recv_from(5000, &mut buf).unwrap(), (1, stranger));
+    assert_eq!(udp.recv_from(5001, &mut buf).unwrap(), (1, peer));
+    assert_eq!(&buf[..1], b"d");
+    let error = udp.recv_from(5001, &mut buf).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+    // Replies come from the stack's address, and connected sockets don't
+    // need to say where to
+    udp.send_to(5000, b"hello", Some(peer)).unwrap();
+    udp.send_to(5001, b"hello", None).unwrap();
+    let sent: Vec<Datagram> =
+        udp.take_outgoing().iter().map(loopback).collect();
+    assert_eq!(sent[0].source, to(5000));
+    assert_eq!(sent[1].source, to(5001));
+    assert!(sent
+        .iter()
+        .all(|d| d.destination == peer && d.data() == b"hello"));
+
+    // A full queue drops and counts what does not fit
+    let data = [0; 1000];
+    while udp.drops(5000).unwrap() == 0 {
+        udp.on_datagram(datagram(peer, to(5000), &data));
+    }
+    let queued = udp.ports[&5000].queue.len();
+    assert_eq!(queued, RECV_QUEUE_SIZE / (data.len() + 28));
+    udp.recv_from(5000, &mut buf).unwrap();
+    assert!(udp.on_datagram(datagram(peer, to(5000), &data)).is_none());
+    assert_eq!(udp.ports[&5000].queue.len(), queued);
+    assert_eq!(udp.drops(5000).unwrap(), 1);
+}